@@ -261,7 +261,8 @@
           testScript = ''
             machine.wait_for_unit("dioxus-fs-demo.service")
             machine.wait_for_open_port(4000)
-            machine.succeed("${pkgs.curl}/bin/curl --fail -v http://localhost:4000/_health")
+            # Ready for systemd before the database is migrated.
+            machine.wait_until_succeeds("${pkgs.curl}/bin/curl --fail -v http://localhost:4000/_health/ready")
           '';
         };
 
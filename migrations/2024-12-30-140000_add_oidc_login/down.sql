@@ -0,0 +1,5 @@
+DROP TABLE oidc_login_states;
+
+ALTER TABLE users DROP COLUMN oidc_subject;
+DELETE FROM users WHERE password_hash IS NULL;
+ALTER TABLE users ALTER COLUMN password_hash SET NOT NULL;
@@ -0,0 +1,9 @@
+ALTER TABLE users ALTER COLUMN password_hash DROP NOT NULL;
+ALTER TABLE users ADD COLUMN oidc_subject VARCHAR UNIQUE;
+
+CREATE TABLE oidc_login_states (
+  csrf_state VARCHAR PRIMARY KEY,
+  pkce_verifier VARCHAR NOT NULL,
+  nonce VARCHAR NOT NULL,
+  created_at timestamp with time zone NOT NULL DEFAULT now()
+);
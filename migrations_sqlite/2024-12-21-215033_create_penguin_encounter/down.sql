@@ -0,0 +1 @@
+DROP TABLE penguin_encounter;
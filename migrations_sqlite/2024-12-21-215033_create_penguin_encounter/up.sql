@@ -0,0 +1,7 @@
+CREATE TABLE penguin_encounter (
+  id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  name TEXT NOT NULL,
+  location TEXT NOT NULL,
+  penalty TEXT NOT NULL CHECK (penalty IN ('pat_penguin', 'become_penguin_good', 'jail', 'sacrifice', 'worship_tux')),
+  date_time TEXT NOT NULL
+);
@@ -0,0 +1,5 @@
+DROP TABLE api_tokens;
+DROP TABLE recovery_codes;
+DROP TABLE oidc_login_states;
+DROP TABLE sessions;
+DROP TABLE users;
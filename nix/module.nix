@@ -12,6 +12,7 @@ let
     mkOption
     mkEnableOption
     mkIf
+    optionalAttrs
     ;
 
   cfg = config.services.dioxus-fs-demo;
@@ -19,10 +20,13 @@ let
   system = pkgs.stdenv.system;
   dioxus-fs-demo = self.packages.${system}.default;
 
+  settingsFormat = pkgs.formats.toml { };
+  configFile = settingsFormat.generate "dioxus-fs-demo.toml" cfg.settings;
+
   wrapper = pkgs.writeShellScriptBin "dioxus-fs-demo" ''
     export RUST_LOG=info
     export PORT=${toString cfg.port}
-    exec "${dioxus-fs-demo}/bin/dioxus-fs-demo"
+    exec "${dioxus-fs-demo}/bin/dioxus-fs-demo" --config ${configFile} "$@"
   '';
 in
 {
@@ -36,6 +40,30 @@ in
       type = types.int;
       default = 8080;
     };
+    listen = mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      example = "/run/dioxus-fs-demo.sock";
+      description = lib.mdDoc ''
+        Socket for systemd to listen on and pass to dioxus-fs-demo, as an
+        address, port or Unix socket path accepted by `ListenStream`. When
+        null, dioxus-fs-demo listens on `port` itself.
+      '';
+    };
+    settings = mkOption {
+      type = settingsFormat.type;
+      default = { };
+      example = {
+        title = "Penguin Encounters";
+        database_connect_retries = 30;
+      };
+      description = lib.mdDoc ''
+        Settings for dioxus-fs-demo, written to its TOML config file. Run
+        `dioxus-fs-demo --print-config` to see the effective settings.
+
+        Secrets don't belong here, as they would end up in the Nix store.
+      '';
+    };
     secretsFile = mkOption {
       type = types.str;
       example = "/run/secrets/dioxus-fs-demo.env";
@@ -44,6 +72,10 @@ in
 
         Must contain at least:
         - `DATABASE_URL` - The URL to the database.
+
+        Secrets can also be given as files with `DATABASE_URL_FILE`,
+        `BOOTSTRAP_PASSWORD_FILE` and `OIDC_CLIENT_SECRET_FILE`, for example
+        using systemd credentials.
       '';
     };
   };
@@ -59,13 +91,29 @@ in
 
     users.groups.dioxus = { };
 
+    systemd.sockets.dioxus-fs-demo = mkIf (cfg.listen != null) {
+      wantedBy = [ "sockets.target" ];
+      socketConfig = {
+        ListenStream = cfg.listen;
+        SocketUser = "dioxus";
+        SocketGroup = "dioxus";
+      };
+    };
+
     systemd.services.dioxus-fs-demo = {
       wantedBy = [ "multi-user.target" ];
       serviceConfig = {
+        Type = "notify";
+        NotifyAccess = "main";
+        WatchdogSec = "30s";
         User = "dioxus";
-        ExecStart = "${wrapper}/bin/dioxus-fs-demo";
+        ExecStart = "${wrapper}/bin/dioxus-fs-demo serve";
         EnvironmentFile = cfg.secretsFile;
       };
+    }
+    // optionalAttrs (cfg.listen != null) {
+      requires = [ "dioxus-fs-demo.socket" ];
+      after = [ "dioxus-fs-demo.socket" ];
     };
   };
 }
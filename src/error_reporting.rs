@@ -0,0 +1,204 @@
+//! Reporting client side panics, errors and warnings to the server.
+//!
+//! Reports are queued and sent to `/_telemetry/errors` a few seconds after
+//! the first one, so a burst of errors is sent together. A panic is sent
+//! immediately, as nothing runs afterwards. Sending uses
+//! `navigator.sendBeacon`, which works even then.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[allow(dead_code)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportLevel {
+    Warn,
+    Error,
+    Panic,
+}
+
+#[allow(dead_code)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClientReport {
+    pub level: ReportLevel,
+    pub message: String,
+    /// Where it was logged, or where the panic happened.
+    pub location: Option<String>,
+    /// The path of the page the client was on.
+    pub route: Option<String>,
+    pub version: String,
+    pub time: DateTime<Utc>,
+}
+
+#[allow(dead_code)]
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ClientReports {
+    pub reports: Vec<ClientReport>,
+    /// Reports not sent because too many were queued.
+    pub dropped: u32,
+}
+
+#[cfg(target_arch = "wasm32")]
+pub use client::{init, install_panic_hook};
+
+#[cfg(target_arch = "wasm32")]
+mod client {
+    use std::cell::RefCell;
+    use std::fmt;
+
+    use tracing::field::{Field, Visit};
+    use tracing::{Event, Level, Subscriber};
+    use tracing_subscriber::filter::LevelFilter;
+    use tracing_subscriber::layer::{Context, SubscriberExt};
+    use tracing_subscriber::util::SubscriberInitExt;
+    use tracing_subscriber::Layer;
+
+    use super::{ClientReport, ClientReports, ReportLevel};
+
+    const URL: &str = "/_telemetry/errors";
+    const FLUSH_DELAY_MS: u32 = 5_000;
+    const MAX_QUEUED: usize = 50;
+
+    #[derive(Default)]
+    struct Queue {
+        reports: ClientReports,
+        flush_scheduled: bool,
+    }
+
+    thread_local! {
+        static QUEUE: RefCell<Queue> = RefCell::new(Queue::default());
+    }
+
+    fn route() -> Option<String> {
+        web_sys::window()?.location().pathname().ok()
+    }
+
+    fn report(level: ReportLevel, message: String, location: Option<String>) -> ClientReport {
+        ClientReport {
+            level,
+            message,
+            location,
+            route: route(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            time: chrono::Utc::now(),
+        }
+    }
+
+    fn push(report: ClientReport) {
+        let schedule = QUEUE.with(|queue| {
+            let mut queue = queue.borrow_mut();
+            if queue.reports.reports.len() >= MAX_QUEUED {
+                queue.reports.dropped += 1;
+            } else {
+                queue.reports.reports.push(report);
+            }
+            !std::mem::replace(&mut queue.flush_scheduled, true)
+        });
+
+        if schedule {
+            gloo_timers::callback::Timeout::new(FLUSH_DELAY_MS, flush).forget();
+        }
+    }
+
+    fn flush() {
+        let reports = QUEUE.with(|queue| {
+            let mut queue = queue.borrow_mut();
+            queue.flush_scheduled = false;
+            std::mem::take(&mut queue.reports)
+        });
+        if reports.reports.is_empty() && reports.dropped == 0 {
+            return;
+        }
+
+        // Failures are ignored, as logging them would queue another report.
+        let Ok(body) = serde_json::to_string(&reports) else {
+            return;
+        };
+        if let Some(window) = web_sys::window() {
+            let _ = window
+                .navigator()
+                .send_beacon_with_opt_str(URL, Some(&body));
+        }
+    }
+
+    #[derive(Default)]
+    struct MessageVisitor {
+        message: String,
+        fields: Vec<String>,
+    }
+
+    impl Visit for MessageVisitor {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            if field.name() == "message" {
+                self.message = format!("{value:?}");
+            } else {
+                self.fields.push(format!("{}={value:?}", field.name()));
+            }
+        }
+    }
+
+    /// Queues warnings and errors.
+    struct ReportLayer;
+
+    impl<S: Subscriber> Layer<S> for ReportLayer {
+        fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+            let metadata = event.metadata();
+            let level = match *metadata.level() {
+                Level::ERROR => ReportLevel::Error,
+                Level::WARN => ReportLevel::Warn,
+                _ => return,
+            };
+
+            let mut visitor = MessageVisitor::default();
+            event.record(&mut visitor);
+            let mut message = visitor.message;
+            for field in visitor.fields {
+                message.push(' ');
+                message.push_str(&field);
+            }
+
+            push(report(level, message, Some(metadata.target().to_string())));
+        }
+    }
+
+    /// Log to the browser console, reporting warnings and errors.
+    ///
+    /// Called before `dioxus::launch`, which then leaves logging alone.
+    pub fn init() {
+        let level = if cfg!(debug_assertions) {
+            LevelFilter::DEBUG
+        } else {
+            LevelFilter::INFO
+        };
+
+        tracing_subscriber::registry()
+            .with(level)
+            .with(tracing_wasm::WASMLayer::new(
+                tracing_wasm::WASMLayerConfig::default(),
+            ))
+            .with(ReportLayer)
+            .init();
+    }
+
+    /// Report panics, after the existing hook has logged them.
+    ///
+    /// Called from a hook in `App`, as launching replaces the panic hook.
+    pub fn install_panic_hook() {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            previous(info);
+
+            let message = match info.payload().downcast_ref::<&str>() {
+                Some(message) => message.to_string(),
+                None => match info.payload().downcast_ref::<String>() {
+                    Some(message) => message.clone(),
+                    None => "Box<dyn Any>".to_string(),
+                },
+            };
+            let location = info.location().map(|location| location.to_string());
+
+            push(report(ReportLevel::Panic, message, location));
+            flush();
+        }));
+    }
+}
@@ -2,19 +2,29 @@
 mod server;
 
 #[cfg(feature = "server")]
-use server::database;
+use server::MyContext;
 
 #[cfg(feature = "server")]
-use server::MyContext;
+use server::functions;
 
 #[cfg(feature = "server")]
-use server::database::list_penguin_encounters;
+use server::auth::{self, CurrentUser, SessionUser};
 
 #[cfg(feature = "server")]
-use dioxus::prelude::server_fn::error::NoCustomError;
+use server::authz::{require_role, server_error, CurrentRole};
 
 mod model;
-use model::PenguinEncounter;
+use model::{
+    AccessError, ApiToken, ApiTokenScope, LoginResult, NewApiToken, PenaltyEnum, PenguinEncounter,
+    Role, TotpEnrolment, User,
+};
+
+mod rpc;
+use rpc::{use_rpc_client, RpcClient, Transport};
+
+mod telemetry;
+
+mod error_reporting;
 
 use dioxus::prelude::*;
 use futures::{SinkExt, StreamExt};
@@ -32,6 +42,14 @@ enum Route {
     Blog { id: i32 },
     #[route("/websocket")]
     Websocket {},
+    #[route("/login")]
+    LoginPage {},
+    #[route("/account/security")]
+    Security {},
+    #[route("/account/api-tokens")]
+    ApiTokens {},
+    #[route("/admin/users")]
+    Users {},
     #[route("/:..segments")]
     NotFound { segments: Vec<String> },
     #[route("/penguin-encounters")]
@@ -51,6 +69,9 @@ const FAVICON: &str = my_asset!("favicon-", favicon_HASH, ".ico");
 // For any other platform, we just launch the app
 #[cfg(not(feature = "server"))]
 fn main() {
+    #[cfg(target_arch = "wasm32")]
+    error_reporting::init();
+
     dioxus::launch(App);
 }
 
@@ -62,6 +83,25 @@ async fn main() {
 
 #[component]
 fn App() -> Element {
+    #[cfg(target_arch = "wasm32")]
+    use_hook(error_reporting::install_panic_hook);
+
+    let rpc_client = use_rpc_client();
+    use_context_provider(|| rpc_client);
+
+    let mut user: Signal<Option<User>> = use_context_provider(|| Signal::new(None));
+    let mut role: Signal<Option<Role>> = use_context_provider(|| Signal::new(None));
+    use_future(move || async move {
+        match get_current_user().await {
+            Ok(current_user) => user.set(current_user),
+            Err(err) => error!("Failed to get current user: {err}"),
+        }
+        match get_current_role().await {
+            Ok(current_role) => role.set(current_role),
+            Err(err) => error!("Failed to get current role: {err}"),
+        }
+    });
+
     rsx! {
         document::Link { rel: "icon", href: FAVICON }
         document::Link { rel: "stylesheet", href: MAIN_CSS }
@@ -129,6 +169,9 @@ pub fn Blog(id: i32) -> Element {
 /// Shared navbar component.
 #[component]
 fn Navbar() -> Element {
+    let mut user = use_context::<Signal<Option<User>>>();
+    let mut role = use_context::<Signal<Option<Role>>>();
+
     rsx! {
         div {
             id: "navbar",
@@ -148,6 +191,48 @@ fn Navbar() -> Element {
                 to: Route::PenguinEncounters {},
                 "Penguin Encounters"
             }
+            if role().is_some_and(|role| role.allows(Role::Admin)) {
+                Link {
+                    to: Route::Users {},
+                    "Users"
+                }
+            }
+            match user() {
+                Some(current_user) => {
+                    rsx! {
+                        Link {
+                            to: Route::Security {},
+                            "Security"
+                        }
+                        Link {
+                            to: Route::ApiTokens {},
+                            "API Tokens"
+                        }
+                        a {
+                            href: "#",
+                            onclick: move |event| async move {
+                                event.prevent_default();
+                                match logout().await {
+                                    Ok(()) => {
+                                        user.set(None);
+                                        role.set(get_current_role().await.ok().flatten());
+                                    }
+                                    Err(err) => error!("Failed to log out: {err}"),
+                                }
+                            },
+                            "Logout {current_user.username}"
+                        }
+                    }
+                }
+                None => {
+                    rsx! {
+                        Link {
+                            to: Route::LoginPage {},
+                            "Login"
+                        }
+                    }
+                }
+            }
         }
 
         Outlet::<Route> {}
@@ -158,16 +243,38 @@ fn Navbar() -> Element {
 #[component]
 fn Echo() -> Element {
     let mut response = use_signal(String::new);
+    let mut transport = use_signal(|| Transport::Websocket);
+    let rpc_client = use_context::<RpcClient>();
 
     rsx! {
         div {
             id: "echo",
             h4 { "ServerFn Echo" }
+            label {
+                input {
+                    r#type: "checkbox",
+                    checked: transport() == Transport::Websocket,
+                    onchange: move |event| {
+                        if event.checked() {
+                            transport.set(Transport::Websocket);
+                        } else {
+                            transport.set(Transport::Http);
+                        }
+                    },
+                }
+                " Send over websocket"
+            }
             input {
                 placeholder: "Type here to echo...",
                 oninput:  move |event| async move {
-                    let data = echo_server(event.value()).await.unwrap();
-                    response.set(data);
+                    let data = match transport() {
+                        Transport::Http => echo_server(event.value()).await,
+                        Transport::Websocket => rpc_client.echo_server(event.value()).await,
+                    };
+                    match data {
+                        Ok(data) => response.set(data),
+                        Err(err) => error!("Echo failed: {err}"),
+                    }
                 },
             }
 
@@ -181,7 +288,10 @@ fn Echo() -> Element {
     }
 }
 
-fn get_websocket_url() -> String {
+/// The websocket URL for `path` on the server the page came from, using
+/// `wss` when the page was served over HTTPS, whether by the server itself
+/// or a proxy in front of it.
+fn get_websocket_url(path: &str) -> String {
     let window = web_sys::window().unwrap();
     let location = window.location();
     let protocol = if location.protocol().unwrap() == "https:" {
@@ -190,7 +300,7 @@ fn get_websocket_url() -> String {
         "ws"
     };
     let host = location.host().unwrap();
-    format!("{protocol}://{host}/echo")
+    format!("{protocol}://{host}{path}")
 }
 
 /// Echo component that demonstrates fullstack server functions.
@@ -199,7 +309,7 @@ fn Websocket() -> Element {
     let mut response = use_signal(String::new);
 
     let tx = use_coroutine(move |mut rx: UnboundedReceiver<String>| async move {
-        let url = get_websocket_url();
+        let url = get_websocket_url("/echo");
         debug!("Connecting to websicket at {url}");
         let mut socket = WebSocket::open(&url).unwrap();
         debug!("Connected to websicket.");
@@ -257,6 +367,350 @@ fn Websocket() -> Element {
     }
 }
 
+/// Login page.
+#[component]
+fn LoginPage() -> Element {
+    let mut user = use_context::<Signal<Option<User>>>();
+    let mut role = use_context::<Signal<Option<Role>>>();
+    let mut username = use_signal(String::new);
+    let mut password = use_signal(String::new);
+    let mut totp_code = use_signal(String::new);
+    let mut totp_required = use_signal(|| false);
+    let mut login_error: Signal<Option<ServerFnError>> = use_signal(|| None);
+    let oidc_enabled = use_resource(oidc_login_enabled);
+
+    rsx! {
+        div {
+            id: "login",
+            h1 { "Login" }
+            if let Some(Ok(true)) = *oidc_enabled.read() {
+                p {
+                    a {
+                        href: "/auth/oidc/login",
+                        "Login with single sign-on"
+                    }
+                }
+            }
+            if let Some(err) = login_error() {
+                div {
+                    class: "alert alert-danger",
+                    "Login failed: {err}"
+                }
+            }
+            form {
+                onsubmit: move |event| async move {
+                    event.prevent_default();
+                    let code = if totp_required() { Some(totp_code()) } else { None };
+                    match login(username(), password(), code).await {
+                        Ok(LoginResult::LoggedIn(logged_in)) => {
+                            role.set(Some(logged_in.role));
+                            user.set(Some(logged_in));
+                            login_error.set(None);
+                            navigator().push(Route::Home {});
+                        }
+                        Ok(LoginResult::TotpRequired) => {
+                            totp_required.set(true);
+                            login_error.set(None);
+                        }
+                        Err(err) => login_error.set(Some(err)),
+                    }
+                },
+                input {
+                    name: "username",
+                    placeholder: "Username",
+                    autocomplete: "username",
+                    value: "{username}",
+                    oninput: move |event| username.set(event.value()),
+                }
+                input {
+                    name: "password",
+                    r#type: "password",
+                    placeholder: "Password",
+                    autocomplete: "current-password",
+                    value: "{password}",
+                    oninput: move |event| password.set(event.value()),
+                }
+                if totp_required() {
+                    input {
+                        name: "totp-code",
+                        placeholder: "Authenticator or recovery code",
+                        autocomplete: "one-time-code",
+                        value: "{totp_code}",
+                        oninput: move |event| totp_code.set(event.value()),
+                    }
+                }
+                button {
+                    r#type: "submit",
+                    "Login"
+                }
+            }
+        }
+    }
+}
+
+/// Two factor authentication settings for the logged in user.
+#[component]
+fn Security() -> Element {
+    let mut user = use_context::<Signal<Option<User>>>();
+    let mut enrolment: Signal<Option<TotpEnrolment>> = use_signal(|| None);
+    let mut recovery_codes: Signal<Option<Vec<String>>> = use_signal(|| None);
+    let mut totp_code = use_signal(String::new);
+    let mut security_error: Signal<Option<ServerFnError>> = use_signal(|| None);
+
+    let Some(current_user) = user() else {
+        return rsx! {
+            div {
+                id: "security",
+                p { "You must be logged in to change your security settings." }
+            }
+        };
+    };
+
+    rsx! {
+        div {
+            id: "security",
+            h1 { "Two Factor Authentication" }
+            if let Some(err) = security_error() {
+                div {
+                    class: "alert alert-danger",
+                    "{err}"
+                }
+            }
+            if let Some(codes) = recovery_codes() {
+                div {
+                    class: "alert alert-success",
+                    p { "Two factor authentication is enabled. Keep these recovery codes somewhere safe, they will not be shown again." }
+                    ul {
+                        for recovery_code in codes {
+                            li { code { "{recovery_code}" } }
+                        }
+                    }
+                }
+            } else if current_user.totp_enabled {
+                p { "Two factor authentication is enabled. Ask an administrator to reset it if you lose your device." }
+            } else if let Some(pending) = enrolment() {
+                p { "Scan this code with your authenticator app, then enter the code it shows." }
+                div { dangerous_inner_html: "{pending.qr_svg}" }
+                p { "Secret: " code { "{pending.secret}" } }
+                form {
+                    onsubmit: move |event| async move {
+                        event.prevent_default();
+                        match confirm_totp_enrolment(totp_code()).await {
+                            Ok(codes) => {
+                                recovery_codes.set(Some(codes));
+                                enrolment.set(None);
+                                security_error.set(None);
+                                user.set(get_current_user().await.ok().flatten());
+                            }
+                            Err(err) => security_error.set(Some(err)),
+                        }
+                    },
+                    input {
+                        name: "totp-code",
+                        placeholder: "Code",
+                        autocomplete: "one-time-code",
+                        value: "{totp_code}",
+                        oninput: move |event| totp_code.set(event.value()),
+                    }
+                    button {
+                        r#type: "submit",
+                        "Enable"
+                    }
+                }
+            } else {
+                button {
+                    onclick: move |_| async move {
+                        match begin_totp_enrolment().await {
+                            Ok(pending) => {
+                                enrolment.set(Some(pending));
+                                security_error.set(None);
+                            }
+                            Err(err) => security_error.set(Some(err)),
+                        }
+                    },
+                    "Set up two factor authentication"
+                }
+            }
+        }
+    }
+}
+
+/// Management of the logged in user's API tokens.
+#[component]
+fn ApiTokens() -> Element {
+    let mut tokens = use_resource(list_api_tokens);
+    let mut created: Signal<Option<NewApiToken>> = use_signal(|| None);
+    let mut tokens_error: Signal<Option<ServerFnError>> = use_signal(|| None);
+    let mut name = use_signal(String::new);
+    let mut scope = use_signal(|| ApiTokenScope::Read);
+    let mut lifetime_days = use_signal(|| 90i64);
+
+    rsx! {
+        div {
+            id: "api-tokens",
+            h1 { "API Tokens" }
+            p { "Send a token in the " code { "Authorization: Bearer" } " header." }
+            if let Some(err) = tokens_error() {
+                div {
+                    class: "alert alert-danger",
+                    "{err}"
+                }
+            }
+            if let Some(new_token) = created() {
+                div {
+                    class: "alert alert-success",
+                    p { "Created token {new_token.token.name}. Copy it now, it will not be shown again." }
+                    code { "{new_token.secret}" }
+                }
+            }
+            form {
+                onsubmit: move |event| async move {
+                    event.prevent_default();
+                    match create_api_token(name(), scope(), lifetime_days()).await {
+                        Ok(new_token) => {
+                            created.set(Some(new_token));
+                            tokens_error.set(None);
+                            name.set(String::new());
+                        }
+                        Err(err) => tokens_error.set(Some(err)),
+                    }
+                    tokens.restart();
+                },
+                input {
+                    name: "name",
+                    placeholder: "Token name",
+                    value: "{name}",
+                    oninput: move |event| name.set(event.value()),
+                }
+                select {
+                    onchange: move |event| {
+                        scope.set(if event.value() == "write" { ApiTokenScope::Write } else { ApiTokenScope::Read });
+                    },
+                    option { value: "read", selected: scope() == ApiTokenScope::Read, "Read" }
+                    option { value: "write", selected: scope() == ApiTokenScope::Write, "Write" }
+                }
+                select {
+                    onchange: move |event| {
+                        if let Ok(days) = event.value().parse() {
+                            lifetime_days.set(days);
+                        }
+                    },
+                    for days in [7i64, 30, 90, 365] {
+                        option { value: "{days}", selected: lifetime_days() == days, "{days} days" }
+                    }
+                }
+                button {
+                    r#type: "submit",
+                    "Create Token"
+                }
+            }
+            ul {
+                for maybe_tokens in &*tokens.read() {
+                    match maybe_tokens {
+                        Ok(tokens_list) => {
+                            let timezone = chrono::Local::now().timezone();
+
+                            rsx! {
+                                for token in tokens_list {
+                                    {
+                                        let id = token.id;
+                                        let expires_at = token.expires_at.with_timezone(&timezone);
+                                        let last_used = match token.last_used_at {
+                                            Some(last_used_at) => last_used_at.with_timezone(&timezone).to_string(),
+                                            None => "never".to_string(),
+                                        };
+                                        rsx! {
+                                            li {
+                                                "{token.name} ({token.scope}), expires {expires_at}, last used {last_used}"
+                                                button {
+                                                    onclick: move |_| async move {
+                                                        match revoke_api_token(id).await {
+                                                            Ok(()) => tokens_error.set(None),
+                                                            Err(err) => tokens_error.set(Some(err)),
+                                                        }
+                                                        tokens.restart();
+                                                    },
+                                                    "Revoke"
+                                                }
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                        Err(err) => {
+                            rsx! {
+                                li {
+                                    "Error loading API tokens: {err}"
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// User administration.
+#[component]
+fn Users() -> Element {
+    let mut users = use_resource(list_users);
+    let mut users_error: Signal<Option<ServerFnError<AccessError>>> = use_signal(|| None);
+
+    rsx! {
+        div {
+            id: "users",
+            h1 { "Users" }
+            if let Some(err) = users_error() {
+                div {
+                    class: "alert alert-danger",
+                    "{err}"
+                }
+            }
+            ul {
+                for maybe_users in &*users.read() {
+                    match maybe_users {
+                        Ok(users_list) => {
+                            rsx! {
+                                for listed_user in users_list {
+                                    {
+                                        let id = listed_user.id;
+                                        rsx! {
+                                            li {
+                                                "{listed_user.username} ({listed_user.role})"
+                                                if listed_user.totp_enabled {
+                                                    button {
+                                                        onclick: move |_| async move {
+                                                            match reset_user_totp(id).await {
+                                                                Ok(()) => users_error.set(None),
+                                                                Err(err) => users_error.set(Some(err)),
+                                                            }
+                                                            users.restart();
+                                                        },
+                                                        "Reset two factor authentication"
+                                                    }
+                                                }
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                        Err(err) => {
+                            rsx! {
+                                li {
+                                    "Error loading users: {err}"
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
 #[component]
 fn NotFound(segments: Vec<String>) -> Element {
     let segments = segments.join(" / ");
@@ -293,9 +747,19 @@ fn NotFound(segments: Vec<String>) -> Element {
 
 #[component]
 fn PenguinEncounters() -> Element {
-    let mut encounters = use_resource(get_penguin_encounters);
-    let mut save_result: Signal<Option<Result<PenguinEncounter, ServerFnError>>> =
+    let role = use_context::<Signal<Option<Role>>>();
+    let mut encounters = use_resource(move || async move {
+        // Reload when the role changes, e.g. after logging in.
+        let _ = role();
+        telemetry::traced("GetPenguinEncounters", get_penguin_encounters()).await
+    });
+    let mut save_result: Signal<Option<Result<PenguinEncounter, ServerFnError<AccessError>>>> =
         use_signal(|| None);
+    let mut delete_error: Signal<Option<ServerFnError<AccessError>>> = use_signal(|| None);
+    let mut penalty = use_signal(|| PenaltyEnum::PatPenguin);
+
+    let can_create = role().is_some_and(|role| role.allows(Role::Recorder));
+    let can_delete = role().is_some_and(|role| role.allows(Role::Admin));
 
     rsx! {
         div {
@@ -318,11 +782,36 @@ fn PenguinEncounters() -> Element {
                         }
                     }
                 }
-                None => {
+                None if can_create => {
                     rsx! {
+                        select {
+                            onchange: move |event| {
+                                if let Some(selected) = event
+                                    .value()
+                                    .parse::<usize>()
+                                    .ok()
+                                    .and_then(|index| PenaltyEnum::ALL.get(index))
+                                {
+                                    penalty.set(*selected);
+                                }
+                            },
+                            for (index, option) in PenaltyEnum::ALL.iter().enumerate() {
+                                if role().is_some_and(|role| role.allows(option.required_role())) {
+                                    option {
+                                        value: "{index}",
+                                        selected: *option == penalty(),
+                                        "{option}"
+                                    }
+                                }
+                            }
+                        }
                         button {
                             onclick: move |_| async move {
-                                let result = create_penguin_encounter().await;
+                                let result = telemetry::traced(
+                                    "CreatePenguinEncounter",
+                                    create_penguin_encounter(penalty()),
+                                )
+                                .await;
                                 save_result.set(Some(result));
                                 encounters.restart();
                             },
@@ -330,22 +819,49 @@ fn PenguinEncounters() -> Element {
                         }
                     }
                 }
+                None => {
+                    rsx! {}
+                }
             }
 
+            if let Some(err) = delete_error() {
+                div {
+                    class: "alert alert-danger",
+                    "Error deleting penguin encounter: {err}"
+                }
+            }
 
             ul {
                 for maybe_encounters in &*encounters.read() {
                     match maybe_encounters {
-                        Ok(encounters) => {
+                        Ok(penguin_encounters) => {
                             let timezone = chrono::Local::now().timezone();
 
                             rsx! {
-                                for encounter in encounters {
+                                for encounter in penguin_encounters {
                                     {
+                                        let id = encounter.id;
                                         let date_time = encounter.date_time.with_timezone(&timezone);
                                         rsx!{
                                             li {
                                                 "Name: {encounter.name}, Location: {encounter.location}, Penalty: {encounter.penalty}, Date: {date_time}"
+                                                if can_delete {
+                                                    button {
+                                                        onclick: move |_| async move {
+                                                            match telemetry::traced(
+                                                                "DeletePenguinEncounter",
+                                                                delete_penguin_encounter(id),
+                                                            )
+                                                            .await
+                                                            {
+                                                                Ok(()) => delete_error.set(None),
+                                                                Err(err) => delete_error.set(Some(err)),
+                                                            }
+                                                            encounters.restart();
+                                                        },
+                                                        "Delete"
+                                                    }
+                                                }
                                             }
                                         }
                                     }
@@ -370,7 +886,7 @@ fn PenguinEncounters() -> Element {
 #[server(EchoServer)]
 async fn echo_server(input: String) -> Result<String, ServerFnError> {
     let FromContext::<MyContext>(context) = extract().await?;
-    Ok(context.title.to_string() + ": " + &input.to_uppercase())
+    Ok(functions::echo(&context, &input))
 }
 
 #[server(MagicNumber)]
@@ -379,40 +895,195 @@ async fn magic_number() -> Result<u32, ServerFnError> {
     Ok(magic_number)
 }
 
-#[server(GetPenguinEncounters)]
-async fn get_penguin_encounters() -> Result<Vec<PenguinEncounter>, ServerFnError> {
-    let FromContext::<database::DatabasePool>(pool) = extract().await?;
+#[cfg_attr(
+    target_arch = "wasm32",
+    server(GetPenguinEncounters, client = telemetry::TracingClient)
+)]
+#[cfg_attr(not(target_arch = "wasm32"), server(GetPenguinEncounters))]
+async fn get_penguin_encounters() -> Result<Vec<PenguinEncounter>, ServerFnError<AccessError>> {
+    require_role(Role::Viewer).await?;
+    let encounters: server::repository::Encounters = functions::context().map_err(server_error)?;
+    functions::get_penguin_encounters(&encounters).await
+}
+
+#[cfg_attr(
+    target_arch = "wasm32",
+    server(CreatePenguinEncounter, client = telemetry::TracingClient)
+)]
+#[cfg_attr(not(target_arch = "wasm32"), server(CreatePenguinEncounter))]
+async fn create_penguin_encounter(
+    penalty: PenaltyEnum,
+) -> Result<PenguinEncounter, ServerFnError<AccessError>> {
+    require_role(penalty.required_role()).await?;
+    let encounters: server::repository::Encounters = functions::context().map_err(server_error)?;
+    let FromContext::<server::events::EncounterEvents>(events) =
+        extract().await.map_err(server_error)?;
+    functions::create_penguin_encounter(&encounters, &events, penalty).await
+}
+
+#[cfg_attr(
+    target_arch = "wasm32",
+    server(DeletePenguinEncounter, client = telemetry::TracingClient)
+)]
+#[cfg_attr(not(target_arch = "wasm32"), server(DeletePenguinEncounter))]
+async fn delete_penguin_encounter(id: i32) -> Result<(), ServerFnError<AccessError>> {
+    require_role(Role::Admin).await?;
+    let encounters: server::repository::Encounters = functions::context().map_err(server_error)?;
+    functions::delete_penguin_encounter(&encounters, id).await
+}
+
+#[server(Login)]
+async fn login(
+    username: String,
+    password: String,
+    totp_code: Option<String>,
+) -> Result<LoginResult, ServerFnError> {
+    use axum::http::header::SET_COOKIE;
+
+    let accounts: server::repository::Accounts = functions::context()?;
+    let axum::Extension(limiter): axum::Extension<server::rate_limit::RateLimiter> =
+        extract().await?;
+    let result = auth::login(
+        &accounts,
+        &limiter,
+        &username,
+        &password,
+        totp_code.as_deref(),
+    )
+    .await;
+    let (user, token) = match result {
+        Ok(result) => result,
+        Err(auth::AuthError::TotpRequired) => return Ok(LoginResult::TotpRequired),
+        Err(err) => return Err(err.into()),
+    };
+
+    server_context()
+        .response_parts_mut()
+        .headers
+        .insert(SET_COOKIE, auth::session_cookie(&token));
+
+    Ok(LoginResult::LoggedIn(user))
+}
+
+#[server(Logout)]
+async fn logout() -> Result<(), ServerFnError> {
+    use axum::http::header::SET_COOKIE;
 
-    let mut connection = pool
-        .get()
+    let accounts: server::repository::Accounts = functions::context()?;
+    let context = server_context();
+
+    let token = auth::session_token(&context.request_parts().headers);
+    if let Some(token) = token {
+        auth::logout(&accounts, &token).await?;
+    }
+
+    context
+        .response_parts_mut()
+        .headers
+        .insert(SET_COOKIE, auth::clear_session_cookie());
+
+    Ok(())
+}
+
+#[server(GetCurrentUser)]
+async fn get_current_user() -> Result<Option<User>, ServerFnError> {
+    let user: Option<CurrentUser> = extract().await?;
+    Ok(user.map(|CurrentUser(user)| user))
+}
+
+#[server(BeginTotpEnrolment)]
+async fn begin_totp_enrolment() -> Result<TotpEnrolment, ServerFnError> {
+    let accounts: server::repository::Accounts = functions::context()?;
+    let SessionUser(user) = extract().await?;
+    Ok(server::totp::begin_enrolment(&accounts, &user).await?)
+}
+
+#[server(ConfirmTotpEnrolment)]
+async fn confirm_totp_enrolment(code: String) -> Result<Vec<String>, ServerFnError> {
+    let accounts: server::repository::Accounts = functions::context()?;
+    let axum::Extension(limiter): axum::Extension<server::rate_limit::RateLimiter> =
+        extract().await?;
+    let SessionUser(user) = extract().await?;
+    Ok(server::totp::confirm(&accounts, &limiter, &user, &code).await?)
+}
+
+#[server(ListUsers)]
+async fn list_users() -> Result<Vec<User>, ServerFnError<AccessError>> {
+    require_role(Role::Admin).await?;
+    let accounts: server::repository::Accounts = functions::context().map_err(server_error)?;
+    accounts
+        .list_users()
         .await
-        .map_err(|err| ServerFnError::<NoCustomError>::ServerError(err.to_string()))?;
+        .map_err(|err| ServerFnError::ServerError(err.to_string()))
+}
 
-    let penguin_encounters = list_penguin_encounters(&mut connection)
+#[server(ResetUserTotp)]
+async fn reset_user_totp(user_id: i32) -> Result<(), ServerFnError<AccessError>> {
+    require_role(Role::Admin).await?;
+    let accounts: server::repository::Accounts = functions::context().map_err(server_error)?;
+    if !server::totp::reset(&accounts, user_id)
         .await
-        .map_err(|err| ServerFnError::<NoCustomError>::ServerError(err.to_string()))?;
+        .map_err(server_error)?
+    {
+        return Err(ServerFnError::ServerError(format!(
+            "User {user_id} not found"
+        )));
+    }
+    Ok(())
+}
 
-    Ok(penguin_encounters)
+#[server(ListApiTokens)]
+async fn list_api_tokens() -> Result<Vec<ApiToken>, ServerFnError> {
+    let accounts: server::repository::Accounts = functions::context()?;
+    let SessionUser(user) = extract().await?;
+    Ok(server::api_tokens::list(&accounts, &user).await?)
 }
 
-#[server(CreatePenguinEncounter)]
-async fn create_penguin_encounter() -> Result<PenguinEncounter, ServerFnError> {
-    let FromContext::<database::DatabasePool>(pool) = extract().await?;
+#[server(CreateApiToken)]
+async fn create_api_token(
+    name: String,
+    scope: ApiTokenScope,
+    lifetime_days: i64,
+) -> Result<NewApiToken, ServerFnError> {
+    let accounts: server::repository::Accounts = functions::context()?;
+    let SessionUser(user) = extract().await?;
 
-    let mut connection = pool
-        .get()
-        .await
-        .map_err(|err| ServerFnError::<NoCustomError>::ServerError(err.to_string()))?;
-
-    let penguin_encounter = database::create_penguin_encounter(
-        &mut connection,
-        "Tux",
-        "Antarctica",
-        model::PenaltyEnum::PatPenguin,
-        chrono::Utc::now(),
-    )
-    .await
-    .map_err(|err| ServerFnError::<NoCustomError>::ServerError(err.to_string()))?;
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(ServerFnError::ServerError(
+            "Token name must not be empty".to_string(),
+        ));
+    }
+    if !(1..=365).contains(&lifetime_days) {
+        return Err(ServerFnError::ServerError(
+            "Tokens must expire within a year".to_string(),
+        ));
+    }
+
+    let lifetime = chrono::Duration::days(lifetime_days);
+    Ok(server::api_tokens::create(&accounts, &user, name, scope, lifetime).await?)
+}
+
+#[server(RevokeApiToken)]
+async fn revoke_api_token(id: i32) -> Result<(), ServerFnError> {
+    let accounts: server::repository::Accounts = functions::context()?;
+    let SessionUser(user) = extract().await?;
+    if !server::api_tokens::revoke(&accounts, &user, id).await? {
+        return Err(ServerFnError::ServerError(format!(
+            "API token {id} not found"
+        )));
+    }
+    Ok(())
+}
+
+#[server(OidcLoginEnabled)]
+async fn oidc_login_enabled() -> Result<bool, ServerFnError> {
+    let axum::Extension(oidc): axum::Extension<server::oidc::OidcState> = extract().await?;
+    Ok(oidc.is_configured())
+}
 
-    Ok(penguin_encounter)
+#[server(GetCurrentRole)]
+async fn get_current_role() -> Result<Option<Role>, ServerFnError> {
+    let CurrentRole(role) = extract().await?;
+    Ok(role)
 }
@@ -2,13 +2,16 @@ use chrono::Utc;
 use serde::{Deserialize, Serialize};
 
 #[cfg(feature = "server")]
-use crate::server::schema::penguin_encounter;
+use crate::server::schema::{api_tokens, penguin_encounter, users};
 
 #[cfg(feature = "server")]
 use diesel::prelude::*;
 
-#[derive(Serialize, Deserialize)]
-#[cfg_attr(feature = "server", derive(diesel_derive_enum::DbEnum, Debug))]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "server",
+    derive(diesel_derive_enum::DbEnum, utoipa::ToSchema, async_graphql::Enum)
+)]
 #[cfg_attr(
     feature = "server",
     ExistingTypePath = "crate::server::schema::sql_types::PenaltyEnum"
@@ -33,10 +36,112 @@ impl std::fmt::Display for PenaltyEnum {
     }
 }
 
-#[derive(Serialize, Deserialize)]
-#[cfg_attr(feature = "server", derive(Queryable, Selectable))]
+impl PenaltyEnum {
+    pub const ALL: [PenaltyEnum; 5] = [
+        PenaltyEnum::PatPenguin,
+        PenaltyEnum::BecomePenguinGood,
+        PenaltyEnum::Jail,
+        PenaltyEnum::Sacrifice,
+        PenaltyEnum::WorshipTux,
+    ];
+
+    /// The role needed to assign this penalty.
+    pub fn required_role(&self) -> Role {
+        match self {
+            PenaltyEnum::Sacrifice => Role::Admin,
+            _ => Role::Recorder,
+        }
+    }
+}
+
+/// User roles, in increasing order of privilege.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(
+    feature = "server",
+    derive(diesel_derive_enum::DbEnum, async_graphql::Enum)
+)]
+#[cfg_attr(
+    feature = "server",
+    ExistingTypePath = "crate::server::schema::sql_types::UserRole"
+)]
+pub enum Role {
+    Viewer,
+    Recorder,
+    Admin,
+}
+
+impl Role {
+    /// Does this role include the privileges of `required`?
+    pub fn allows(&self, required: Role) -> bool {
+        *self >= required
+    }
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Role::Viewer => write!(f, "viewer"),
+            Role::Recorder => write!(f, "recorder"),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+impl std::str::FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "viewer" => Ok(Role::Viewer),
+            "recorder" => Ok(Role::Recorder),
+            "admin" => Ok(Role::Admin),
+            _ => Err(format!("Unknown role: {s}")),
+        }
+    }
+}
+
+/// Typed error returned by server functions that are guarded by a role.
+///
+/// The `Display` and `FromStr` implementations must round trip, as this is how
+/// the error is sent to the client.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AccessError {
+    NotLoggedIn,
+    Forbidden(Role),
+}
+
+impl std::fmt::Display for AccessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AccessError::NotLoggedIn => write!(f, "Not logged in"),
+            AccessError::Forbidden(role) => write!(f, "Requires {role} role"),
+        }
+    }
+}
+
+impl std::str::FromStr for AccessError {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "Not logged in" {
+            return Ok(AccessError::NotLoggedIn);
+        }
+        s.strip_prefix("Requires ")
+            .and_then(|s| s.strip_suffix(" role"))
+            .ok_or_else(|| format!("Unknown access error: {s}"))?
+            .parse()
+            .map(AccessError::Forbidden)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(
+    feature = "server",
+    derive(Queryable, Selectable, utoipa::ToSchema, async_graphql::SimpleObject)
+)]
 #[cfg_attr(feature = "server", diesel(table_name = penguin_encounter))]
 #[cfg_attr(feature = "server", diesel(check_for_backend(diesel::pg::Pg)))]
+#[cfg_attr(feature = "server", graphql(complex))]
 pub struct PenguinEncounter {
     pub id: i32,
     pub name: String,
@@ -46,7 +151,7 @@ pub struct PenguinEncounter {
 }
 
 #[allow(dead_code)]
-#[cfg_attr(feature = "server", derive(Insertable))]
+#[cfg_attr(feature = "server", derive(Insertable, AsChangeset))]
 #[cfg_attr(feature = "server", diesel(table_name = penguin_encounter))]
 pub struct CreatePenguinEncounter<'a> {
     pub name: &'a str,
@@ -54,3 +159,81 @@ pub struct CreatePenguinEncounter<'a> {
     pub penalty: PenaltyEnum,
     pub date_time: chrono::DateTime<Utc>,
 }
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "server", derive(Queryable, Selectable))]
+#[cfg_attr(feature = "server", diesel(table_name = users))]
+#[cfg_attr(feature = "server", diesel(check_for_backend(diesel::pg::Pg)))]
+pub struct User {
+    pub id: i32,
+    pub username: String,
+    pub role: Role,
+    pub totp_enabled: bool,
+}
+
+/// Outcome of a password login.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum LoginResult {
+    LoggedIn(User),
+    /// The password was correct, but a TOTP or recovery code is also needed.
+    TotpRequired,
+}
+
+/// A new TOTP secret waiting to be confirmed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TotpEnrolment {
+    pub secret: String,
+    pub qr_svg: String,
+}
+
+/// What an API token may be used for.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "server", derive(diesel_derive_enum::DbEnum))]
+#[cfg_attr(
+    feature = "server",
+    ExistingTypePath = "crate::server::schema::sql_types::ApiTokenScope"
+)]
+pub enum ApiTokenScope {
+    Read,
+    Write,
+}
+
+impl ApiTokenScope {
+    /// The most privileged role a token with this scope can act as. Tokens
+    /// never act as admins, so admin operations need a session.
+    pub fn max_role(&self) -> Role {
+        match self {
+            ApiTokenScope::Read => Role::Viewer,
+            ApiTokenScope::Write => Role::Recorder,
+        }
+    }
+}
+
+impl std::fmt::Display for ApiTokenScope {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ApiTokenScope::Read => write!(f, "Read"),
+            ApiTokenScope::Write => write!(f, "Write"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "server", derive(Queryable, Selectable))]
+#[cfg_attr(feature = "server", diesel(table_name = api_tokens))]
+#[cfg_attr(feature = "server", diesel(check_for_backend(diesel::pg::Pg)))]
+pub struct ApiToken {
+    pub id: i32,
+    pub name: String,
+    pub scope: ApiTokenScope,
+    pub created_at: chrono::DateTime<Utc>,
+    pub expires_at: chrono::DateTime<Utc>,
+    pub last_used_at: Option<chrono::DateTime<Utc>>,
+}
+
+/// A newly created token, the only time the secret is available.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NewApiToken {
+    pub token: ApiToken,
+    pub secret: String,
+}
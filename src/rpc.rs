@@ -0,0 +1,207 @@
+//! Server function calls multiplexed over a single websocket.
+//!
+//! Each request carries a correlation id which the server echoes back in the
+//! response, so many calls can be in flight on the one connection at once.
+
+use std::collections::HashMap;
+
+use dioxus::prelude::*;
+use futures::channel::oneshot;
+use futures::{SinkExt, StreamExt};
+use gloo_net::websocket::futures::WebSocket;
+use gloo_net::websocket::Message;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error};
+
+use crate::model::{PenaltyEnum, PenguinEncounter};
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "name", content = "args")]
+pub enum RpcCall {
+    EchoServer { input: String },
+    MagicNumber,
+    GetPenguinEncounters,
+    CreatePenguinEncounter { penalty: PenaltyEnum },
+    DeletePenguinEncounter { id: i32 },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "name", content = "value")]
+pub enum RpcReply {
+    EchoServer(String),
+    MagicNumber(u32),
+    GetPenguinEncounters(Vec<PenguinEncounter>),
+    CreatePenguinEncounter(PenguinEncounter),
+    DeletePenguinEncounter,
+}
+
+impl RpcCall {
+    /// The name of the equivalent `#[server]` function.
+    pub fn name(&self) -> &'static str {
+        match self {
+            RpcCall::EchoServer { .. } => "EchoServer",
+            RpcCall::MagicNumber => "MagicNumber",
+            RpcCall::GetPenguinEncounters => "GetPenguinEncounters",
+            RpcCall::CreatePenguinEncounter { .. } => "CreatePenguinEncounter",
+            RpcCall::DeletePenguinEncounter { .. } => "DeletePenguinEncounter",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RpcRequest {
+    pub id: u64,
+    pub call: RpcCall,
+    /// W3C trace context of the caller, as in the `traceparent` header.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub traceparent: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RpcResponse {
+    pub id: u64,
+    pub result: Result<RpcReply, String>,
+}
+
+type Pending = oneshot::Sender<Result<RpcReply, String>>;
+
+/// A call waiting to be sent.
+struct Queued {
+    call: RpcCall,
+    traceparent: Option<String>,
+    reply: Pending,
+}
+
+/// Which transport to use for server function calls.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transport {
+    Http,
+    Websocket,
+}
+
+/// Client side of the websocket transport.
+///
+/// The connection is opened lazily on the first call and reopened on the next
+/// call after it drops.
+#[derive(Clone, Copy)]
+pub struct RpcClient {
+    tx: Coroutine<Queued>,
+}
+
+pub fn use_rpc_client() -> RpcClient {
+    let tx = use_coroutine(move |mut rx: UnboundedReceiver<Queued>| async move {
+        let mut next_id: u64 = 0;
+
+        while let Some(first) = rx.next().await {
+            let url = crate::get_websocket_url("/_rpc");
+            debug!("Connecting to rpc websocket at {url}");
+            let mut socket = match WebSocket::open(&url) {
+                Ok(socket) => socket,
+                Err(err) => {
+                    let _ = first.reply.send(Err(err.to_string()));
+                    continue;
+                }
+            };
+
+            let mut pending: HashMap<u64, Pending> = HashMap::new();
+            let mut queued = Some(first);
+
+            loop {
+                if let Some(Queued {
+                    call,
+                    traceparent,
+                    reply,
+                }) = queued.take()
+                {
+                    next_id += 1;
+                    let request = RpcRequest {
+                        id: next_id,
+                        call,
+                        traceparent,
+                    };
+                    let text = match serde_json::to_string(&request) {
+                        Ok(text) => text,
+                        Err(err) => {
+                            let _ = reply.send(Err(err.to_string()));
+                            continue;
+                        }
+                    };
+                    pending.insert(next_id, reply);
+                    if let Err(err) = socket.send(Message::Text(text)).await {
+                        error!("Error sending rpc request: {:?}", err);
+                        break;
+                    }
+                    continue;
+                }
+
+                match futures::future::select(rx.next(), socket.next()).await {
+                    futures::future::Either::Left((msg, _)) => match msg {
+                        Some(msg) => queued = Some(msg),
+                        None => return,
+                    },
+                    futures::future::Either::Right((msg, _)) => match msg {
+                        Some(Ok(Message::Text(msg))) => {
+                            match serde_json::from_str::<RpcResponse>(&msg) {
+                                Ok(response) => {
+                                    if let Some(reply) = pending.remove(&response.id) {
+                                        let _ = reply.send(response.result);
+                                    }
+                                }
+                                Err(err) => {
+                                    error!("Invalid rpc response: {:?}", err);
+                                }
+                            }
+                        }
+                        Some(Ok(Message::Bytes(msg))) => {
+                            error!("Received binary message: {:?}", msg);
+                        }
+                        Some(Err(err)) => {
+                            error!("Error: {:?}", err);
+                            break;
+                        }
+                        None => {
+                            break;
+                        }
+                    },
+                }
+            }
+
+            for (_, reply) in pending.drain() {
+                let _ = reply.send(Err("Connection to server lost".to_string()));
+            }
+            debug!("Disconnected from rpc websocket");
+        }
+    });
+
+    RpcClient { tx }
+}
+
+impl RpcClient {
+    pub async fn call(&self, call: RpcCall) -> Result<RpcReply, ServerFnError> {
+        let name = call.name();
+        crate::telemetry::traced(name, async move {
+            let (reply_tx, reply_rx) = oneshot::channel();
+            self.tx.send(Queued {
+                call,
+                traceparent: crate::telemetry::current_traceparent(),
+                reply: reply_tx,
+            });
+            match reply_rx.await {
+                Ok(reply) => reply.map_err(ServerFnError::ServerError),
+                Err(_) => Err(ServerFnError::Request("Request was cancelled".to_string())),
+            }
+        })
+        .await
+    }
+
+    pub async fn echo_server(&self, input: String) -> Result<String, ServerFnError> {
+        match self.call(RpcCall::EchoServer { input }).await? {
+            RpcReply::EchoServer(output) => Ok(output),
+            _ => Err(unexpected_reply()),
+        }
+    }
+}
+
+fn unexpected_reply() -> ServerFnError {
+    ServerFnError::Deserialization("Unexpected reply from server".to_string())
+}
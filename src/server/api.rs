@@ -0,0 +1,543 @@
+//! Versioned JSON REST API for penguin encounters.
+//!
+//! Authenticated the same way as server functions, with a session cookie or an
+//! API token. The OpenAPI document is served at `/api/v1/openapi.json`.
+
+use axum::extract::{Path, Query};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Extension, Json, Router};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{IntoParams, Modify, OpenApi, ToSchema};
+
+use crate::model::{AccessError, CreatePenguinEncounter, PenaltyEnum, PenguinEncounter, Role};
+use crate::server::authz::{self, CurrentRole};
+use crate::server::events::EncounterEvents;
+use crate::server::repository::{EncounterFilter, Encounters, RepositoryError};
+
+const DEFAULT_LIMIT: i64 = 50;
+const MAX_LIMIT: i64 = 500;
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Penguin Encounters API", version = "1"),
+    paths(
+        list_encounters,
+        get_encounter,
+        create_encounter,
+        update_encounter,
+        delete_encounter
+    ),
+    components(schemas(PenguinEncounter, PenaltyEnum, EncounterInput, EncounterPage, ErrorBody)),
+    modifiers(&SecurityAddon),
+    security(("bearer" = [])),
+    tags((name = "encounters", description = "Penguin encounters"))
+)]
+pub struct ApiDoc;
+
+struct SecurityAddon;
+
+impl Modify for SecurityAddon {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+    }
+}
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/openapi.json", get(openapi))
+        .route("/encounters", get(list_encounters).post(create_encounter))
+        .route(
+            "/encounters/:id",
+            get(get_encounter)
+                .put(update_encounter)
+                .delete(delete_encounter),
+        )
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ErrorBody {
+    error: String,
+}
+
+#[derive(Debug)]
+pub enum ApiError {
+    BadRequest(String),
+    Access(AccessError),
+    NotFound,
+    Unavailable(String),
+    Internal(String),
+}
+
+impl From<RepositoryError> for ApiError {
+    fn from(err: RepositoryError) -> Self {
+        match err {
+            RepositoryError::Unavailable(_) => ApiError::Unavailable(err.to_string()),
+            RepositoryError::Database(_) => ApiError::Internal(err.to_string()),
+        }
+    }
+}
+
+impl From<AccessError> for ApiError {
+    fn from(err: AccessError) -> Self {
+        ApiError::Access(err)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            ApiError::BadRequest(message) => (StatusCode::BAD_REQUEST, message),
+            ApiError::Access(err @ AccessError::NotLoggedIn) => {
+                (StatusCode::UNAUTHORIZED, err.to_string())
+            }
+            ApiError::Access(err @ AccessError::Forbidden(_)) => {
+                (StatusCode::FORBIDDEN, err.to_string())
+            }
+            ApiError::NotFound => (StatusCode::NOT_FOUND, "Not found".to_string()),
+            ApiError::Unavailable(message) => (StatusCode::SERVICE_UNAVAILABLE, message),
+            ApiError::Internal(message) => {
+                error!("API error: {message}");
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Internal server error".to_string(),
+                )
+            }
+        };
+        (status, Json(ErrorBody { error: message })).into_response()
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct EncounterQuery {
+    /// Only encounters with this exact name.
+    name: Option<String>,
+    /// Only encounters at this exact location.
+    location: Option<String>,
+    /// Only encounters with this penalty.
+    #[param(inline)]
+    penalty: Option<PenaltyEnum>,
+    /// Only encounters at or after this time.
+    from: Option<chrono::DateTime<chrono::Utc>>,
+    /// Only encounters before this time.
+    to: Option<chrono::DateTime<chrono::Utc>>,
+    /// Maximum number of encounters to return, at most 500.
+    limit: Option<i64>,
+    /// Number of encounters to skip.
+    offset: Option<i64>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct EncounterPage {
+    items: Vec<PenguinEncounter>,
+    total: i64,
+    limit: i64,
+    offset: i64,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct EncounterInput {
+    name: String,
+    location: String,
+    penalty: PenaltyEnum,
+    date_time: chrono::DateTime<chrono::Utc>,
+}
+
+impl EncounterInput {
+    fn validate(&self) -> Result<(), ApiError> {
+        if self.name.trim().is_empty() {
+            return Err(ApiError::BadRequest("name must not be empty".to_string()));
+        }
+        if self.location.trim().is_empty() {
+            return Err(ApiError::BadRequest(
+                "location must not be empty".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn as_new(&self) -> CreatePenguinEncounter<'_> {
+        CreatePenguinEncounter {
+            name: &self.name,
+            location: &self.location,
+            penalty: self.penalty,
+            date_time: self.date_time,
+        }
+    }
+}
+
+async fn openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+/// List encounters, newest first.
+#[utoipa::path(
+    get,
+    path = "/api/v1/encounters",
+    tag = "encounters",
+    params(EncounterQuery),
+    responses(
+        (status = 200, description = "A page of encounters", body = EncounterPage),
+        (status = 400, description = "Invalid query", body = ErrorBody),
+        (status = 401, description = "Not logged in", body = ErrorBody),
+    )
+)]
+async fn list_encounters(
+    Extension(encounters): Extension<Encounters>,
+    CurrentRole(role): CurrentRole,
+    Query(query): Query<EncounterQuery>,
+) -> Result<Json<EncounterPage>, ApiError> {
+    authz::check(role, Role::Viewer)?;
+
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+    if !(1..=MAX_LIMIT).contains(&limit) {
+        return Err(ApiError::BadRequest(format!(
+            "limit must be between 1 and {MAX_LIMIT}"
+        )));
+    }
+    let offset = query.offset.unwrap_or(0);
+    if offset < 0 {
+        return Err(ApiError::BadRequest(
+            "offset must not be negative".to_string(),
+        ));
+    }
+
+    let filter = EncounterFilter {
+        name: query.name,
+        location: query.location,
+        penalty: query.penalty,
+        from: query.from,
+        to: query.to,
+    };
+
+    let (items, total) = encounters.search(&filter, limit, offset).await?;
+
+    Ok(Json(EncounterPage {
+        items,
+        total,
+        limit,
+        offset,
+    }))
+}
+
+/// Get a single encounter.
+#[utoipa::path(
+    get,
+    path = "/api/v1/encounters/{id}",
+    tag = "encounters",
+    params(("id" = i32, Path, description = "Encounter id")),
+    responses(
+        (status = 200, description = "The encounter", body = PenguinEncounter),
+        (status = 401, description = "Not logged in", body = ErrorBody),
+        (status = 404, description = "No such encounter", body = ErrorBody),
+    )
+)]
+async fn get_encounter(
+    Extension(encounters): Extension<Encounters>,
+    CurrentRole(role): CurrentRole,
+    Path(id): Path<i32>,
+) -> Result<Json<PenguinEncounter>, ApiError> {
+    authz::check(role, Role::Viewer)?;
+
+    encounters
+        .get(id)
+        .await?
+        .map(Json)
+        .ok_or(ApiError::NotFound)
+}
+
+/// Create an encounter. Assigning `Sacrifice` requires the admin role.
+#[utoipa::path(
+    post,
+    path = "/api/v1/encounters",
+    tag = "encounters",
+    request_body = EncounterInput,
+    responses(
+        (status = 201, description = "The created encounter", body = PenguinEncounter),
+        (status = 400, description = "Invalid encounter", body = ErrorBody),
+        (status = 401, description = "Not logged in", body = ErrorBody),
+        (status = 403, description = "Role does not allow this", body = ErrorBody),
+    )
+)]
+async fn create_encounter(
+    Extension(encounters): Extension<Encounters>,
+    Extension(events): Extension<EncounterEvents>,
+    CurrentRole(role): CurrentRole,
+    Json(input): Json<EncounterInput>,
+) -> Result<(StatusCode, Json<PenguinEncounter>), ApiError> {
+    authz::check(role, input.penalty.required_role())?;
+    input.validate()?;
+
+    let encounter = encounters.create(&input.as_new()).await?;
+
+    events.created(&encounter);
+    Ok((StatusCode::CREATED, Json(encounter)))
+}
+
+/// Replace an encounter. Assigning `Sacrifice` requires the admin role.
+#[utoipa::path(
+    put,
+    path = "/api/v1/encounters/{id}",
+    tag = "encounters",
+    params(("id" = i32, Path, description = "Encounter id")),
+    request_body = EncounterInput,
+    responses(
+        (status = 200, description = "The updated encounter", body = PenguinEncounter),
+        (status = 400, description = "Invalid encounter", body = ErrorBody),
+        (status = 401, description = "Not logged in", body = ErrorBody),
+        (status = 403, description = "Role does not allow this", body = ErrorBody),
+        (status = 404, description = "No such encounter", body = ErrorBody),
+    )
+)]
+async fn update_encounter(
+    Extension(encounters): Extension<Encounters>,
+    CurrentRole(role): CurrentRole,
+    Path(id): Path<i32>,
+    Json(input): Json<EncounterInput>,
+) -> Result<Json<PenguinEncounter>, ApiError> {
+    authz::check(role, input.penalty.required_role())?;
+    input.validate()?;
+
+    // Taking a sacrifice away is as privileged as assigning one.
+    if let Some(existing) = encounters.get(id).await? {
+        authz::check(role, existing.penalty.required_role())?;
+    }
+
+    encounters
+        .update(id, &input.as_new())
+        .await?
+        .map(Json)
+        .ok_or(ApiError::NotFound)
+}
+
+/// Delete an encounter. Requires the admin role.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/encounters/{id}",
+    tag = "encounters",
+    params(("id" = i32, Path, description = "Encounter id")),
+    responses(
+        (status = 204, description = "The encounter was deleted"),
+        (status = 401, description = "Not logged in", body = ErrorBody),
+        (status = 403, description = "Role does not allow this", body = ErrorBody),
+        (status = 404, description = "No such encounter", body = ErrorBody),
+    )
+)]
+async fn delete_encounter(
+    Extension(encounters): Extension<Encounters>,
+    CurrentRole(role): CurrentRole,
+    Path(id): Path<i32>,
+) -> Result<StatusCode, ApiError> {
+    authz::check(role, Role::Admin)?;
+
+    if encounters.delete(id).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(ApiError::NotFound)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::body::Body;
+    use axum::http::header::CONTENT_TYPE;
+    use axum::http::{Method, Request};
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::server::config::{Config, Settings};
+    use crate::server::repository::InMemoryEncounterRepository;
+
+    const ROLE_HEADER: &str = "x-role";
+
+    fn app() -> Router {
+        let config = Config::load(
+            Settings {
+                role_header: Some(ROLE_HEADER.to_string()),
+                ..Default::default()
+            },
+            None,
+        )
+        .unwrap();
+        let encounters: Encounters = Arc::new(InMemoryEncounterRepository::new());
+
+        router()
+            .layer(Extension(encounters))
+            .layer(Extension(EncounterEvents::default()))
+            .layer(Extension(Arc::new(config)))
+    }
+
+    async fn send(
+        app: &Router,
+        method: Method,
+        uri: &str,
+        role: Option<&str>,
+        body: Option<Value>,
+    ) -> (StatusCode, Value) {
+        let mut request = Request::builder().method(method).uri(uri);
+        if let Some(role) = role {
+            request = request.header(ROLE_HEADER, role);
+        }
+        let body = match body {
+            Some(body) => {
+                request = request.header(CONTENT_TYPE, "application/json");
+                Body::from(body.to_string())
+            }
+            None => Body::empty(),
+        };
+
+        let response = app
+            .clone()
+            .oneshot(request.body(body).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = serde_json::from_slice(&bytes).unwrap_or(Value::Null);
+        (status, body)
+    }
+
+    fn encounter(penalty: &str) -> Value {
+        json!({
+            "name": "Tux",
+            "location": "Hobart",
+            "penalty": penalty,
+            "date_time": "2024-12-01T12:00:00Z",
+        })
+    }
+
+    #[tokio::test]
+    async fn viewing_requires_a_role() {
+        let app = app();
+
+        let (status, _) = send(&app, Method::GET, "/encounters", None, None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, body) = send(&app, Method::GET, "/encounters", Some("viewer"), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["total"], 0);
+    }
+
+    async fn create(app: &Router, role: &str, penalty: &str) -> (StatusCode, Value) {
+        send(
+            app,
+            Method::POST,
+            "/encounters",
+            Some(role),
+            Some(encounter(penalty)),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn creating_requires_the_penalty_role() {
+        let app = app();
+
+        let (status, _) = create(&app, "viewer", "Jail").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let (status, body) = create(&app, "recorder", "Jail").await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body["penalty"], "Jail");
+
+        let (status, _) = create(&app, "recorder", "Sacrifice").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let (status, _) = create(&app, "admin", "Sacrifice").await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn only_admins_can_take_away_a_sacrifice() {
+        let app = app();
+        let (_, created) = create(&app, "admin", "Sacrifice").await;
+        let uri = format!("/encounters/{}", created["id"]);
+
+        let (status, _) = send(
+            &app,
+            Method::PUT,
+            &uri,
+            Some("recorder"),
+            Some(encounter("Jail")),
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let (status, body) = send(
+            &app,
+            Method::PUT,
+            &uri,
+            Some("admin"),
+            Some(encounter("Jail")),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["penalty"], "Jail");
+    }
+
+    #[tokio::test]
+    async fn only_admins_can_delete() {
+        let app = app();
+        let (_, created) = create(&app, "recorder", "Jail").await;
+        let uri = format!("/encounters/{}", created["id"]);
+
+        let (status, _) = send(&app, Method::DELETE, &uri, Some("recorder"), None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let (status, _) = send(&app, Method::DELETE, &uri, Some("admin"), None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let (status, _) = send(&app, Method::GET, &uri, Some("viewer"), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_pages_and_encounters() {
+        let app = app();
+
+        let (status, _) = send(
+            &app,
+            Method::GET,
+            "/encounters?limit=0",
+            Some("viewer"),
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) = send(
+            &app,
+            Method::GET,
+            "/encounters?offset=-1",
+            Some("viewer"),
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let mut empty_name = encounter("Jail");
+        empty_name["name"] = json!(" ");
+        let (status, _) = send(
+            &app,
+            Method::POST,
+            "/encounters",
+            Some("recorder"),
+            Some(empty_name),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,137 @@
+//! Personal API tokens for programmatic access.
+//!
+//! Tokens are sent as `Authorization: Bearer <token>` and are accepted by
+//! every route, including server functions. A read token acts as a viewer, a
+//! write token as a recorder, or less if its owner is. Admin operations need
+//! a session, so a leaked token can't be used for them. Unknown tokens count
+//! against the client's address, which is refused for a while once it has
+//! sent too many.
+
+use axum::extract::Request;
+use axum::http::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use axum::http::{HeaderMap, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+
+use crate::model::{ApiToken, ApiTokenScope, NewApiToken, Role, User};
+use crate::server::auth::{self, AuthError};
+use crate::server::rate_limit::{self, Attempt, RateLimiter};
+use crate::server::repository::Accounts;
+
+/// Prefix so leaked tokens are easy to recognise.
+const TOKEN_PREFIX: &str = "dfd_";
+
+/// A request authenticated with an API token.
+#[derive(Debug, Clone)]
+pub struct TokenAuth {
+    pub user: User,
+    pub scope: ApiTokenScope,
+}
+
+impl TokenAuth {
+    pub fn role(&self) -> Role {
+        self.user.role.min(self.scope.max_role())
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+/// Whether `token` could have come from [`create`].
+fn is_api_token(token: &str) -> bool {
+    token.strip_prefix(TOKEN_PREFIX).is_some_and(auth::is_token)
+}
+
+/// Middleware that checks any bearer token on the request.
+///
+/// Requests with an invalid, expired or revoked token are rejected outright,
+/// rather than silently treated as anonymous. Valid tokens are added to the
+/// request extensions as [`TokenAuth`].
+pub async fn bearer_auth(mut request: Request, next: Next) -> Response {
+    let Some(token) = bearer_token(request.headers()).map(str::to_string) else {
+        return next.run(request).await;
+    };
+
+    // Checked before the token is looked up, so made up tokens can't be
+    // used to flood the database.
+    let limiter = request.extensions().get::<RateLimiter>().cloned();
+    let address = limiter.as_ref().map(|limiter| {
+        limiter
+            .client_address(request.extensions(), request.headers())
+            .to_string()
+    });
+    if let (Some(limiter), Some(address)) = (&limiter, &address) {
+        if let Err(retry_after) = limiter.check_attempt(Attempt::ApiToken, address) {
+            return rate_limit::too_many_requests(Some(retry_after));
+        }
+    }
+
+    let Some(accounts) = request.extensions().get::<Accounts>().cloned() else {
+        return AuthError::Database("Accounts not available".to_string()).into_response();
+    };
+
+    let token_auth = if is_api_token(&token) {
+        match authenticate(&accounts, &token).await {
+            Ok(token_auth) => token_auth,
+            Err(err) => return err.into_response(),
+        }
+    } else {
+        None
+    };
+
+    match token_auth {
+        Some(token_auth) => {
+            request.extensions_mut().insert(token_auth);
+            next.run(request).await
+        }
+        None => {
+            if let (Some(limiter), Some(address)) = (&limiter, &address) {
+                limiter.attempt_failed(Attempt::ApiToken, address);
+            }
+            (
+                StatusCode::UNAUTHORIZED,
+                [(WWW_AUTHENTICATE, "Bearer error=\"invalid_token\"")],
+                "Invalid API token",
+            )
+                .into_response()
+        }
+    }
+}
+
+async fn authenticate(accounts: &Accounts, token: &str) -> Result<Option<TokenAuth>, AuthError> {
+    let token_auth = accounts
+        .use_api_token(&auth::hash_token(token))
+        .await?
+        .map(|(user, scope)| TokenAuth { user, scope });
+
+    Ok(token_auth)
+}
+
+pub async fn create(
+    accounts: &Accounts,
+    user: &User,
+    name: &str,
+    scope: ApiTokenScope,
+    lifetime: chrono::Duration,
+) -> Result<NewApiToken, AuthError> {
+    let secret = format!("{TOKEN_PREFIX}{}", auth::generate_token());
+    let expires_at = chrono::Utc::now() + lifetime;
+    let token = accounts
+        .create_api_token(user.id, name, &auth::hash_token(&secret), scope, expires_at)
+        .await?;
+
+    Ok(NewApiToken { token, secret })
+}
+
+pub async fn list(accounts: &Accounts, user: &User) -> Result<Vec<ApiToken>, AuthError> {
+    Ok(accounts.list_api_tokens(user.id).await?)
+}
+
+pub async fn revoke(accounts: &Accounts, user: &User, id: i32) -> Result<bool, AuthError> {
+    Ok(accounts.revoke_api_token(user.id, id).await?)
+}
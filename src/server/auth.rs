@@ -0,0 +1,273 @@
+//! Password based accounts with sessions stored in the database.
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use axum::extract::FromRequestParts;
+use axum::http::header::COOKIE;
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use lazy_static::lazy_static;
+use sha2::{Digest, Sha256};
+use tracing::{error, info};
+
+use crate::model::{Role, User};
+use crate::server::api_tokens::TokenAuth;
+use crate::server::config::Config;
+use crate::server::rate_limit::{self, Attempt, RateLimiter};
+use crate::server::repository::{Accounts, RepositoryError};
+use crate::server::totp;
+
+pub const SESSION_COOKIE: &str = "session";
+
+fn session_lifetime() -> chrono::Duration {
+    chrono::Duration::days(30)
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum AuthError {
+    #[error("Not logged in")]
+    NotLoggedIn,
+    #[error("Invalid username or password")]
+    InvalidCredentials,
+    #[error("Two factor authentication code required")]
+    TotpRequired,
+    #[error("Two factor authentication error: {0}")]
+    Totp(String),
+    #[error("Database error: {0}")]
+    Database(String),
+    #[error("Password hash error: {0}")]
+    Hash(String),
+    #[error("Too many failed attempts, try again in {}s", .0.as_secs() + 1)]
+    TooManyAttempts(std::time::Duration),
+}
+
+impl From<RepositoryError> for AuthError {
+    fn from(err: RepositoryError) -> Self {
+        AuthError::Database(err.to_string())
+    }
+}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> Response {
+        if let AuthError::TooManyAttempts(retry_after) = self {
+            return rate_limit::too_many_requests(Some(retry_after));
+        }
+        let status = match self {
+            AuthError::NotLoggedIn | AuthError::InvalidCredentials | AuthError::TotpRequired => {
+                StatusCode::UNAUTHORIZED
+            }
+            AuthError::Totp(_) => StatusCode::BAD_REQUEST,
+            AuthError::Database(_) | AuthError::Hash(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AuthError::TooManyAttempts(_) => StatusCode::TOO_MANY_REQUESTS,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
+
+lazy_static! {
+    /// Checked against when there is no real hash, so that unknown usernames
+    /// take as long to reject as wrong passwords.
+    static ref DUMMY_PASSWORD_HASH: String =
+        hash_password("dummy password").expect("Failed to hash dummy password");
+}
+
+pub fn hash_password(password: &str) -> Result<String, AuthError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|err| AuthError::Hash(err.to_string()))
+}
+
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    match PasswordHash::new(password_hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(err) => {
+            error!("Invalid password hash in database: {err}");
+            false
+        }
+    }
+}
+
+/// Generate a random token suitable for use as a secret.
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes).expect("Failed to get random bytes");
+    hex::encode(bytes)
+}
+
+/// Whether `token` could have come from [`generate_token`].
+pub fn is_token(token: &str) -> bool {
+    token.len() == 64 && token.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+/// Tokens are only ever stored hashed, so a leaked table can't be replayed.
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Check a username and password, returning the user and a new session token.
+///
+/// Users with TOTP enabled must also supply a TOTP or recovery code.
+/// Usernames that have had too many wrong passwords are refused for a while,
+/// whether or not the account exists.
+pub async fn login(
+    accounts: &Accounts,
+    limiter: &RateLimiter,
+    username: &str,
+    password: &str,
+    totp_code: Option<&str>,
+) -> Result<(User, String), AuthError> {
+    limiter
+        .check_attempt(Attempt::Password, username)
+        .map_err(AuthError::TooManyAttempts)?;
+
+    let account = accounts.get_user_by_username(username).await?;
+    let password_hash = match &account {
+        Some((_, Some(password_hash))) => password_hash.as_str(),
+        _ => DUMMY_PASSWORD_HASH.as_str(),
+    };
+    let verified = verify_password(password, password_hash);
+    let user = match account {
+        Some((user, Some(_))) if verified => user,
+        _ => {
+            limiter.attempt_failed(Attempt::Password, username);
+            return Err(AuthError::InvalidCredentials);
+        }
+    };
+
+    if user.totp_enabled {
+        let code = totp_code.ok_or(AuthError::TotpRequired)?;
+        if !totp::verify(accounts, limiter, &user, code).await? {
+            return Err(AuthError::InvalidCredentials);
+        }
+    }
+
+    let token = start_session(accounts, &user).await?;
+    Ok((user, token))
+}
+
+/// Create a session for a user who has already been authenticated.
+pub async fn start_session(accounts: &Accounts, user: &User) -> Result<String, AuthError> {
+    accounts.delete_expired_sessions().await?;
+
+    let token = generate_token();
+    let expires_at = chrono::Utc::now() + session_lifetime();
+    accounts
+        .create_session(&hash_token(&token), user.id, expires_at)
+        .await?;
+
+    Ok(token)
+}
+
+pub async fn logout(accounts: &Accounts, token: &str) -> Result<(), AuthError> {
+    accounts.delete_session(&hash_token(token)).await?;
+    Ok(())
+}
+
+pub async fn session_user(accounts: &Accounts, token: &str) -> Result<Option<User>, AuthError> {
+    Ok(accounts.get_session_user(&hash_token(token)).await?)
+}
+
+pub fn session_cookie(token: &str) -> HeaderValue {
+    let max_age = session_lifetime().num_seconds();
+    let cookie = format!(
+        "{SESSION_COOKIE}={token}; Path=/; HttpOnly; Secure; SameSite=Lax; Max-Age={max_age}"
+    );
+    HeaderValue::from_str(&cookie).expect("Session cookie is a valid header value")
+}
+
+pub fn clear_session_cookie() -> HeaderValue {
+    let cookie = format!("{SESSION_COOKIE}=; Path=/; HttpOnly; Secure; SameSite=Lax; Max-Age=0");
+    HeaderValue::from_str(&cookie).expect("Session cookie is a valid header value")
+}
+
+/// Find a cookie in the request.
+pub fn cookie(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(cookie_name, _)| *cookie_name == name)
+        .map(|(_, value)| value.to_string())
+}
+
+/// Find the session token in the request cookies.
+pub fn session_token(headers: &HeaderMap) -> Option<String> {
+    cookie(headers, SESSION_COOKIE)
+}
+
+/// The logged in user, from either their session or an API token.
+///
+/// Use `extract()` inside a `#[server]` function, or as an axum extractor.
+/// Wrap in `Option` when logging in is not required.
+pub struct CurrentUser(pub User);
+
+#[axum::async_trait]
+impl<S> FromRequestParts<S> for CurrentUser
+where
+    S: Send + Sync,
+{
+    type Rejection = AuthError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(token_auth) = parts.extensions.get::<TokenAuth>() {
+            return Ok(CurrentUser(token_auth.user.clone()));
+        }
+
+        let SessionUser(user) = SessionUser::from_request_parts(parts, state).await?;
+        Ok(CurrentUser(user))
+    }
+}
+
+/// The user logged in with a session cookie.
+///
+/// Used where an API token must not be enough, such as managing API tokens.
+pub struct SessionUser(pub User);
+
+#[axum::async_trait]
+impl<S> FromRequestParts<S> for SessionUser
+where
+    S: Send + Sync,
+{
+    type Rejection = AuthError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let accounts = parts
+            .extensions
+            .get::<Accounts>()
+            .cloned()
+            .ok_or_else(|| AuthError::Database("Accounts not available".to_string()))?;
+
+        let token = session_token(&parts.headers).ok_or(AuthError::NotLoggedIn)?;
+
+        session_user(&accounts, &token)
+            .await?
+            .map(SessionUser)
+            .ok_or(AuthError::NotLoggedIn)
+    }
+}
+
+/// Create the configured bootstrap user if there are no users yet.
+pub async fn bootstrap(accounts: &Accounts, config: &Config) -> Result<(), AuthError> {
+    let (Some(username), Some(password)) = (&config.bootstrap_username, &config.bootstrap_password)
+    else {
+        return Ok(());
+    };
+
+    if accounts.count_users().await? == 0 {
+        let password_hash = hash_password(password.expose())?;
+        accounts
+            .create_user(username, &password_hash, Role::Admin)
+            .await?;
+        info!("Created initial user {username}");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,98 @@
+//! Role based authorization for server functions.
+//!
+//! The role comes from the API token or the logged in user's session. If
+//! `role_header` is configured, anonymous requests may instead carry their
+//! role in that header; only set this when a trusted reverse proxy strips the
+//! header from client requests. Otherwise anonymous requests get the
+//! configured `anonymous_role`, if any.
+
+use std::sync::Arc;
+
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use dioxus::prelude::*;
+
+use crate::model::{AccessError, Role};
+use crate::server::api_tokens::TokenAuth;
+use crate::server::auth::{self, AuthError};
+use crate::server::config::Config;
+use crate::server::repository::Accounts;
+
+/// The role of the caller, if they have one.
+pub struct CurrentRole(pub Option<Role>);
+
+#[axum::async_trait]
+impl<S> FromRequestParts<S> for CurrentRole
+where
+    S: Send + Sync,
+{
+    type Rejection = AuthError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(token_auth) = parts.extensions.get::<TokenAuth>() {
+            return Ok(CurrentRole(Some(token_auth.role())));
+        }
+
+        if let Some(token) = auth::session_token(&parts.headers) {
+            let accounts = parts
+                .extensions
+                .get::<Accounts>()
+                .cloned()
+                .ok_or_else(|| AuthError::Database("Accounts not available".to_string()))?;
+
+            if let Some(user) = auth::session_user(&accounts, &token).await? {
+                return Ok(CurrentRole(Some(user.role)));
+            }
+        }
+
+        let Some(config) = parts.extensions.get::<Arc<Config>>() else {
+            return Ok(CurrentRole(None));
+        };
+
+        let role = config
+            .role_header
+            .as_ref()
+            .and_then(|header| {
+                parts
+                    .headers
+                    .get(header)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.parse().ok())
+            })
+            .or(config.anonymous_role);
+
+        Ok(CurrentRole(role))
+    }
+}
+
+/// Check that `role` includes the privileges of `required`.
+pub fn check(role: Option<Role>, required: Role) -> Result<Role, AccessError> {
+    match role {
+        Some(role) if role.allows(required) => Ok(role),
+        Some(_) => Err(AccessError::Forbidden(required)),
+        None => Err(AccessError::NotLoggedIn),
+    }
+}
+
+/// Guard for `#[server]` functions.
+///
+/// ```ignore
+/// #[server(DeletePenguinEncounter)]
+/// async fn delete_penguin_encounter(id: i32) -> Result<(), ServerFnError<AccessError>> {
+///     require_role(Role::Admin).await?;
+///     ...
+/// }
+/// ```
+pub async fn require_role(required: Role) -> Result<Role, ServerFnError<AccessError>> {
+    let CurrentRole(role) = extract()
+        .await
+        .map_err(|err: AuthError| ServerFnError::ServerError(err.to_string()))?;
+
+    check(role, required).map_err(ServerFnError::WrappedServerError)
+}
+
+/// For other errors in `#[server]` functions that fail with [`AccessError`],
+/// which `?` can't convert by itself.
+pub fn server_error(err: impl std::fmt::Display) -> ServerFnError<AccessError> {
+    ServerFnError::ServerError(err.to_string())
+}
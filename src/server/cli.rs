@@ -0,0 +1,233 @@
+//! Administrative subcommands, run instead of serving.
+//!
+//! These use the same configuration as the server, so can be run from the
+//! same systemd unit, for example `dioxus-fs-demo --config ... migrate status`.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+
+use clap::Subcommand;
+use diesel::backend::Backend;
+use diesel_migrations::{EmbeddedMigrations, MigrationHarness};
+use serde::Deserialize;
+
+use crate::model::{CreatePenguinEncounter, PenaltyEnum};
+use crate::server::config::Config;
+use crate::server::database;
+use crate::server::repository::{self, Encounters, Store};
+
+pub type CliError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Subcommand, Debug, Clone, Default)]
+pub enum Command {
+    /// Run the server. This is the default.
+    #[default]
+    Serve,
+    /// Manage database migrations.
+    Migrate {
+        #[command(subcommand)]
+        action: MigrateAction,
+    },
+    /// Check the database can be reached and has no pending migrations.
+    CheckDb,
+    /// Add example encounters.
+    Seed {
+        /// How many encounters to add.
+        #[arg(long, default_value_t = 20)]
+        count: u32,
+    },
+    /// Write every encounter as JSON.
+    Export {
+        /// File to write to, instead of standard output.
+        path: Option<PathBuf>,
+    },
+    /// Add encounters from JSON written by `export`. Encounters get new ids.
+    Import {
+        /// File to read from, instead of standard input.
+        path: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone, Copy)]
+pub enum MigrateAction {
+    /// Run all pending migrations.
+    Up,
+    /// Revert the last migration.
+    Down,
+    /// List applied and pending migrations.
+    Status,
+    /// Revert and rerun the last migration.
+    Redo,
+}
+
+/// Run `action` with `harness`, returning lines to show the operator.
+/// Running migrations consumes them, so `migrations` is called for each step.
+fn apply_migrations<DB: Backend>(
+    harness: &mut impl MigrationHarness<DB>,
+    migrations: fn() -> EmbeddedMigrations,
+    action: MigrateAction,
+) -> Result<Vec<String>, CliError> {
+    let lines = match action {
+        MigrateAction::Up => database::apply_pending_migrations(harness, migrations())?
+            .iter()
+            .map(|name| format!("Applied {name}"))
+            .collect(),
+        MigrateAction::Down => {
+            vec![format!(
+                "Reverted {}",
+                harness.revert_last_migration(migrations())?
+            )]
+        }
+        MigrateAction::Redo => {
+            let reverted = harness.revert_last_migration(migrations())?;
+            let applied = harness.run_next_migration(migrations())?;
+            vec![format!("Reverted {reverted}"), format!("Applied {applied}")]
+        }
+        MigrateAction::Status => {
+            let status = database::migration_status_of(harness, migrations())?;
+            let applied = status.applied.iter().map(|name| format!("[X] {name}"));
+            let pending = status.pending.iter().map(|name| format!("[ ] {name}"));
+            applied.chain(pending).collect()
+        }
+    };
+    Ok(lines)
+}
+
+async fn run_migrations(config: &Config, action: MigrateAction) -> Result<Vec<String>, CliError> {
+    match Store::from_config(config)? {
+        #[cfg(feature = "sqlite")]
+        Store::Sqlite(path) => {
+            tokio::task::spawn_blocking(move || {
+                let mut conn = database::sqlite::migration_connection(&path)?;
+                apply_migrations(&mut conn, || database::sqlite::MIGRATIONS, action)
+            })
+            .await?
+        }
+        _ => {
+            let pool = database::connect(config).await?;
+            let mut conn = database::migration_connection(&pool).await?;
+            tokio::task::spawn_blocking(move || {
+                database::with_migration_lock(&mut conn, |conn| {
+                    apply_migrations(conn, || database::MIGRATIONS, action)
+                })
+            })
+            .await?
+        }
+    }
+}
+
+pub async fn migrate(config: &Config, action: MigrateAction) -> Result<(), CliError> {
+    let lines = run_migrations(config, action).await?;
+    if lines.is_empty() {
+        println!("Nothing to do");
+    }
+    for line in lines {
+        println!("{line}");
+    }
+    Ok(())
+}
+
+pub async fn check_db(config: &Config) -> Result<(), CliError> {
+    let status = run_migrations(config, MigrateAction::Status).await?;
+    let pending = status.iter().filter(|line| line.starts_with("[ ]")).count();
+
+    if pending > 0 {
+        return Err(format!("Database is reachable, with {pending} pending migrations").into());
+    }
+
+    println!("Database is reachable and up to date");
+    Ok(())
+}
+
+/// Open the configured store for the data commands.
+async fn open_encounters(config: &Config) -> Result<Encounters, CliError> {
+    let store = Store::from_config(config)?;
+    let database = match store {
+        Store::Postgres => database::init(config).await?,
+        Store::Memory => return Err("The memory store has nothing to manage".into()),
+        #[cfg(feature = "sqlite")]
+        Store::Sqlite(_) => database::pool(""),
+    };
+    let state = database::DatabaseState::new(true);
+    let (encounters, _accounts) = repository::open(store, &database, &state).await?;
+    Ok(encounters)
+}
+
+pub async fn seed(config: &Config, count: u32) -> Result<(), CliError> {
+    const NAMES: [&str; 5] = ["Tux", "Pingu", "Gloria", "Mumble", "Skipper"];
+    const LOCATIONS: [&str; 4] = [
+        "Antarctica",
+        "Phillip Island",
+        "Melbourne Zoo",
+        "Falkland Islands",
+    ];
+
+    let encounters = open_encounters(config).await?;
+    let now = chrono::Utc::now();
+
+    for i in 0..count as usize {
+        encounters
+            .create(&CreatePenguinEncounter {
+                name: NAMES[i % NAMES.len()],
+                location: LOCATIONS[i % LOCATIONS.len()],
+                penalty: PenaltyEnum::ALL[i % PenaltyEnum::ALL.len()],
+                date_time: now - chrono::Duration::hours(i as i64),
+            })
+            .await?;
+    }
+
+    println!("Added {count} encounters");
+    Ok(())
+}
+
+pub async fn export(config: &Config, path: Option<PathBuf>) -> Result<(), CliError> {
+    let encounters = open_encounters(config).await?;
+
+    let mut all = encounters.list().await?;
+    all.sort_by_key(|encounter| encounter.id);
+
+    let writer: Box<dyn Write> = match &path {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout()),
+    };
+    let mut writer = BufWriter::new(writer);
+    serde_json::to_writer_pretty(&mut writer, &all)?;
+    writeln!(writer)?;
+    writer.flush()?;
+
+    eprintln!("Exported {} encounters", all.len());
+    Ok(())
+}
+
+/// An exported encounter. Any `id` is ignored.
+#[derive(Deserialize)]
+struct ImportedEncounter {
+    name: String,
+    location: String,
+    penalty: PenaltyEnum,
+    date_time: chrono::DateTime<chrono::Utc>,
+}
+
+pub async fn import(config: &Config, path: Option<PathBuf>) -> Result<(), CliError> {
+    let reader: Box<dyn Read> = match &path {
+        Some(path) => Box::new(File::open(path)?),
+        None => Box::new(io::stdin()),
+    };
+    let imported: Vec<ImportedEncounter> = serde_json::from_reader(BufReader::new(reader))?;
+
+    let encounters = open_encounters(config).await?;
+    for encounter in &imported {
+        encounters
+            .create(&CreatePenguinEncounter {
+                name: &encounter.name,
+                location: &encounter.location,
+                penalty: encounter.penalty,
+                date_time: encounter.date_time,
+            })
+            .await?;
+    }
+
+    eprintln!("Imported {} encounters", imported.len());
+    Ok(())
+}
@@ -1,67 +1,346 @@
 use chrono::Utc;
+use diesel::backend::Backend;
+use diesel::migration::MigrationSource;
 use diesel::pg::Pg;
 use diesel::prelude::*;
 use diesel_async::async_connection_wrapper::AsyncConnectionWrapper;
-use diesel_async::pooled_connection::mobc::Pool;
-use diesel_async::pooled_connection::AsyncDieselConnectionManager;
+use diesel_async::pooled_connection::mobc::{Pool, PooledConnection};
+use diesel_async::pooled_connection::{AsyncDieselConnectionManager, ManagerConfig};
+use diesel_async::scoped_futures::ScopedFutureExt;
 use diesel_async::AsyncConnection;
 use diesel_async::AsyncPgConnection;
 use diesel_async::RunQueryDsl;
 use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use futures::FutureExt;
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{error, info, instrument, warn};
 
-use std::env;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod tls;
 
-const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
 
-use crate::model::{CreatePenguinEncounter, PenaltyEnum, PenguinEncounter};
+use crate::model::{ApiToken, ApiTokenScope, CreatePenguinEncounter, PenguinEncounter, Role, User};
+use crate::server::config::Config;
+use crate::server::repository::EncounterFilter;
 
 pub type DatabasePool = Pool<AsyncPgConnection>;
+pub type DatabaseConnection = PooledConnection<AsyncPgConnection>;
 
-async fn run_migrations<A>(async_connection: A) -> Result<(), Box<dyn std::error::Error>>
+#[derive(Debug, thiserror::Error)]
+pub enum DatabaseError {
+    #[error("Database unavailable: {0}")]
+    Unavailable(String),
+    #[error("Failed to lock migrations: {0}")]
+    MigrationLock(diesel::result::Error),
+    #[error("Migration {name} failed: {source}")]
+    Migration {
+        name: String,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    #[error("Failed to list migrations: {0}")]
+    Migrations(Box<dyn std::error::Error + Send + Sync>),
+    #[error("Migration task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+/// Which migrations have been applied to the database, by name.
+#[derive(Debug, Clone, Serialize)]
+pub struct MigrationStatus {
+    pub applied: Vec<String>,
+    pub pending: Vec<String>,
+}
+
+/// Key for the advisory lock held while migrating, so replicas starting at
+/// the same time don't race each other.
+const MIGRATION_LOCK_KEY: i64 = 0x0070_656e_6775_696e;
+
+/// Run `f` while holding the migration lock. Waits for any other process
+/// holding it to finish first.
+pub fn with_migration_lock<C, T, E>(
+    conn: &mut C,
+    f: impl FnOnce(&mut C) -> Result<T, E>,
+) -> Result<T, E>
 where
-    A: AsyncConnection<Backend = Pg> + 'static,
+    C: diesel::Connection<Backend = Pg>,
+    E: From<DatabaseError>,
 {
-    let mut async_wrapper: AsyncConnectionWrapper<A> =
-        AsyncConnectionWrapper::from(async_connection);
+    diesel::RunQueryDsl::execute(
+        diesel::sql_query("SELECT pg_advisory_lock($1)")
+            .bind::<diesel::sql_types::BigInt, _>(MIGRATION_LOCK_KEY),
+        conn,
+    )
+    .map_err(DatabaseError::MigrationLock)?;
 
-    tokio::task::spawn_blocking(move || {
-        async_wrapper.run_pending_migrations(MIGRATIONS).unwrap();
-    })
-    .await
-    .map_err(|e| Box::new(e) as Box<dyn std::error::Error>)
+    let result = f(conn);
+
+    diesel::RunQueryDsl::execute(
+        diesel::sql_query("SELECT pg_advisory_unlock($1)")
+            .bind::<diesel::sql_types::BigInt, _>(MIGRATION_LOCK_KEY),
+        conn,
+    )
+    .map_err(DatabaseError::MigrationLock)?;
+
+    result
 }
 
-pub async fn init() -> DatabasePool {
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let config = AsyncDieselConnectionManager::<diesel_async::AsyncPgConnection>::new(database_url);
+/// Run pending migrations one at a time, so a failure names the migration.
+pub fn apply_pending_migrations<DB: Backend>(
+    harness: &mut impl MigrationHarness<DB>,
+    migrations: EmbeddedMigrations,
+) -> Result<Vec<String>, DatabaseError> {
+    let pending = harness
+        .pending_migrations(migrations)
+        .map_err(DatabaseError::Migrations)?;
 
-    let pool = Pool::new(config);
+    let mut applied = Vec::new();
+    for migration in pending {
+        let name = migration.name().to_string();
+        harness
+            .run_migration(&*migration)
+            .map_err(|source| DatabaseError::Migration {
+                name: name.clone(),
+                source,
+            })?;
+        applied.push(name);
+    }
 
-    let mut tries = 0;
+    Ok(applied)
+}
+
+pub fn migration_status_of<DB: Backend>(
+    harness: &mut impl MigrationHarness<DB>,
+    migrations: EmbeddedMigrations,
+) -> Result<MigrationStatus, DatabaseError> {
+    let applied_versions = harness
+        .applied_migrations()
+        .map_err(DatabaseError::Migrations)?;
+
+    let mut status = MigrationStatus {
+        applied: Vec::new(),
+        pending: Vec::new(),
+    };
+    for migration in
+        MigrationSource::<DB>::migrations(&migrations).map_err(DatabaseError::Migrations)?
+    {
+        let name = migration.name();
+        if applied_versions
+            .iter()
+            .any(|version| *version == name.version())
+        {
+            status.applied.push(name.to_string());
+        } else {
+            status.pending.push(name.to_string());
+        }
+    }
+
+    Ok(status)
+}
+
+/// How long to wait for a connection before giving up, so requests fail
+/// quickly while the database is down.
+const POOL_GET_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often to check the database is still there once connected.
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Create a pool without connecting to the database. Connections use TLS
+/// as `sslmode` in the URL says, see [`tls`].
+pub fn pool(database_url: &str) -> DatabasePool {
+    // A bad URL is reported by every attempt to connect, like an
+    // unreachable database.
+    let connector = tls::Connector::from_url(database_url).map_err(|err| err.to_string());
+    let mut manager_config = ManagerConfig::default();
+    manager_config.custom_setup = Box::new(move |_| {
+        let connector = connector.clone();
+        async move {
+            connector
+                .map_err(diesel::ConnectionError::BadConnection)?
+                .connect()
+                .await
+        }
+        .boxed()
+    });
+
+    let config = AsyncDieselConnectionManager::<diesel_async::AsyncPgConnection>::new_with_config(
+        database_url,
+        manager_config,
+    );
+    DatabasePool::builder()
+        .get_timeout(Some(POOL_GET_TIMEOUT))
+        .build(config)
+}
+
+/// Whether the database is connected and migrated.
+#[derive(Debug, Clone)]
+pub struct DatabaseState {
+    ready: Arc<watch::Sender<bool>>,
+}
+
+impl DatabaseState {
+    pub fn new(ready: bool) -> Self {
+        DatabaseState {
+            ready: Arc::new(watch::channel(ready).0),
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        *self.ready.borrow()
+    }
+
+    /// Follow the database becoming ready or unavailable.
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.ready.subscribe()
+    }
+
+    /// Wait until the database is ready.
+    pub async fn wait_ready(&self) {
+        let mut receiver = self.ready.subscribe();
+        let _ = receiver.wait_for(|ready| *ready).await;
+    }
+
+    fn set_ready(&self, ready: bool) {
+        self.ready.send_replace(ready);
+    }
+}
+
+async fn prepare(pool: &DatabasePool, config: &Config) -> Result<(), DatabaseError> {
+    if config.auto_migrate {
+        for name in run_migrations(pool).await? {
+            info!("Applied migration {name}");
+        }
+    } else {
+        let pending = migration_status(pool).await?.pending;
+        if !pending.is_empty() {
+            warn!("Not applying pending migrations: {}", pending.join(", "));
+        }
+    }
+    Ok(())
+}
+
+/// Keep the database connected for the lifetime of the server.
+///
+/// Until the database can be reached, connecting is retried with exponential
+/// backoff. Migrations are run every time the connection comes back, after
+/// which `state` is ready.
+pub async fn supervise(pool: DatabasePool, state: DatabaseState, config: Arc<Config>) {
+    let mut delay = config.database_retry_interval();
 
     loop {
-        let conn = pool.get().await;
-        match conn {
-            Ok(conn) => {
-                run_migrations(conn).await.unwrap();
-                break;
+        match pool.get().await {
+            Ok(_) if state.is_ready() => {}
+            Ok(_) => match prepare(&pool, &config).await {
+                Ok(()) => {
+                    info!("Database available");
+                    state.set_ready(true);
+                    delay = config.database_retry_interval();
+                }
+                Err(err) => error!("Failed to prepare database: {err}"),
+            },
+            Err(err) => {
+                if state.is_ready() {
+                    state.set_ready(false);
+                }
+                warn!("Database unavailable, retrying in {delay:?}: {err}");
             }
+        }
+
+        if state.is_ready() {
+            tokio::time::sleep(CHECK_INTERVAL).await;
+        } else {
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(config.database_max_retry_interval());
+        }
+    }
+}
+
+/// Close the pool's connections once they are returned, and stop it opening
+/// more.
+pub async fn close(pool: &DatabasePool) {
+    pool.set_max_open_conns(0).await;
+    pool.set_max_idle_conns(0).await;
+}
+
+/// Connect to the database, retrying as configured, without running
+/// migrations.
+pub async fn connect(config: &Config) -> Result<DatabasePool, DatabaseError> {
+    let database_url = config
+        .database_url
+        .as_ref()
+        .ok_or_else(|| DatabaseError::Unavailable("DATABASE_URL must be set".to_string()))?;
+    let pool = pool(database_url.expose());
+
+    let mut tries = 0;
+
+    loop {
+        match pool.get().await {
+            Ok(_) => return Ok(pool),
             Err(e) => {
-                eprintln!("Failed to connect to database: {}", e);
+                warn!("Failed to connect to database: {}", e);
             }
         }
 
-        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        tokio::time::sleep(config.database_retry_interval()).await;
 
         tries += 1;
-        if tries > 10 {
-            panic!("Failed to connect to database after 10 tries");
+        if tries > config.database_connect_retries {
+            return Err(DatabaseError::Unavailable(format!(
+                "Failed to connect to database after {} tries",
+                config.database_connect_retries
+            )));
         }
     }
+}
+
+/// A connection for running migrations with [`MigrationHarness`], which must
+/// be used from a blocking task.
+pub async fn migration_connection(
+    pool: &DatabasePool,
+) -> Result<AsyncConnectionWrapper<DatabaseConnection>, DatabaseError> {
+    let conn = pool
+        .get()
+        .await
+        .map_err(|err| DatabaseError::Unavailable(err.to_string()))?;
+    Ok(AsyncConnectionWrapper::from(conn))
+}
 
-    pool
+/// Run pending migrations while holding the migration lock, returning the
+/// names of those applied.
+pub async fn run_migrations(pool: &DatabasePool) -> Result<Vec<String>, DatabaseError> {
+    let mut conn = migration_connection(pool).await?;
+    tokio::task::spawn_blocking(move || {
+        with_migration_lock(&mut conn, |conn| apply_pending_migrations(conn, MIGRATIONS))
+    })
+    .await?
 }
 
+pub async fn migration_status(pool: &DatabasePool) -> Result<MigrationStatus, DatabaseError> {
+    let mut conn = migration_connection(pool).await?;
+    tokio::task::spawn_blocking(move || migration_status_of(&mut conn, MIGRATIONS)).await?
+}
+
+/// Connect, and run pending migrations unless `auto_migrate` is turned off.
+///
+/// Used by the admin commands. The server uses [`supervise`] instead, so
+/// it can start while the database is down.
+pub async fn init(config: &Config) -> Result<DatabasePool, DatabaseError> {
+    let pool = connect(config).await?;
+    prepare(&pool, config).await?;
+    Ok(pool)
+}
+
+/// A query that does nothing, to check the connection works.
+#[instrument(skip_all)]
+pub async fn ping(conn: &mut AsyncPgConnection) -> Result<(), diesel::result::Error> {
+    diesel::sql_query("SELECT 1").execute(conn).await?;
+    Ok(())
+}
+
+#[instrument(skip_all)]
 pub async fn list_penguin_encounters(
     conn: &mut AsyncPgConnection,
 ) -> Result<Vec<PenguinEncounter>, diesel::result::Error> {
@@ -70,25 +349,599 @@ pub async fn list_penguin_encounters(
     dsl::penguin_encounter.load(conn).await
 }
 
+#[instrument(skip_all)]
 pub async fn create_penguin_encounter(
     conn: &mut AsyncPgConnection,
-    name: &str,
-    location: &str,
-    penalty: PenaltyEnum,
-    date_time: chrono::DateTime<Utc>,
+    penguin_encounter: &CreatePenguinEncounter<'_>,
 ) -> Result<PenguinEncounter, diesel::result::Error> {
     use crate::server::schema::penguin_encounter::dsl;
 
-    let penguin_encounter = CreatePenguinEncounter {
-        name,
-        location,
-        penalty,
-        date_time,
-    };
-
     diesel::insert_into(dsl::penguin_encounter)
-        .values(&penguin_encounter)
+        .values(penguin_encounter)
+        .returning(PenguinEncounter::as_returning())
+        .get_result(conn)
+        .await
+}
+
+fn filter_query(
+    filter: &EncounterFilter,
+) -> crate::server::schema::penguin_encounter::BoxedQuery<'static, Pg> {
+    use crate::server::schema::penguin_encounter::dsl;
+
+    let mut query = dsl::penguin_encounter.into_boxed();
+    if let Some(name) = &filter.name {
+        query = query.filter(dsl::name.eq(name.clone()));
+    }
+    if let Some(location) = &filter.location {
+        query = query.filter(dsl::location.eq(location.clone()));
+    }
+    if let Some(penalty) = filter.penalty {
+        query = query.filter(dsl::penalty.eq(penalty));
+    }
+    if let Some(from) = filter.from {
+        query = query.filter(dsl::date_time.ge(from));
+    }
+    if let Some(to) = filter.to {
+        query = query.filter(dsl::date_time.lt(to));
+    }
+    query
+}
+
+/// Get a page of encounters matching the filter, along with the total number
+/// of matching encounters.
+#[instrument(skip_all)]
+pub async fn search_penguin_encounters(
+    conn: &mut AsyncPgConnection,
+    filter: &EncounterFilter,
+    limit: i64,
+    offset: i64,
+) -> Result<(Vec<PenguinEncounter>, i64), diesel::result::Error> {
+    use crate::server::schema::penguin_encounter::dsl;
+
+    let total = filter_query(filter).count().get_result(conn).await?;
+
+    let encounters = filter_query(filter)
+        .order((dsl::date_time.desc(), dsl::id.desc()))
+        .limit(limit)
+        .offset(offset)
+        .select(PenguinEncounter::as_select())
+        .load(conn)
+        .await?;
+
+    Ok((encounters, total))
+}
+
+/// The distinct names of penguins that have been encountered.
+#[instrument(skip_all)]
+pub async fn list_penguin_names(
+    conn: &mut AsyncPgConnection,
+) -> Result<Vec<String>, diesel::result::Error> {
+    use crate::server::schema::penguin_encounter::dsl;
+
+    dsl::penguin_encounter
+        .select(dsl::name)
+        .distinct()
+        .order(dsl::name)
+        .load(conn)
+        .await
+}
+
+#[instrument(skip_all)]
+pub async fn get_penguin_encounter(
+    conn: &mut AsyncPgConnection,
+    id: i32,
+) -> Result<Option<PenguinEncounter>, diesel::result::Error> {
+    use crate::server::schema::penguin_encounter::dsl;
+
+    dsl::penguin_encounter
+        .filter(dsl::id.eq(id))
+        .select(PenguinEncounter::as_select())
+        .first(conn)
+        .await
+        .optional()
+}
+
+#[instrument(skip_all)]
+pub async fn update_penguin_encounter(
+    conn: &mut AsyncPgConnection,
+    id: i32,
+    penguin_encounter: &CreatePenguinEncounter<'_>,
+) -> Result<Option<PenguinEncounter>, diesel::result::Error> {
+    use crate::server::schema::penguin_encounter::dsl;
+
+    diesel::update(dsl::penguin_encounter.filter(dsl::id.eq(id)))
+        .set(penguin_encounter)
         .returning(PenguinEncounter::as_returning())
         .get_result(conn)
         .await
+        .optional()
+}
+
+/// Delete an encounter, returning `false` if it did not exist.
+#[instrument(skip_all)]
+pub async fn delete_penguin_encounter(
+    conn: &mut AsyncPgConnection,
+    id: i32,
+) -> Result<bool, diesel::result::Error> {
+    use crate::server::schema::penguin_encounter::dsl;
+
+    diesel::delete(dsl::penguin_encounter.filter(dsl::id.eq(id)))
+        .execute(conn)
+        .await
+        .map(|count| count > 0)
+}
+
+pub async fn count_users(conn: &mut AsyncPgConnection) -> Result<i64, diesel::result::Error> {
+    use crate::server::schema::users::dsl;
+
+    dsl::users.count().get_result(conn).await
+}
+
+pub async fn create_user(
+    conn: &mut AsyncPgConnection,
+    username: &str,
+    password_hash: &str,
+    role: Role,
+) -> Result<User, diesel::result::Error> {
+    use crate::server::schema::users::dsl;
+
+    diesel::insert_into(dsl::users)
+        .values((
+            dsl::username.eq(username),
+            dsl::password_hash.eq(password_hash),
+            dsl::role.eq(role),
+        ))
+        .returning(User::as_returning())
+        .get_result(conn)
+        .await
+}
+
+/// Look up a user along with their password hash.
+///
+/// Users that only log in with OpenID Connect have no password hash.
+pub async fn get_user_by_username(
+    conn: &mut AsyncPgConnection,
+    username: &str,
+) -> Result<Option<(User, Option<String>)>, diesel::result::Error> {
+    use crate::server::schema::users::dsl;
+
+    dsl::users
+        .filter(dsl::username.eq(username))
+        .select((User::as_select(), dsl::password_hash))
+        .first(conn)
+        .await
+        .optional()
+}
+
+pub async fn get_user_by_oidc_subject(
+    conn: &mut AsyncPgConnection,
+    subject: &str,
+) -> Result<Option<User>, diesel::result::Error> {
+    use crate::server::schema::users::dsl;
+
+    dsl::users
+        .filter(dsl::oidc_subject.eq(subject))
+        .select(User::as_select())
+        .first(conn)
+        .await
+        .optional()
+}
+
+/// Whether the user logs in with OpenID Connect.
+pub async fn is_oidc_user(
+    conn: &mut AsyncPgConnection,
+    user_id: i32,
+) -> Result<bool, diesel::result::Error> {
+    use crate::server::schema::users::dsl;
+
+    diesel::select(diesel::dsl::exists(
+        dsl::users
+            .filter(dsl::id.eq(user_id))
+            .filter(dsl::oidc_subject.is_not_null()),
+    ))
+    .get_result(conn)
+    .await
+}
+
+pub async fn username_exists(
+    conn: &mut AsyncPgConnection,
+    username: &str,
+) -> Result<bool, diesel::result::Error> {
+    use crate::server::schema::users::dsl;
+
+    diesel::select(diesel::dsl::exists(
+        dsl::users.filter(dsl::username.eq(username)),
+    ))
+    .get_result(conn)
+    .await
+}
+
+pub async fn create_oidc_user(
+    conn: &mut AsyncPgConnection,
+    username: &str,
+    subject: &str,
+    role: Role,
+) -> Result<User, diesel::result::Error> {
+    use crate::server::schema::users::dsl;
+
+    diesel::insert_into(dsl::users)
+        .values((
+            dsl::username.eq(username),
+            dsl::oidc_subject.eq(subject),
+            dsl::role.eq(role),
+        ))
+        .returning(User::as_returning())
+        .get_result(conn)
+        .await
+}
+
+pub async fn set_user_role(
+    conn: &mut AsyncPgConnection,
+    id: i32,
+    role: Role,
+) -> Result<User, diesel::result::Error> {
+    use crate::server::schema::users::dsl;
+
+    diesel::update(dsl::users.filter(dsl::id.eq(id)))
+        .set(dsl::role.eq(role))
+        .returning(User::as_returning())
+        .get_result(conn)
+        .await
+}
+
+pub async fn list_users(conn: &mut AsyncPgConnection) -> Result<Vec<User>, diesel::result::Error> {
+    use crate::server::schema::users::dsl;
+
+    dsl::users
+        .order(dsl::username)
+        .select(User::as_select())
+        .load(conn)
+        .await
+}
+
+pub async fn get_totp_secret(
+    conn: &mut AsyncPgConnection,
+    user_id: i32,
+) -> Result<Option<String>, diesel::result::Error> {
+    use crate::server::schema::users::dsl;
+
+    dsl::users
+        .filter(dsl::id.eq(user_id))
+        .select(dsl::totp_secret)
+        .first(conn)
+        .await
+}
+
+/// Store a new secret that has not been confirmed yet.
+///
+/// Fails to update anything if TOTP is already enabled for the user.
+pub async fn set_pending_totp_secret(
+    conn: &mut AsyncPgConnection,
+    user_id: i32,
+    secret: &str,
+) -> Result<bool, diesel::result::Error> {
+    use crate::server::schema::users::dsl;
+
+    diesel::update(
+        dsl::users
+            .filter(dsl::id.eq(user_id))
+            .filter(dsl::totp_enabled.eq(false)),
+    )
+    .set(dsl::totp_secret.eq(secret))
+    .execute(conn)
+    .await
+    .map(|count| count > 0)
+}
+
+/// Enable TOTP, replacing any existing recovery codes.
+pub async fn enable_totp(
+    conn: &mut AsyncPgConnection,
+    user_id: i32,
+    recovery_code_hashes: &[String],
+) -> Result<(), diesel::result::Error> {
+    use crate::server::schema::recovery_codes;
+    use crate::server::schema::users::dsl;
+
+    conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        async move {
+            diesel::update(dsl::users.filter(dsl::id.eq(user_id)))
+                .set(dsl::totp_enabled.eq(true))
+                .execute(conn)
+                .await?;
+
+            diesel::delete(recovery_codes::table.filter(recovery_codes::user_id.eq(user_id)))
+                .execute(conn)
+                .await?;
+
+            let rows: Vec<_> = recovery_code_hashes
+                .iter()
+                .map(|code_hash| {
+                    (
+                        recovery_codes::user_id.eq(user_id),
+                        recovery_codes::code_hash.eq(code_hash),
+                    )
+                })
+                .collect();
+
+            diesel::insert_into(recovery_codes::table)
+                .values(rows)
+                .execute(conn)
+                .await?;
+
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await
+}
+
+/// Record that a code for `step` has been used, returning `false` if one
+/// for the same or a later step already was, so each code only works once.
+pub async fn accept_totp_step(
+    conn: &mut AsyncPgConnection,
+    user_id: i32,
+    step: i64,
+) -> Result<bool, diesel::result::Error> {
+    use crate::server::schema::users::dsl;
+
+    diesel::update(
+        dsl::users.filter(dsl::id.eq(user_id)).filter(
+            dsl::totp_last_step
+                .is_null()
+                .or(dsl::totp_last_step.lt(step)),
+        ),
+    )
+    .set(dsl::totp_last_step.eq(step))
+    .execute(conn)
+    .await
+    .map(|count| count > 0)
+}
+
+/// Turn off TOTP and remove the secret and recovery codes.
+pub async fn reset_totp(
+    conn: &mut AsyncPgConnection,
+    user_id: i32,
+) -> Result<bool, diesel::result::Error> {
+    use crate::server::schema::recovery_codes;
+    use crate::server::schema::users::dsl;
+
+    conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        async move {
+            diesel::delete(recovery_codes::table.filter(recovery_codes::user_id.eq(user_id)))
+                .execute(conn)
+                .await?;
+
+            diesel::update(dsl::users.filter(dsl::id.eq(user_id)))
+                .set((
+                    dsl::totp_secret.eq(None::<String>),
+                    dsl::totp_enabled.eq(false),
+                    dsl::totp_last_step.eq(None::<i64>),
+                ))
+                .execute(conn)
+                .await
+                .map(|count| count > 0)
+        }
+        .scope_boxed()
+    })
+    .await
+}
+
+/// Mark an unused recovery code as used, returning `false` if there was none.
+pub async fn use_recovery_code(
+    conn: &mut AsyncPgConnection,
+    user_id: i32,
+    code_hash: &str,
+) -> Result<bool, diesel::result::Error> {
+    use crate::server::schema::recovery_codes::dsl;
+
+    diesel::update(
+        dsl::recovery_codes
+            .filter(dsl::user_id.eq(user_id))
+            .filter(dsl::code_hash.eq(code_hash))
+            .filter(dsl::used_at.is_null()),
+    )
+    .set(dsl::used_at.eq(Utc::now()))
+    .execute(conn)
+    .await
+    .map(|count| count > 0)
+}
+
+/// Store a login state, first removing any created before `not_before`.
+pub async fn create_oidc_login_state(
+    conn: &mut AsyncPgConnection,
+    csrf_state: &str,
+    pkce_verifier: &str,
+    nonce: &str,
+    not_before: chrono::DateTime<Utc>,
+) -> Result<(), diesel::result::Error> {
+    use crate::server::schema::oidc_login_states::dsl;
+
+    diesel::delete(dsl::oidc_login_states.filter(dsl::created_at.lt(not_before)))
+        .execute(conn)
+        .await?;
+
+    diesel::insert_into(dsl::oidc_login_states)
+        .values((
+            dsl::csrf_state.eq(csrf_state),
+            dsl::pkce_verifier.eq(pkce_verifier),
+            dsl::nonce.eq(nonce),
+        ))
+        .execute(conn)
+        .await
+        .map(|_| ())
+}
+
+/// Remove a login state, returning the PKCE verifier and nonce if it was
+/// created after `not_before`.
+pub async fn take_oidc_login_state(
+    conn: &mut AsyncPgConnection,
+    csrf_state: &str,
+    not_before: chrono::DateTime<Utc>,
+) -> Result<Option<(String, String)>, diesel::result::Error> {
+    use crate::server::schema::oidc_login_states::dsl;
+
+    diesel::delete(dsl::oidc_login_states.filter(dsl::created_at.lt(not_before)))
+        .execute(conn)
+        .await?;
+
+    diesel::delete(dsl::oidc_login_states.filter(dsl::csrf_state.eq(csrf_state)))
+        .returning((dsl::pkce_verifier, dsl::nonce))
+        .get_result(conn)
+        .await
+        .optional()
+}
+
+pub async fn create_session(
+    conn: &mut AsyncPgConnection,
+    session_hash: &str,
+    user_id: i32,
+    expires_at: chrono::DateTime<Utc>,
+) -> Result<(), diesel::result::Error> {
+    use crate::server::schema::sessions::dsl;
+
+    diesel::insert_into(dsl::sessions)
+        .values((
+            dsl::id.eq(session_hash),
+            dsl::user_id.eq(user_id),
+            dsl::expires_at.eq(expires_at),
+        ))
+        .execute(conn)
+        .await
+        .map(|_| ())
+}
+
+/// Get the user for a session that has not yet expired.
+#[instrument(skip_all)]
+pub async fn get_session_user(
+    conn: &mut AsyncPgConnection,
+    session_hash: &str,
+) -> Result<Option<User>, diesel::result::Error> {
+    use crate::server::schema::sessions::dsl;
+    use crate::server::schema::users;
+
+    dsl::sessions
+        .inner_join(users::table)
+        .filter(dsl::id.eq(session_hash))
+        .filter(dsl::expires_at.gt(Utc::now()))
+        .select(User::as_select())
+        .first(conn)
+        .await
+        .optional()
+}
+
+pub async fn delete_session(
+    conn: &mut AsyncPgConnection,
+    session_hash: &str,
+) -> Result<(), diesel::result::Error> {
+    use crate::server::schema::sessions::dsl;
+
+    diesel::delete(dsl::sessions.filter(dsl::id.eq(session_hash)))
+        .execute(conn)
+        .await
+        .map(|_| ())
+}
+
+pub async fn delete_expired_sessions(
+    conn: &mut AsyncPgConnection,
+) -> Result<usize, diesel::result::Error> {
+    use crate::server::schema::sessions::dsl;
+
+    diesel::delete(dsl::sessions.filter(dsl::expires_at.le(Utc::now())))
+        .execute(conn)
+        .await
+}
+
+pub async fn create_api_token(
+    conn: &mut AsyncPgConnection,
+    user_id: i32,
+    name: &str,
+    token_hash: &str,
+    scope: ApiTokenScope,
+    expires_at: chrono::DateTime<Utc>,
+) -> Result<ApiToken, diesel::result::Error> {
+    use crate::server::schema::api_tokens::dsl;
+
+    diesel::insert_into(dsl::api_tokens)
+        .values((
+            dsl::user_id.eq(user_id),
+            dsl::name.eq(name),
+            dsl::token_hash.eq(token_hash),
+            dsl::scope.eq(scope),
+            dsl::expires_at.eq(expires_at),
+        ))
+        .returning(ApiToken::as_returning())
+        .get_result(conn)
+        .await
+}
+
+/// List a user's tokens that have not been revoked.
+pub async fn list_api_tokens(
+    conn: &mut AsyncPgConnection,
+    user_id: i32,
+) -> Result<Vec<ApiToken>, diesel::result::Error> {
+    use crate::server::schema::api_tokens::dsl;
+
+    dsl::api_tokens
+        .filter(dsl::user_id.eq(user_id))
+        .filter(dsl::revoked_at.is_null())
+        .order(dsl::created_at.desc())
+        .select(ApiToken::as_select())
+        .load(conn)
+        .await
+}
+
+/// Revoke one of a user's tokens, returning `false` if there was none.
+pub async fn revoke_api_token(
+    conn: &mut AsyncPgConnection,
+    user_id: i32,
+    id: i32,
+) -> Result<bool, diesel::result::Error> {
+    use crate::server::schema::api_tokens::dsl;
+
+    diesel::update(
+        dsl::api_tokens
+            .filter(dsl::id.eq(id))
+            .filter(dsl::user_id.eq(user_id))
+            .filter(dsl::revoked_at.is_null()),
+    )
+    .set(dsl::revoked_at.eq(Utc::now()))
+    .execute(conn)
+    .await
+    .map(|count| count > 0)
+}
+
+/// Get the user and scope for a token that is neither expired nor revoked,
+/// recording that it has been used.
+#[instrument(skip_all)]
+pub async fn use_api_token(
+    conn: &mut AsyncPgConnection,
+    token_hash: &str,
+) -> Result<Option<(User, ApiTokenScope)>, diesel::result::Error> {
+    use crate::server::schema::api_tokens::dsl;
+    use crate::server::schema::users;
+
+    let now = Utc::now();
+    let token_user_id = diesel::update(
+        dsl::api_tokens
+            .filter(dsl::token_hash.eq(token_hash))
+            .filter(dsl::revoked_at.is_null())
+            .filter(dsl::expires_at.gt(now)),
+    )
+    .set(dsl::last_used_at.eq(now))
+    .returning((dsl::user_id, dsl::scope))
+    .get_result::<(i32, ApiTokenScope)>(conn)
+    .await
+    .optional()?;
+
+    let Some((user_id, scope)) = token_user_id else {
+        return Ok(None);
+    };
+
+    let user = users::table
+        .filter(users::id.eq(user_id))
+        .select(User::as_select())
+        .first(conn)
+        .await?;
+
+    Ok(Some((user, scope)))
 }
@@ -0,0 +1,870 @@
+//! SQLite storage for penguin encounters and accounts, for machines that
+//! can't run Postgres. Selected with `DATABASE_URL=sqlite://path/to/file.db`.
+//!
+//! The tables match the Postgres ones, with their own migrations in
+//! `migrations_sqlite`. Enums are stored as text, with the same names as in
+//! Postgres.
+
+use std::time::Duration;
+
+use chrono::Utc;
+use diesel::connection::SimpleConnection;
+use diesel::prelude::*;
+use diesel::sqlite::{Sqlite, SqliteConnection};
+use diesel_async::pooled_connection::mobc::{Pool, PooledConnection};
+use diesel_async::pooled_connection::{AsyncDieselConnectionManager, ManagerConfig};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::sync_connection_wrapper::SyncConnectionWrapper;
+use diesel_async::{AsyncConnection, RunQueryDsl};
+use diesel_migrations::{embed_migrations, EmbeddedMigrations};
+use futures::FutureExt;
+use tracing::{info, instrument};
+
+use super::{apply_pending_migrations, DatabaseError};
+use crate::model::{
+    ApiToken, ApiTokenScope, CreatePenguinEncounter, PenaltyEnum, PenguinEncounter, Role, User,
+};
+use crate::server::repository::EncounterFilter;
+
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations_sqlite");
+
+pub const URL_PREFIX: &str = "sqlite://";
+
+pub type SqliteAsyncConnection = SyncConnectionWrapper<SqliteConnection>;
+pub type SqlitePool = Pool<SqliteAsyncConnection>;
+pub type SqlitePooledConnection = PooledConnection<SqliteAsyncConnection>;
+
+/// How long a connection waits for another to finish writing before
+/// failing with "database is locked".
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+mod schema {
+    diesel::table! {
+        penguin_encounter (id) {
+            id -> Integer,
+            name -> Text,
+            location -> Text,
+            penalty -> Text,
+            date_time -> TimestamptzSqlite,
+        }
+    }
+
+    diesel::table! {
+        api_tokens (id) {
+            id -> Integer,
+            user_id -> Integer,
+            name -> Text,
+            token_hash -> Text,
+            scope -> Text,
+            created_at -> TimestamptzSqlite,
+            expires_at -> TimestamptzSqlite,
+            last_used_at -> Nullable<TimestamptzSqlite>,
+            revoked_at -> Nullable<TimestamptzSqlite>,
+        }
+    }
+
+    diesel::table! {
+        oidc_login_states (csrf_state) {
+            csrf_state -> Text,
+            pkce_verifier -> Text,
+            nonce -> Text,
+            created_at -> TimestamptzSqlite,
+        }
+    }
+
+    diesel::table! {
+        recovery_codes (id) {
+            id -> Integer,
+            user_id -> Integer,
+            code_hash -> Text,
+            used_at -> Nullable<TimestamptzSqlite>,
+        }
+    }
+
+    diesel::table! {
+        sessions (id) {
+            id -> Text,
+            user_id -> Integer,
+            created_at -> TimestamptzSqlite,
+            expires_at -> TimestamptzSqlite,
+        }
+    }
+
+    diesel::table! {
+        users (id) {
+            id -> Integer,
+            username -> Text,
+            password_hash -> Nullable<Text>,
+            created_at -> TimestamptzSqlite,
+            role -> Text,
+            oidc_subject -> Nullable<Text>,
+            totp_secret -> Nullable<Text>,
+            totp_enabled -> Bool,
+            totp_last_step -> Nullable<BigInt>,
+        }
+    }
+
+    diesel::joinable!(api_tokens -> users (user_id));
+    diesel::joinable!(recovery_codes -> users (user_id));
+    diesel::joinable!(sessions -> users (user_id));
+
+    diesel::allow_tables_to_appear_in_same_query!(
+        api_tokens,
+        oidc_login_states,
+        penguin_encounter,
+        recovery_codes,
+        sessions,
+        users,
+    );
+}
+
+use schema::{api_tokens, oidc_login_states, penguin_encounter, recovery_codes, sessions, users};
+
+/// The path of the database file, if `database_url` is a SQLite URL.
+pub fn path(database_url: &str) -> Option<&str> {
+    database_url.strip_prefix(URL_PREFIX)
+}
+
+/// Open a connection that waits for other writers rather than failing, and
+/// checks foreign keys. WAL lets readers carry on while another connection
+/// writes.
+fn connect(path: &str) -> ConnectionResult<SqliteConnection> {
+    let mut conn = SqliteConnection::establish(path)?;
+    conn.batch_execute(&format!(
+        "PRAGMA busy_timeout = {}; PRAGMA journal_mode = WAL; PRAGMA foreign_keys = ON;",
+        BUSY_TIMEOUT.as_millis()
+    ))
+    .map_err(ConnectionError::CouldntSetupConfiguration)?;
+    Ok(conn)
+}
+
+/// A synchronous connection, as needed for running migrations.
+pub fn migration_connection(path: &str) -> ConnectionResult<SqliteConnection> {
+    connect(path)
+}
+
+pub fn pool(path: &str) -> SqlitePool {
+    let mut manager_config = ManagerConfig::default();
+    manager_config.custom_setup = Box::new(|path| {
+        let path = path.to_string();
+        async move {
+            tokio::task::spawn_blocking(move || connect(&path).map(SyncConnectionWrapper::new))
+                .await
+                .map_err(|err| ConnectionError::BadConnection(err.to_string()))?
+        }
+        .boxed()
+    });
+
+    let config = AsyncDieselConnectionManager::<SqliteAsyncConnection>::new_with_config(
+        path,
+        manager_config,
+    );
+    Pool::new(config)
+}
+
+/// Open the database, creating it if needed, and run pending migrations.
+pub async fn init(path: &str) -> Result<SqlitePool, DatabaseError> {
+    let migration_path = path.to_string();
+    let applied = tokio::task::spawn_blocking(move || {
+        let mut conn = migration_connection(&migration_path)
+            .map_err(|err| DatabaseError::Unavailable(err.to_string()))?;
+        apply_pending_migrations(&mut conn, MIGRATIONS)
+    })
+    .await??;
+
+    for name in applied {
+        info!("Applied migration {name}");
+    }
+
+    Ok(pool(path))
+}
+
+/// Penalties are stored as text, with the same names as the Postgres enum.
+fn penalty_to_text(penalty: PenaltyEnum) -> &'static str {
+    match penalty {
+        PenaltyEnum::PatPenguin => "pat_penguin",
+        PenaltyEnum::BecomePenguinGood => "become_penguin_good",
+        PenaltyEnum::Jail => "jail",
+        PenaltyEnum::Sacrifice => "sacrifice",
+        PenaltyEnum::WorshipTux => "worship_tux",
+    }
+}
+
+fn penalty_from_text(text: &str) -> Result<PenaltyEnum, diesel::result::Error> {
+    PenaltyEnum::ALL
+        .into_iter()
+        .find(|penalty| penalty_to_text(*penalty) == text)
+        .ok_or_else(|| {
+            diesel::result::Error::DeserializationError(format!("Unknown penalty: {text}").into())
+        })
+}
+
+#[derive(Queryable, Selectable)]
+#[diesel(table_name = penguin_encounter)]
+#[diesel(check_for_backend(Sqlite))]
+struct EncounterRow {
+    id: i32,
+    name: String,
+    location: String,
+    penalty: String,
+    date_time: chrono::DateTime<Utc>,
+}
+
+impl TryFrom<EncounterRow> for PenguinEncounter {
+    type Error = diesel::result::Error;
+
+    fn try_from(row: EncounterRow) -> Result<Self, Self::Error> {
+        Ok(PenguinEncounter {
+            id: row.id,
+            name: row.name,
+            location: row.location,
+            penalty: penalty_from_text(&row.penalty)?,
+            date_time: row.date_time,
+        })
+    }
+}
+
+fn to_encounters(rows: Vec<EncounterRow>) -> Result<Vec<PenguinEncounter>, diesel::result::Error> {
+    rows.into_iter().map(PenguinEncounter::try_from).collect()
+}
+
+fn filter_query(filter: &EncounterFilter) -> penguin_encounter::BoxedQuery<'static, Sqlite> {
+    use penguin_encounter::dsl;
+
+    let mut query = dsl::penguin_encounter.into_boxed();
+    if let Some(name) = &filter.name {
+        query = query.filter(dsl::name.eq(name.clone()));
+    }
+    if let Some(location) = &filter.location {
+        query = query.filter(dsl::location.eq(location.clone()));
+    }
+    if let Some(penalty) = filter.penalty {
+        query = query.filter(dsl::penalty.eq(penalty_to_text(penalty)));
+    }
+    if let Some(from) = filter.from {
+        query = query.filter(dsl::date_time.ge(from));
+    }
+    if let Some(to) = filter.to {
+        query = query.filter(dsl::date_time.lt(to));
+    }
+    query
+}
+
+#[instrument(skip_all)]
+pub async fn list_penguin_encounters(
+    conn: &mut SqliteAsyncConnection,
+) -> Result<Vec<PenguinEncounter>, diesel::result::Error> {
+    let rows = penguin_encounter::table
+        .select(EncounterRow::as_select())
+        .load(conn)
+        .await?;
+    to_encounters(rows)
+}
+
+#[instrument(skip_all)]
+pub async fn search_penguin_encounters(
+    conn: &mut SqliteAsyncConnection,
+    filter: &EncounterFilter,
+    limit: i64,
+    offset: i64,
+) -> Result<(Vec<PenguinEncounter>, i64), diesel::result::Error> {
+    use penguin_encounter::dsl;
+
+    let total = filter_query(filter).count().get_result(conn).await?;
+
+    let rows = filter_query(filter)
+        .order((dsl::date_time.desc(), dsl::id.desc()))
+        .limit(limit)
+        .offset(offset)
+        .select(EncounterRow::as_select())
+        .load(conn)
+        .await?;
+
+    Ok((to_encounters(rows)?, total))
+}
+
+#[instrument(skip_all)]
+pub async fn list_penguin_names(
+    conn: &mut SqliteAsyncConnection,
+) -> Result<Vec<String>, diesel::result::Error> {
+    use penguin_encounter::dsl;
+
+    dsl::penguin_encounter
+        .select(dsl::name)
+        .distinct()
+        .order(dsl::name)
+        .load(conn)
+        .await
+}
+
+#[instrument(skip_all)]
+pub async fn get_penguin_encounter(
+    conn: &mut SqliteAsyncConnection,
+    id: i32,
+) -> Result<Option<PenguinEncounter>, diesel::result::Error> {
+    use penguin_encounter::dsl;
+
+    dsl::penguin_encounter
+        .filter(dsl::id.eq(id))
+        .select(EncounterRow::as_select())
+        .first(conn)
+        .await
+        .optional()?
+        .map(PenguinEncounter::try_from)
+        .transpose()
+}
+
+#[instrument(skip_all)]
+pub async fn create_penguin_encounter(
+    conn: &mut SqliteAsyncConnection,
+    encounter: &CreatePenguinEncounter<'_>,
+) -> Result<PenguinEncounter, diesel::result::Error> {
+    use penguin_encounter::dsl;
+
+    diesel::insert_into(dsl::penguin_encounter)
+        .values((
+            dsl::name.eq(encounter.name),
+            dsl::location.eq(encounter.location),
+            dsl::penalty.eq(penalty_to_text(encounter.penalty)),
+            dsl::date_time.eq(encounter.date_time),
+        ))
+        .returning(EncounterRow::as_returning())
+        .get_result(conn)
+        .await?
+        .try_into()
+}
+
+#[instrument(skip_all)]
+pub async fn update_penguin_encounter(
+    conn: &mut SqliteAsyncConnection,
+    id: i32,
+    encounter: &CreatePenguinEncounter<'_>,
+) -> Result<Option<PenguinEncounter>, diesel::result::Error> {
+    use penguin_encounter::dsl;
+
+    diesel::update(dsl::penguin_encounter.filter(dsl::id.eq(id)))
+        .set((
+            dsl::name.eq(encounter.name),
+            dsl::location.eq(encounter.location),
+            dsl::penalty.eq(penalty_to_text(encounter.penalty)),
+            dsl::date_time.eq(encounter.date_time),
+        ))
+        .returning(EncounterRow::as_returning())
+        .get_result(conn)
+        .await
+        .optional()?
+        .map(PenguinEncounter::try_from)
+        .transpose()
+}
+
+#[instrument(skip_all)]
+pub async fn delete_penguin_encounter(
+    conn: &mut SqliteAsyncConnection,
+    id: i32,
+) -> Result<bool, diesel::result::Error> {
+    use penguin_encounter::dsl;
+
+    diesel::delete(dsl::penguin_encounter.filter(dsl::id.eq(id)))
+        .execute(conn)
+        .await
+        .map(|count| count > 0)
+}
+
+fn role_to_text(role: Role) -> &'static str {
+    match role {
+        Role::Viewer => "viewer",
+        Role::Recorder => "recorder",
+        Role::Admin => "admin",
+    }
+}
+
+fn role_from_text(text: &str) -> Result<Role, diesel::result::Error> {
+    text.parse()
+        .map_err(|err: String| diesel::result::Error::DeserializationError(err.into()))
+}
+
+fn scope_to_text(scope: ApiTokenScope) -> &'static str {
+    match scope {
+        ApiTokenScope::Read => "read",
+        ApiTokenScope::Write => "write",
+    }
+}
+
+fn scope_from_text(text: &str) -> Result<ApiTokenScope, diesel::result::Error> {
+    match text {
+        "read" => Ok(ApiTokenScope::Read),
+        "write" => Ok(ApiTokenScope::Write),
+        _ => Err(diesel::result::Error::DeserializationError(
+            format!("Unknown API token scope: {text}").into(),
+        )),
+    }
+}
+
+#[derive(Queryable, Selectable)]
+#[diesel(table_name = users)]
+#[diesel(check_for_backend(Sqlite))]
+struct UserRow {
+    id: i32,
+    username: String,
+    role: String,
+    totp_enabled: bool,
+}
+
+impl TryFrom<UserRow> for User {
+    type Error = diesel::result::Error;
+
+    fn try_from(row: UserRow) -> Result<Self, Self::Error> {
+        Ok(User {
+            id: row.id,
+            username: row.username,
+            role: role_from_text(&row.role)?,
+            totp_enabled: row.totp_enabled,
+        })
+    }
+}
+
+#[derive(Queryable, Selectable)]
+#[diesel(table_name = api_tokens)]
+#[diesel(check_for_backend(Sqlite))]
+struct ApiTokenRow {
+    id: i32,
+    name: String,
+    scope: String,
+    created_at: chrono::DateTime<Utc>,
+    expires_at: chrono::DateTime<Utc>,
+    last_used_at: Option<chrono::DateTime<Utc>>,
+}
+
+impl TryFrom<ApiTokenRow> for ApiToken {
+    type Error = diesel::result::Error;
+
+    fn try_from(row: ApiTokenRow) -> Result<Self, Self::Error> {
+        Ok(ApiToken {
+            id: row.id,
+            name: row.name,
+            scope: scope_from_text(&row.scope)?,
+            created_at: row.created_at,
+            expires_at: row.expires_at,
+            last_used_at: row.last_used_at,
+        })
+    }
+}
+
+pub async fn count_users(conn: &mut SqliteAsyncConnection) -> Result<i64, diesel::result::Error> {
+    users::table.count().get_result(conn).await
+}
+
+pub async fn create_user(
+    conn: &mut SqliteAsyncConnection,
+    username: &str,
+    password_hash: &str,
+    role: Role,
+) -> Result<User, diesel::result::Error> {
+    diesel::insert_into(users::table)
+        .values((
+            users::username.eq(username),
+            users::password_hash.eq(password_hash),
+            users::role.eq(role_to_text(role)),
+        ))
+        .returning(UserRow::as_returning())
+        .get_result::<UserRow>(conn)
+        .await?
+        .try_into()
+}
+
+pub async fn get_user_by_username(
+    conn: &mut SqliteAsyncConnection,
+    username: &str,
+) -> Result<Option<(User, Option<String>)>, diesel::result::Error> {
+    let found = users::table
+        .filter(users::username.eq(username))
+        .select((UserRow::as_select(), users::password_hash))
+        .first::<(UserRow, Option<String>)>(conn)
+        .await
+        .optional()?;
+
+    let Some((row, password_hash)) = found else {
+        return Ok(None);
+    };
+    Ok(Some((row.try_into()?, password_hash)))
+}
+
+pub async fn get_user_by_oidc_subject(
+    conn: &mut SqliteAsyncConnection,
+    subject: &str,
+) -> Result<Option<User>, diesel::result::Error> {
+    users::table
+        .filter(users::oidc_subject.eq(subject))
+        .select(UserRow::as_select())
+        .first::<UserRow>(conn)
+        .await
+        .optional()?
+        .map(User::try_from)
+        .transpose()
+}
+
+pub async fn is_oidc_user(
+    conn: &mut SqliteAsyncConnection,
+    user_id: i32,
+) -> Result<bool, diesel::result::Error> {
+    diesel::select(diesel::dsl::exists(
+        users::table
+            .filter(users::id.eq(user_id))
+            .filter(users::oidc_subject.is_not_null()),
+    ))
+    .get_result(conn)
+    .await
+}
+
+pub async fn username_exists(
+    conn: &mut SqliteAsyncConnection,
+    username: &str,
+) -> Result<bool, diesel::result::Error> {
+    diesel::select(diesel::dsl::exists(
+        users::table.filter(users::username.eq(username)),
+    ))
+    .get_result(conn)
+    .await
+}
+
+pub async fn create_oidc_user(
+    conn: &mut SqliteAsyncConnection,
+    username: &str,
+    subject: &str,
+    role: Role,
+) -> Result<User, diesel::result::Error> {
+    diesel::insert_into(users::table)
+        .values((
+            users::username.eq(username),
+            users::oidc_subject.eq(subject),
+            users::role.eq(role_to_text(role)),
+        ))
+        .returning(UserRow::as_returning())
+        .get_result::<UserRow>(conn)
+        .await?
+        .try_into()
+}
+
+pub async fn set_user_role(
+    conn: &mut SqliteAsyncConnection,
+    id: i32,
+    role: Role,
+) -> Result<User, diesel::result::Error> {
+    diesel::update(users::table.filter(users::id.eq(id)))
+        .set(users::role.eq(role_to_text(role)))
+        .returning(UserRow::as_returning())
+        .get_result::<UserRow>(conn)
+        .await?
+        .try_into()
+}
+
+pub async fn list_users(
+    conn: &mut SqliteAsyncConnection,
+) -> Result<Vec<User>, diesel::result::Error> {
+    users::table
+        .order(users::username)
+        .select(UserRow::as_select())
+        .load::<UserRow>(conn)
+        .await?
+        .into_iter()
+        .map(User::try_from)
+        .collect()
+}
+
+pub async fn get_totp_secret(
+    conn: &mut SqliteAsyncConnection,
+    user_id: i32,
+) -> Result<Option<String>, diesel::result::Error> {
+    users::table
+        .filter(users::id.eq(user_id))
+        .select(users::totp_secret)
+        .first(conn)
+        .await
+}
+
+pub async fn set_pending_totp_secret(
+    conn: &mut SqliteAsyncConnection,
+    user_id: i32,
+    secret: &str,
+) -> Result<bool, diesel::result::Error> {
+    diesel::update(
+        users::table
+            .filter(users::id.eq(user_id))
+            .filter(users::totp_enabled.eq(false)),
+    )
+    .set(users::totp_secret.eq(secret))
+    .execute(conn)
+    .await
+    .map(|count| count > 0)
+}
+
+pub async fn enable_totp(
+    conn: &mut SqliteAsyncConnection,
+    user_id: i32,
+    recovery_code_hashes: &[String],
+) -> Result<(), diesel::result::Error> {
+    conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        async move {
+            diesel::update(users::table.filter(users::id.eq(user_id)))
+                .set(users::totp_enabled.eq(true))
+                .execute(conn)
+                .await?;
+
+            diesel::delete(recovery_codes::table.filter(recovery_codes::user_id.eq(user_id)))
+                .execute(conn)
+                .await?;
+
+            for code_hash in recovery_code_hashes {
+                diesel::insert_into(recovery_codes::table)
+                    .values((
+                        recovery_codes::user_id.eq(user_id),
+                        recovery_codes::code_hash.eq(code_hash),
+                    ))
+                    .execute(conn)
+                    .await?;
+            }
+
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await
+}
+
+pub async fn accept_totp_step(
+    conn: &mut SqliteAsyncConnection,
+    user_id: i32,
+    step: i64,
+) -> Result<bool, diesel::result::Error> {
+    diesel::update(
+        users::table.filter(users::id.eq(user_id)).filter(
+            users::totp_last_step
+                .is_null()
+                .or(users::totp_last_step.lt(step)),
+        ),
+    )
+    .set(users::totp_last_step.eq(step))
+    .execute(conn)
+    .await
+    .map(|count| count > 0)
+}
+
+pub async fn reset_totp(
+    conn: &mut SqliteAsyncConnection,
+    user_id: i32,
+) -> Result<bool, diesel::result::Error> {
+    conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        async move {
+            diesel::delete(recovery_codes::table.filter(recovery_codes::user_id.eq(user_id)))
+                .execute(conn)
+                .await?;
+
+            diesel::update(users::table.filter(users::id.eq(user_id)))
+                .set((
+                    users::totp_secret.eq(None::<String>),
+                    users::totp_enabled.eq(false),
+                    users::totp_last_step.eq(None::<i64>),
+                ))
+                .execute(conn)
+                .await
+                .map(|count| count > 0)
+        }
+        .scope_boxed()
+    })
+    .await
+}
+
+pub async fn use_recovery_code(
+    conn: &mut SqliteAsyncConnection,
+    user_id: i32,
+    code_hash: &str,
+) -> Result<bool, diesel::result::Error> {
+    diesel::update(
+        recovery_codes::table
+            .filter(recovery_codes::user_id.eq(user_id))
+            .filter(recovery_codes::code_hash.eq(code_hash))
+            .filter(recovery_codes::used_at.is_null()),
+    )
+    .set(recovery_codes::used_at.eq(Utc::now()))
+    .execute(conn)
+    .await
+    .map(|count| count > 0)
+}
+
+pub async fn create_oidc_login_state(
+    conn: &mut SqliteAsyncConnection,
+    csrf_state: &str,
+    pkce_verifier: &str,
+    nonce: &str,
+    not_before: chrono::DateTime<Utc>,
+) -> Result<(), diesel::result::Error> {
+    diesel::delete(oidc_login_states::table.filter(oidc_login_states::created_at.lt(not_before)))
+        .execute(conn)
+        .await?;
+
+    diesel::insert_into(oidc_login_states::table)
+        .values((
+            oidc_login_states::csrf_state.eq(csrf_state),
+            oidc_login_states::pkce_verifier.eq(pkce_verifier),
+            oidc_login_states::nonce.eq(nonce),
+            oidc_login_states::created_at.eq(Utc::now()),
+        ))
+        .execute(conn)
+        .await
+        .map(|_| ())
+}
+
+pub async fn take_oidc_login_state(
+    conn: &mut SqliteAsyncConnection,
+    csrf_state: &str,
+    not_before: chrono::DateTime<Utc>,
+) -> Result<Option<(String, String)>, diesel::result::Error> {
+    diesel::delete(oidc_login_states::table.filter(oidc_login_states::created_at.lt(not_before)))
+        .execute(conn)
+        .await?;
+
+    diesel::delete(oidc_login_states::table.filter(oidc_login_states::csrf_state.eq(csrf_state)))
+        .returning((oidc_login_states::pkce_verifier, oidc_login_states::nonce))
+        .get_result(conn)
+        .await
+        .optional()
+}
+
+pub async fn create_session(
+    conn: &mut SqliteAsyncConnection,
+    session_hash: &str,
+    user_id: i32,
+    expires_at: chrono::DateTime<Utc>,
+) -> Result<(), diesel::result::Error> {
+    diesel::insert_into(sessions::table)
+        .values((
+            sessions::id.eq(session_hash),
+            sessions::user_id.eq(user_id),
+            sessions::expires_at.eq(expires_at),
+        ))
+        .execute(conn)
+        .await
+        .map(|_| ())
+}
+
+#[instrument(skip_all)]
+pub async fn get_session_user(
+    conn: &mut SqliteAsyncConnection,
+    session_hash: &str,
+) -> Result<Option<User>, diesel::result::Error> {
+    sessions::table
+        .inner_join(users::table)
+        .filter(sessions::id.eq(session_hash))
+        .filter(sessions::expires_at.gt(Utc::now()))
+        .select(UserRow::as_select())
+        .first::<UserRow>(conn)
+        .await
+        .optional()?
+        .map(User::try_from)
+        .transpose()
+}
+
+pub async fn delete_session(
+    conn: &mut SqliteAsyncConnection,
+    session_hash: &str,
+) -> Result<(), diesel::result::Error> {
+    diesel::delete(sessions::table.filter(sessions::id.eq(session_hash)))
+        .execute(conn)
+        .await
+        .map(|_| ())
+}
+
+pub async fn delete_expired_sessions(
+    conn: &mut SqliteAsyncConnection,
+) -> Result<usize, diesel::result::Error> {
+    diesel::delete(sessions::table.filter(sessions::expires_at.le(Utc::now())))
+        .execute(conn)
+        .await
+}
+
+pub async fn create_api_token(
+    conn: &mut SqliteAsyncConnection,
+    user_id: i32,
+    name: &str,
+    token_hash: &str,
+    scope: ApiTokenScope,
+    expires_at: chrono::DateTime<Utc>,
+) -> Result<ApiToken, diesel::result::Error> {
+    diesel::insert_into(api_tokens::table)
+        .values((
+            api_tokens::user_id.eq(user_id),
+            api_tokens::name.eq(name),
+            api_tokens::token_hash.eq(token_hash),
+            api_tokens::scope.eq(scope_to_text(scope)),
+            api_tokens::created_at.eq(Utc::now()),
+            api_tokens::expires_at.eq(expires_at),
+        ))
+        .returning(ApiTokenRow::as_returning())
+        .get_result::<ApiTokenRow>(conn)
+        .await?
+        .try_into()
+}
+
+pub async fn list_api_tokens(
+    conn: &mut SqliteAsyncConnection,
+    user_id: i32,
+) -> Result<Vec<ApiToken>, diesel::result::Error> {
+    api_tokens::table
+        .filter(api_tokens::user_id.eq(user_id))
+        .filter(api_tokens::revoked_at.is_null())
+        .order(api_tokens::created_at.desc())
+        .select(ApiTokenRow::as_select())
+        .load::<ApiTokenRow>(conn)
+        .await?
+        .into_iter()
+        .map(ApiToken::try_from)
+        .collect()
+}
+
+pub async fn revoke_api_token(
+    conn: &mut SqliteAsyncConnection,
+    user_id: i32,
+    id: i32,
+) -> Result<bool, diesel::result::Error> {
+    diesel::update(
+        api_tokens::table
+            .filter(api_tokens::id.eq(id))
+            .filter(api_tokens::user_id.eq(user_id))
+            .filter(api_tokens::revoked_at.is_null()),
+    )
+    .set(api_tokens::revoked_at.eq(Utc::now()))
+    .execute(conn)
+    .await
+    .map(|count| count > 0)
+}
+
+#[instrument(skip_all)]
+pub async fn use_api_token(
+    conn: &mut SqliteAsyncConnection,
+    token_hash: &str,
+) -> Result<Option<(User, ApiTokenScope)>, diesel::result::Error> {
+    let now = Utc::now();
+    let token = diesel::update(
+        api_tokens::table
+            .filter(api_tokens::token_hash.eq(token_hash))
+            .filter(api_tokens::revoked_at.is_null())
+            .filter(api_tokens::expires_at.gt(now)),
+    )
+    .set(api_tokens::last_used_at.eq(now))
+    .returning((api_tokens::user_id, api_tokens::scope))
+    .get_result::<(i32, String)>(conn)
+    .await
+    .optional()?;
+
+    let Some((user_id, scope)) = token else {
+        return Ok(None);
+    };
+
+    let user = users::table
+        .filter(users::id.eq(user_id))
+        .select(UserRow::as_select())
+        .first::<UserRow>(conn)
+        .await?;
+
+    Ok(Some((user.try_into()?, scope_from_text(&scope)?)))
+}
@@ -0,0 +1,487 @@
+//! TLS for Postgres connections.
+//!
+//! `sslmode` and `sslrootcert` in `DATABASE_URL` are read as libpq does,
+//! except that the server certificate is always verified when TLS is used:
+//! `prefer` and `require` check it as `verify-full` does, and only
+//! `verify-ca` skips the hostname. Certificates are checked against
+//! `sslrootcert`, or the system's roots when that is unset or `system`.
+
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use diesel::ConnectionError;
+use diesel_async::AsyncPgConnection;
+use tokio_postgres::config::SslMode as PgSslMode;
+use tokio_postgres_rustls::MakeRustlsConnect;
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::crypto::{self, CryptoProvider};
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use tokio_rustls::rustls::server::ParsedCertificate;
+use tokio_rustls::rustls::{self, ClientConfig, DigitallySignedStruct, RootCertStore};
+use tracing::warn;
+use url::Url;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConnectionConfigError {
+    #[error("Invalid sslmode {0}")]
+    SslMode(String),
+    #[error("Invalid database URL: {0}")]
+    Url(String),
+    #[error("Failed to read {path}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("No certificates in {0}")]
+    NoCertificates(PathBuf),
+    #[error("No system root certificates: {0}")]
+    SystemRoots(String),
+    #[error("Invalid TLS configuration: {0}")]
+    Rustls(#[from] rustls::Error),
+}
+
+/// How a connection uses TLS, named as in libpq.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SslMode {
+    Disable,
+    Prefer,
+    Require,
+    VerifyCa,
+    VerifyFull,
+}
+
+impl FromStr for SslMode {
+    type Err = ConnectionConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disable" => Ok(SslMode::Disable),
+            // We never connect without TLS when the server offers it.
+            "allow" | "prefer" => Ok(SslMode::Prefer),
+            "require" => Ok(SslMode::Require),
+            "verify-ca" => Ok(SslMode::VerifyCa),
+            "verify-full" => Ok(SslMode::VerifyFull),
+            _ => Err(ConnectionConfigError::SslMode(s.to_string())),
+        }
+    }
+}
+
+/// Opens connections to the database in `DATABASE_URL`.
+#[derive(Clone)]
+pub struct Connector {
+    config: tokio_postgres::Config,
+    tls: Option<MakeRustlsConnect>,
+}
+
+impl Connector {
+    pub fn from_url(database_url: &str) -> Result<Self, ConnectionConfigError> {
+        // tokio-postgres rejects the modes it doesn't know, and `sslrootcert`,
+        // so they are taken out before it parses the rest.
+        let (rest, mode, root_cert) = split_ssl_options(database_url)?;
+        let mut config = tokio_postgres::Config::from_str(&rest)
+            .map_err(|err| ConnectionConfigError::Url(err.to_string()))?;
+
+        let mode = mode.unwrap_or(SslMode::Prefer);
+        let tls = match mode {
+            SslMode::Disable => {
+                config.ssl_mode(PgSslMode::Disable);
+                None
+            }
+            SslMode::Prefer => {
+                config.ssl_mode(PgSslMode::Prefer);
+                Some(client_config(root_cert.as_deref(), true)?)
+            }
+            SslMode::Require | SslMode::VerifyFull => {
+                config.ssl_mode(PgSslMode::Require);
+                Some(client_config(root_cert.as_deref(), true)?)
+            }
+            SslMode::VerifyCa => {
+                config.ssl_mode(PgSslMode::Require);
+                Some(client_config(root_cert.as_deref(), false)?)
+            }
+        };
+
+        Ok(Connector {
+            config,
+            tls: tls.map(MakeRustlsConnect::new),
+        })
+    }
+
+    pub async fn connect(&self) -> Result<AsyncPgConnection, ConnectionError> {
+        let bad_connection =
+            |err: tokio_postgres::Error| ConnectionError::BadConnection(err.to_string());
+
+        let client = match &self.tls {
+            Some(tls) => {
+                let (client, connection) = self
+                    .config
+                    .connect(tls.clone())
+                    .await
+                    .map_err(bad_connection)?;
+                tokio::spawn(drive(connection));
+                client
+            }
+            None => {
+                let (client, connection) = self
+                    .config
+                    .connect(tokio_postgres::NoTls)
+                    .await
+                    .map_err(bad_connection)?;
+                tokio::spawn(drive(connection));
+                client
+            }
+        };
+
+        AsyncPgConnection::try_from(client).await
+    }
+}
+
+/// Handle the connection's messages until it closes.
+async fn drive<F>(connection: F)
+where
+    F: std::future::Future<Output = Result<(), tokio_postgres::Error>>,
+{
+    if let Err(err) = connection.await {
+        warn!("Database connection failed: {err}");
+    }
+}
+
+/// Take `sslmode` and `sslrootcert` out of a URL or key/value connection
+/// string.
+fn split_ssl_options(
+    database_url: &str,
+) -> Result<(String, Option<SslMode>, Option<PathBuf>), ConnectionConfigError> {
+    let mut mode = None;
+    let mut root_cert = None;
+
+    if database_url.starts_with("postgres://") || database_url.starts_with("postgresql://") {
+        let mut url =
+            Url::parse(database_url).map_err(|err| ConnectionConfigError::Url(err.to_string()))?;
+        let mut rest = Vec::new();
+        for (key, value) in url.query_pairs() {
+            match &*key {
+                "sslmode" => mode = Some(value.parse()?),
+                "sslrootcert" => root_cert = Some(PathBuf::from(&*value)),
+                _ => rest.push((key.into_owned(), value.into_owned())),
+            }
+        }
+
+        if rest.is_empty() {
+            url.set_query(None);
+        } else {
+            url.query_pairs_mut().clear().extend_pairs(rest);
+        }
+        return Ok((url.into(), mode, root_cert));
+    }
+
+    let mut rest = Vec::new();
+    for option in database_url.split_whitespace() {
+        match option.split_once('=') {
+            Some(("sslmode", value)) => mode = Some(value.parse()?),
+            Some(("sslrootcert", value)) => root_cert = Some(PathBuf::from(value)),
+            _ => rest.push(option),
+        }
+    }
+    Ok((rest.join(" "), mode, root_cert))
+}
+
+fn root_certs(root_cert: Option<&Path>) -> Result<RootCertStore, ConnectionConfigError> {
+    let mut roots = RootCertStore::empty();
+
+    match root_cert {
+        Some(path) if path != Path::new("system") => {
+            let pem = std::fs::read(path).map_err(|source| ConnectionConfigError::Read {
+                path: path.to_path_buf(),
+                source,
+            })?;
+            for cert in rustls_pemfile::certs(&mut pem.as_slice()) {
+                let cert = cert.map_err(|source| ConnectionConfigError::Read {
+                    path: path.to_path_buf(),
+                    source,
+                })?;
+                roots.add(cert)?;
+            }
+            if roots.is_empty() {
+                return Err(ConnectionConfigError::NoCertificates(path.to_path_buf()));
+            }
+        }
+        _ => {
+            let native = rustls_native_certs::load_native_certs();
+            for err in &native.errors {
+                warn!("Failed to load a system root certificate: {err}");
+            }
+            roots.add_parsable_certificates(native.certs);
+            if roots.is_empty() {
+                return Err(ConnectionConfigError::SystemRoots(
+                    native
+                        .errors
+                        .first()
+                        .map(|err| err.to_string())
+                        .unwrap_or_else(|| "none found".to_string()),
+                ));
+            }
+        }
+    }
+
+    Ok(roots)
+}
+
+fn client_config(
+    root_cert: Option<&Path>,
+    verify_hostname: bool,
+) -> Result<ClientConfig, ConnectionConfigError> {
+    let provider = Arc::new(crypto::ring::default_provider());
+    let roots = Arc::new(root_certs(root_cert)?);
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+
+    let config = if verify_hostname {
+        builder.with_root_certificates(roots)
+    } else {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(VerifyCa { roots, provider }))
+    };
+    Ok(config.with_no_client_auth())
+}
+
+/// Checks the certificate chains to a trusted root, like `verify-ca`, but
+/// not that it names the host.
+#[derive(Debug)]
+struct VerifyCa {
+    roots: Arc<RootCertStore>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for VerifyCa {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let cert = ParsedCertificate::try_from(end_entity)?;
+        rustls::client::verify_server_cert_signed_by_trust_anchor(
+            &cert,
+            &self.roots,
+            intermediates,
+            now,
+            self.provider.signature_verification_algorithms.all,
+        )?;
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("src/server/testdata")
+            .join(name)
+    }
+
+    /// A server presenting a certificate for `localhost`, signed by
+    /// `db_ca.pem`.
+    fn server_config() -> rustls::ServerConfig {
+        let pem = std::fs::read(testdata("db_server.pem")).unwrap();
+        let certs = rustls_pemfile::certs(&mut pem.as_slice())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let pem = std::fs::read(testdata("db_server.key")).unwrap();
+        let key = rustls_pemfile::private_key(&mut pem.as_slice())
+            .unwrap()
+            .unwrap();
+        rustls::ServerConfig::builder_with_provider(Arc::new(crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .unwrap()
+    }
+
+    /// Complete a TLS handshake with [`server_config`] as `host`.
+    async fn handshake(client: ClientConfig, host: &'static str) -> std::io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        let acceptor = TlsAcceptor::from(Arc::new(server_config()));
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await?;
+            acceptor.accept(stream).await.map(drop)
+        });
+
+        let stream = TcpStream::connect(address).await?;
+        let result = TlsConnector::from(Arc::new(client))
+            .connect(ServerName::try_from(host).unwrap(), stream)
+            .await
+            .map(drop);
+        let _ = server.await;
+        result
+    }
+
+    #[test]
+    fn takes_ssl_options_out_of_urls() {
+        let (rest, mode, root_cert) = split_ssl_options(
+            "postgres://penguins@db.example.com/penguins?sslmode=verify-full&application_name=demo&sslrootcert=/etc/ssl/db-ca.pem",
+        )
+        .unwrap();
+        assert_eq!(
+            rest,
+            "postgres://penguins@db.example.com/penguins?application_name=demo"
+        );
+        assert_eq!(mode, Some(SslMode::VerifyFull));
+        assert_eq!(root_cert, Some(PathBuf::from("/etc/ssl/db-ca.pem")));
+
+        let (rest, mode, root_cert) =
+            split_ssl_options("postgresql://db.example.com/penguins?sslmode=disable").unwrap();
+        assert_eq!(rest, "postgresql://db.example.com/penguins");
+        assert_eq!(mode, Some(SslMode::Disable));
+        assert_eq!(root_cert, None);
+    }
+
+    #[test]
+    fn takes_ssl_options_out_of_key_value_strings() {
+        let (rest, mode, root_cert) = split_ssl_options(
+            "host=db.example.com sslmode=verify-ca user=penguins sslrootcert=ca.pem",
+        )
+        .unwrap();
+        assert_eq!(rest, "host=db.example.com user=penguins");
+        assert_eq!(mode, Some(SslMode::VerifyCa));
+        assert_eq!(root_cert, Some(PathBuf::from("ca.pem")));
+
+        let (rest, mode, root_cert) = split_ssl_options("host=localhost").unwrap();
+        assert_eq!(rest, "host=localhost");
+        assert_eq!(mode, None);
+        assert_eq!(root_cert, None);
+    }
+
+    #[test]
+    fn reads_libpq_ssl_modes() {
+        assert_eq!("allow".parse::<SslMode>().unwrap(), SslMode::Prefer);
+        assert_eq!("prefer".parse::<SslMode>().unwrap(), SslMode::Prefer);
+        assert_eq!("require".parse::<SslMode>().unwrap(), SslMode::Require);
+        assert!(matches!(
+            split_ssl_options("postgres://localhost/penguins?sslmode=sometimes"),
+            Err(ConnectionConfigError::SslMode(mode)) if mode == "sometimes"
+        ));
+        assert!(matches!(
+            Connector::from_url("host=localhost sslmode=verify"),
+            Err(ConnectionConfigError::SslMode(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_unusable_root_certificates() {
+        assert!(matches!(
+            root_certs(Some(&testdata("missing.pem"))),
+            Err(ConnectionConfigError::Read { .. })
+        ));
+        assert!(matches!(
+            root_certs(Some(&testdata("db_server.key"))),
+            Err(ConnectionConfigError::NoCertificates(_))
+        ));
+        assert_eq!(root_certs(Some(&testdata("db_ca.pem"))).unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn verifies_the_certificate_and_host_name() {
+        let ca = testdata("db_ca.pem");
+        handshake(client_config(Some(&ca), true).unwrap(), "localhost")
+            .await
+            .unwrap();
+        handshake(client_config(Some(&ca), true).unwrap(), "127.0.0.1")
+            .await
+            .unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn verify_ca_skips_only_the_host_name() {
+        let ca = testdata("db_ca.pem");
+        handshake(client_config(Some(&ca), false).unwrap(), "127.0.0.1")
+            .await
+            .unwrap();
+
+        let untrusted = testdata("db_untrusted_ca.pem");
+        handshake(client_config(Some(&untrusted), false).unwrap(), "localhost")
+            .await
+            .unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn rejects_certificates_from_untrusted_roots() {
+        let untrusted = testdata("db_untrusted_ca.pem");
+        handshake(client_config(Some(&untrusted), true).unwrap(), "localhost")
+            .await
+            .unwrap_err();
+    }
+
+    /// Needs a Postgres serving `db_server.pem`, as described in the README,
+    /// at `TLS_TEST_DATABASE_URL`.
+    #[tokio::test]
+    #[ignore = "needs Postgres with TLS at TLS_TEST_DATABASE_URL"]
+    async fn connects_to_postgres_over_tls() {
+        let url = std::env::var("TLS_TEST_DATABASE_URL").expect("TLS_TEST_DATABASE_URL is unset");
+        let with = |mode: &str, root_cert: &str| {
+            let root_cert = testdata(root_cert);
+            Connector::from_url(&format!(
+                "{url}?sslmode={mode}&sslrootcert={}",
+                root_cert.display()
+            ))
+            .unwrap()
+        };
+
+        with("verify-full", "db_ca.pem").connect().await.unwrap();
+        with("verify-ca", "db_ca.pem").connect().await.unwrap();
+        assert!(with("verify-full", "db_untrusted_ca.pem")
+            .connect()
+            .await
+            .is_err());
+        assert!(with("require", "db_untrusted_ca.pem")
+            .connect()
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,173 @@
+//! Panics, errors and warnings reported by clients.
+//!
+//! Reports are logged with the `client` target and counted. A crash loop in
+//! many browsers at once could send a lot of them, so they are rate limited
+//! across all clients, and anything over the limit is only counted. Anyone
+//! can send them, so every field is truncated, and none are used as metric
+//! labels.
+
+use std::sync::Arc;
+
+use axum::http::StatusCode;
+use axum::Extension;
+use tracing::{debug, error, warn};
+
+use crate::error_reporting::{ClientReports, ReportLevel};
+use crate::server::metrics;
+use crate::server::rate_limit::TokenBucket;
+
+/// Most reports logged at once.
+const BURST: u32 = 50;
+
+/// Reports logged per second, once the burst is used up.
+const PER_SECOND: f64 = 1.0;
+
+/// Longest message logged, in bytes.
+const MAX_MESSAGE_LEN: usize = 2_000;
+
+/// Longest location, route or version logged, in bytes.
+const MAX_FIELD_LEN: usize = 200;
+
+/// Most reports a client can say it dropped at once, so one can't inflate
+/// `client_errors_dropped_total`.
+const MAX_DROPPED: u32 = 1_000;
+
+#[derive(Clone)]
+pub struct ErrorReporting {
+    limit: Arc<TokenBucket>,
+}
+
+impl Default for ErrorReporting {
+    fn default() -> Self {
+        ErrorReporting {
+            limit: Arc::new(TokenBucket::new(BURST, PER_SECOND)),
+        }
+    }
+}
+
+/// At most the first `max_len` bytes of `text`, without splitting a
+/// character.
+pub fn truncate(text: &str, max_len: usize) -> &str {
+    if text.len() <= max_len {
+        return text;
+    }
+    let mut end = max_len;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+/// Sent with `navigator.sendBeacon`, so the body is JSON but not labelled
+/// as such.
+pub async fn client_errors(
+    Extension(reporting): Extension<ErrorReporting>,
+    body: String,
+) -> StatusCode {
+    let reports: ClientReports = match serde_json::from_str(&body) {
+        Ok(reports) => reports,
+        Err(err) => {
+            debug!("Invalid client error report: {err}");
+            return StatusCode::BAD_REQUEST;
+        }
+    };
+
+    if reports.dropped > 0 {
+        metrics::client_errors_dropped(reports.dropped.min(MAX_DROPPED).into());
+    }
+    if reports.reports.is_empty() {
+        return StatusCode::NO_CONTENT;
+    }
+
+    let count = reports.reports.len() as u32;
+    if reporting.limit.take(count).is_err() {
+        metrics::client_errors_dropped(count.into());
+        return StatusCode::TOO_MANY_REQUESTS;
+    }
+
+    for report in reports.reports {
+        metrics::client_error_reported(report.level);
+
+        let message = truncate(&report.message, MAX_MESSAGE_LEN);
+        let location = truncate(
+            report.location.as_deref().unwrap_or("unknown"),
+            MAX_FIELD_LEN,
+        );
+        let route = truncate(report.route.as_deref().unwrap_or("unknown"), MAX_FIELD_LEN);
+        let version = truncate(&report.version, MAX_FIELD_LEN);
+        match report.level {
+            ReportLevel::Panic => error!(
+                target: "client",
+                location, route, version, time = %report.time,
+                "Client panicked: {message}"
+            ),
+            ReportLevel::Error => error!(
+                target: "client",
+                location, route, version, time = %report.time,
+                "{message}"
+            ),
+            ReportLevel::Warn => warn!(
+                target: "client",
+                location, route, version, time = %report.time,
+                "{message}"
+            ),
+        }
+    }
+
+    StatusCode::NO_CONTENT
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+    use crate::error_reporting::ClientReport;
+
+    fn body(count: usize) -> String {
+        let report = ClientReport {
+            level: ReportLevel::Error,
+            message: "Failed to load encounters".to_string(),
+            location: Some("src/main.rs:1".to_string()),
+            route: Some("/".to_string()),
+            version: "0.1.0".to_string(),
+            time: Utc::now(),
+        };
+        serde_json::to_string(&ClientReports {
+            reports: vec![report; count],
+            dropped: 0,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn truncates_on_a_character_boundary() {
+        assert_eq!(truncate("penguin", 10), "penguin");
+        assert_eq!(truncate("penguin", 3), "pen");
+        // "ü" is two bytes, so is left out rather than cut in half.
+        assert_eq!(truncate("pingü", 5), "ping");
+        assert_eq!(truncate("pingü", 6), "pingü");
+    }
+
+    #[tokio::test]
+    async fn limits_reports_across_clients() {
+        let reporting = ErrorReporting::default();
+        let status = client_errors(Extension(reporting.clone()), body(BURST as usize)).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let status = client_errors(Extension(reporting), body(1)).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+
+        // More than the burst is refused outright, rather than logged in part.
+        let reporting = ErrorReporting::default();
+        let status = client_errors(Extension(reporting), body(BURST as usize + 1)).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_reports() {
+        let reporting = ErrorReporting::default();
+        let status = client_errors(Extension(reporting), "not json".to_string()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,33 @@
+//! Notifications about changes to encounters.
+
+use tokio::sync::broadcast;
+
+use crate::model::PenguinEncounter;
+use crate::server::metrics;
+
+/// Number of events a slow subscriber can fall behind by before missing some.
+const CAPACITY: usize = 64;
+
+#[derive(Clone)]
+pub struct EncounterEvents {
+    sender: broadcast::Sender<PenguinEncounter>,
+}
+
+impl Default for EncounterEvents {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        EncounterEvents { sender }
+    }
+}
+
+impl EncounterEvents {
+    pub fn created(&self, encounter: &PenguinEncounter) {
+        metrics::encounter_created(encounter.penalty);
+        // It is not an error for nobody to be listening.
+        let _ = self.sender.send(encounter.clone());
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<PenguinEncounter> {
+        self.sender.subscribe()
+    }
+}
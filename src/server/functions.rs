@@ -0,0 +1,77 @@
+//! Implementations shared by the `#[server]` functions and the websocket rpc
+//! transport.
+
+use dioxus::prelude::{server_context, ServerFnError};
+
+use crate::model::{self, CreatePenguinEncounter, PenguinEncounter};
+use crate::server::events::EncounterEvents;
+use crate::server::repository::Encounters;
+use crate::server::MyContext;
+
+pub const MAGIC_NUMBER: u32 = 42;
+
+#[derive(Debug, thiserror::Error)]
+#[error("`{0}` not found in server context")]
+pub struct NotInContext(&'static str);
+
+/// A value provided to the server functions, such as [`Encounters`].
+///
+/// Like extracting `FromContext<T>`, but without awaiting: for trait objects
+/// rustc can't prove that future `Send`, which server functions must be.
+pub fn context<T: Clone + Send + Sync + 'static>() -> Result<T, NotInContext> {
+    server_context()
+        .get::<T>()
+        .ok_or(NotInContext(std::any::type_name::<T>()))
+}
+
+pub fn echo(context: &MyContext, input: &str) -> String {
+    context.title.to_string() + ": " + &input.to_uppercase()
+}
+
+pub async fn get_penguin_encounters<E>(
+    encounters: &Encounters,
+) -> Result<Vec<PenguinEncounter>, ServerFnError<E>> {
+    let penguin_encounters = encounters
+        .list()
+        .await
+        .map_err(|err| ServerFnError::ServerError(err.to_string()))?;
+
+    Ok(penguin_encounters)
+}
+
+pub async fn create_penguin_encounter<E>(
+    encounters: &Encounters,
+    events: &EncounterEvents,
+    penalty: model::PenaltyEnum,
+) -> Result<PenguinEncounter, ServerFnError<E>> {
+    let penguin_encounter = encounters
+        .create(&CreatePenguinEncounter {
+            name: "Tux",
+            location: "Antarctica",
+            penalty,
+            date_time: chrono::Utc::now(),
+        })
+        .await
+        .map_err(|err| ServerFnError::ServerError(err.to_string()))?;
+
+    events.created(&penguin_encounter);
+    Ok(penguin_encounter)
+}
+
+pub async fn delete_penguin_encounter<E>(
+    encounters: &Encounters,
+    id: i32,
+) -> Result<(), ServerFnError<E>> {
+    let deleted = encounters
+        .delete(id)
+        .await
+        .map_err(|err| ServerFnError::ServerError(err.to_string()))?;
+
+    if !deleted {
+        return Err(ServerFnError::ServerError(format!(
+            "Penguin encounter {id} not found"
+        )));
+    }
+
+    Ok(())
+}
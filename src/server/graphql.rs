@@ -0,0 +1,560 @@
+//! GraphQL schema over penguin encounters.
+//!
+//! Queries and mutations are served at `/graphql`, which also serves GraphiQL
+//! for browsers, and subscriptions over a websocket at `/graphql/ws`. Access
+//! is checked against the caller's role in the same way as the REST API.
+
+use async_graphql::http::{
+    GraphiQLSource, WebSocket, WebSocketProtocols, WsMessage, ALL_WEBSOCKET_PROTOCOLS,
+};
+use async_graphql::{
+    ComplexObject, Context, InputObject, Object, Schema, SimpleObject, Subscription,
+};
+use axum::extract::ws::{CloseFrame, Message};
+use axum::extract::WebSocketUpgrade;
+use axum::http::header::{CONTENT_SECURITY_POLICY, SEC_WEBSOCKET_PROTOCOL};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{Html, IntoResponse, Response};
+use axum::{Extension, Json};
+use futures::{future, SinkExt, Stream, StreamExt};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{Instrument, Span};
+
+use crate::model::{CreatePenguinEncounter, PenaltyEnum, PenguinEncounter, Role};
+use crate::server::authz::{self, CurrentRole};
+use crate::server::events::EncounterEvents;
+use crate::server::metrics::WebsocketGuard;
+use crate::server::rate_limit::WebsocketPermit;
+use crate::server::repository::{EncounterFilter, Encounters};
+use crate::server::shutdown::{self, Shutdown};
+
+const MAX_LIMIT: i64 = 500;
+
+/// Penguins link back to their encounters and encounters to their penguin, so
+/// queries are limited in how deep they nest and how many rows they could
+/// fetch, counting each list as its `limit`. GraphiQL's introspection query
+/// nests 13 deep.
+const MAX_DEPTH: usize = 16;
+const MAX_COMPLEXITY: usize = 10_000;
+
+/// How many penguins `penguins` is counted as returning.
+const PENGUINS_COMPLEXITY: usize = 50;
+
+pub type EncounterSchema = Schema<QueryRoot, MutationRoot, SubscriptionRoot>;
+
+pub fn schema(encounters: Encounters, events: EncounterEvents) -> EncounterSchema {
+    Schema::build(QueryRoot, MutationRoot, SubscriptionRoot)
+        .data(encounters)
+        .data(events)
+        .limit_depth(MAX_DEPTH)
+        .limit_complexity(MAX_COMPLEXITY)
+        .finish()
+}
+
+/// The complexity of a list of up to `limit` items.
+fn list_complexity(limit: i64, child_complexity: usize) -> usize {
+    usize::try_from(limit.clamp(1, MAX_LIMIT))
+        .unwrap_or(1)
+        .saturating_mul(child_complexity)
+}
+
+/// The role of the caller, added to every request.
+struct GraphqlRole(Option<Role>);
+
+fn require(ctx: &Context<'_>, required: Role) -> async_graphql::Result<()> {
+    let GraphqlRole(role) = ctx.data::<GraphqlRole>()?;
+    authz::check(*role, required)?;
+    Ok(())
+}
+
+fn encounters<'a>(ctx: &Context<'a>) -> async_graphql::Result<&'a Encounters> {
+    ctx.data::<Encounters>()
+}
+
+/// A penguin, identified by name.
+pub struct Penguin {
+    name: String,
+}
+
+#[Object]
+impl Penguin {
+    async fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Encounters with this penguin, newest first.
+    #[graphql(complexity = "list_complexity(limit, child_complexity)")]
+    async fn encounters(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = 50)] limit: i64,
+        #[graphql(default = 0)] offset: i64,
+    ) -> async_graphql::Result<Vec<PenguinEncounter>> {
+        let filter = EncounterFilter {
+            name: Some(self.name.clone()),
+            ..Default::default()
+        };
+        let page = search(ctx, &filter, limit, offset).await?;
+        Ok(page.items)
+    }
+}
+
+/// A penalty that can be assigned in an encounter.
+pub struct Penalty {
+    penalty: PenaltyEnum,
+}
+
+#[Object]
+impl Penalty {
+    async fn penalty(&self) -> PenaltyEnum {
+        self.penalty
+    }
+
+    async fn description(&self) -> String {
+        self.penalty.to_string()
+    }
+
+    /// The role needed to assign this penalty.
+    async fn required_role(&self) -> Role {
+        self.penalty.required_role()
+    }
+
+    /// Encounters with this penalty, newest first.
+    #[graphql(complexity = "list_complexity(limit, child_complexity)")]
+    async fn encounters(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = 50)] limit: i64,
+        #[graphql(default = 0)] offset: i64,
+    ) -> async_graphql::Result<Vec<PenguinEncounter>> {
+        let filter = EncounterFilter {
+            penalty: Some(self.penalty),
+            ..Default::default()
+        };
+        let page = search(ctx, &filter, limit, offset).await?;
+        Ok(page.items)
+    }
+}
+
+#[ComplexObject]
+impl PenguinEncounter {
+    async fn penguin(&self) -> Penguin {
+        Penguin {
+            name: self.name.clone(),
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct EncounterPage {
+    items: Vec<PenguinEncounter>,
+    total: i64,
+}
+
+#[derive(InputObject)]
+pub struct EncounterInput {
+    name: String,
+    location: String,
+    penalty: PenaltyEnum,
+    date_time: chrono::DateTime<chrono::Utc>,
+}
+
+impl EncounterInput {
+    fn validate(&self) -> async_graphql::Result<()> {
+        if self.name.trim().is_empty() {
+            return Err("name must not be empty".into());
+        }
+        if self.location.trim().is_empty() {
+            return Err("location must not be empty".into());
+        }
+        Ok(())
+    }
+
+    fn as_new(&self) -> CreatePenguinEncounter<'_> {
+        CreatePenguinEncounter {
+            name: &self.name,
+            location: &self.location,
+            penalty: self.penalty,
+            date_time: self.date_time,
+        }
+    }
+}
+
+async fn search(
+    ctx: &Context<'_>,
+    filter: &EncounterFilter,
+    limit: i64,
+    offset: i64,
+) -> async_graphql::Result<EncounterPage> {
+    require(ctx, Role::Viewer)?;
+
+    if !(1..=MAX_LIMIT).contains(&limit) {
+        return Err(format!("limit must be between 1 and {MAX_LIMIT}").into());
+    }
+    if offset < 0 {
+        return Err("offset must not be negative".into());
+    }
+
+    let (items, total) = encounters(ctx)?.search(filter, limit, offset).await?;
+    Ok(EncounterPage { items, total })
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    /// Encounters matching all of the given filters, newest first.
+    #[allow(clippy::too_many_arguments)]
+    #[graphql(complexity = "list_complexity(limit, child_complexity)")]
+    async fn encounters(
+        &self,
+        ctx: &Context<'_>,
+        name: Option<String>,
+        location: Option<String>,
+        penalty: Option<PenaltyEnum>,
+        from: Option<chrono::DateTime<chrono::Utc>>,
+        to: Option<chrono::DateTime<chrono::Utc>>,
+        #[graphql(default = 50)] limit: i64,
+        #[graphql(default = 0)] offset: i64,
+    ) -> async_graphql::Result<EncounterPage> {
+        let filter = EncounterFilter {
+            name,
+            location,
+            penalty,
+            from,
+            to,
+        };
+        search(ctx, &filter, limit, offset).await
+    }
+
+    async fn encounter(
+        &self,
+        ctx: &Context<'_>,
+        id: i32,
+    ) -> async_graphql::Result<Option<PenguinEncounter>> {
+        require(ctx, Role::Viewer)?;
+        Ok(encounters(ctx)?.get(id).await?)
+    }
+
+    /// Every penguin that has been encountered.
+    #[graphql(complexity = "PENGUINS_COMPLEXITY * child_complexity")]
+    async fn penguins(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<Penguin>> {
+        require(ctx, Role::Viewer)?;
+        let names = encounters(ctx)?.names().await?;
+        Ok(names.into_iter().map(|name| Penguin { name }).collect())
+    }
+
+    async fn penalties(&self) -> Vec<Penalty> {
+        PenaltyEnum::ALL
+            .iter()
+            .map(|penalty| Penalty { penalty: *penalty })
+            .collect()
+    }
+}
+
+pub struct MutationRoot;
+
+#[Object]
+impl MutationRoot {
+    /// Assigning `SACRIFICE` requires the admin role.
+    async fn create_encounter(
+        &self,
+        ctx: &Context<'_>,
+        input: EncounterInput,
+    ) -> async_graphql::Result<PenguinEncounter> {
+        require(ctx, input.penalty.required_role())?;
+        input.validate()?;
+
+        let encounter = encounters(ctx)?.create(&input.as_new()).await?;
+
+        ctx.data::<EncounterEvents>()?.created(&encounter);
+        Ok(encounter)
+    }
+
+    /// Assigning or removing `SACRIFICE` requires the admin role.
+    async fn update_encounter(
+        &self,
+        ctx: &Context<'_>,
+        id: i32,
+        input: EncounterInput,
+    ) -> async_graphql::Result<Option<PenguinEncounter>> {
+        require(ctx, input.penalty.required_role())?;
+        input.validate()?;
+
+        let encounters = encounters(ctx)?;
+        if let Some(existing) = encounters.get(id).await? {
+            require(ctx, existing.penalty.required_role())?;
+        }
+
+        Ok(encounters.update(id, &input.as_new()).await?)
+    }
+
+    /// Returns `false` if there was no such encounter. Requires the admin role.
+    async fn delete_encounter(&self, ctx: &Context<'_>, id: i32) -> async_graphql::Result<bool> {
+        require(ctx, Role::Admin)?;
+        Ok(encounters(ctx)?.delete(id).await?)
+    }
+}
+
+pub struct SubscriptionRoot;
+
+#[Subscription]
+impl SubscriptionRoot {
+    /// Encounters as they are created.
+    async fn encounter_created(
+        &self,
+        ctx: &Context<'_>,
+    ) -> async_graphql::Result<impl Stream<Item = PenguinEncounter>> {
+        require(ctx, Role::Viewer)?;
+        let receiver = ctx.data::<EncounterEvents>()?.subscribe();
+
+        Ok(futures::stream::unfold(
+            receiver,
+            |mut receiver| async move {
+                loop {
+                    match receiver.recv().await {
+                        Ok(encounter) => return Some((encounter, receiver)),
+                        Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => return None,
+                    }
+                }
+            },
+        ))
+    }
+}
+
+/// The playground loads GraphiQL from unpkg, which the default policy
+/// doesn't allow.
+const GRAPHIQL_CONTENT_SECURITY_POLICY: &str = "default-src 'self'; \
+    script-src 'self' 'unsafe-inline' https://unpkg.com; \
+    style-src 'self' 'unsafe-inline' https://unpkg.com; \
+    img-src 'self' data: https://unpkg.com; font-src 'self' data: https://unpkg.com; \
+    connect-src 'self'";
+
+pub async fn graphiql() -> impl IntoResponse {
+    (
+        [(CONTENT_SECURITY_POLICY, GRAPHIQL_CONTENT_SECURITY_POLICY)],
+        Html(
+            GraphiQLSource::build()
+                .endpoint("/graphql")
+                .subscription_endpoint("/graphql/ws")
+                .finish(),
+        ),
+    )
+}
+
+pub async fn graphql_handler(
+    Extension(schema): Extension<EncounterSchema>,
+    CurrentRole(role): CurrentRole,
+    Json(request): Json<async_graphql::Request>,
+) -> Json<async_graphql::Response> {
+    Json(schema.execute(request.data(GraphqlRole(role))).await)
+}
+
+/// The first subprotocol the client offered that we speak.
+fn websocket_protocol(headers: &HeaderMap) -> Option<WebSocketProtocols> {
+    headers
+        .get(SEC_WEBSOCKET_PROTOCOL)?
+        .to_str()
+        .ok()?
+        .split(',')
+        .find_map(|protocol| protocol.trim().parse().ok())
+}
+
+pub async fn graphql_ws_handler(
+    Extension(schema): Extension<EncounterSchema>,
+    Extension(shutdown): Extension<Shutdown>,
+    CurrentRole(role): CurrentRole,
+    permit: WebsocketPermit,
+    headers: HeaderMap,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    let Some(protocol) = websocket_protocol(&headers) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    let span = Span::current();
+    let task = shutdown.task();
+    upgrade
+        .protocols(ALL_WEBSOCKET_PROTOCOLS)
+        .on_upgrade(move |socket| {
+            async move {
+                let _permit = permit;
+                let _task = task;
+                let _guard = WebsocketGuard::new("graphql");
+                let mut data = async_graphql::Data::default();
+                data.insert(GraphqlRole(role));
+
+                // The socket is split rather than handed over, so it is still
+                // available to close once the subscriptions stop.
+                let (mut sink, stream) = socket.split();
+                let requests = stream
+                    .take_until(Box::pin(shutdown.wait()))
+                    .take_while(|message| future::ready(message.is_ok()))
+                    .filter_map(|message| {
+                        future::ready(match message {
+                            Ok(Message::Text(text)) => Some(text.into_bytes()),
+                            Ok(Message::Binary(data)) => Some(data),
+                            _ => None,
+                        })
+                    });
+                let mut replies = WebSocket::new(schema, requests, protocol)
+                    .connection_data(data)
+                    .map(|reply| match reply {
+                        WsMessage::Text(text) => Message::Text(text),
+                        WsMessage::Close(code, reason) => Message::Close(Some(CloseFrame {
+                            code,
+                            reason: reason.into(),
+                        })),
+                    });
+                while let Some(reply) = replies.next().await {
+                    if sink.send(reply).await.is_err() {
+                        break;
+                    }
+                }
+
+                if shutdown.is_triggered() {
+                    let _ = sink.send(shutdown::close_message()).await;
+                }
+            }
+            .instrument(span)
+        })
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use async_graphql::{Request, Response};
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::server::repository::InMemoryEncounterRepository;
+
+    fn test_schema() -> EncounterSchema {
+        schema(
+            Arc::new(InMemoryEncounterRepository::new()),
+            EncounterEvents::default(),
+        )
+    }
+
+    async fn execute(schema: &EncounterSchema, role: Option<Role>, query: &str) -> Response {
+        schema
+            .execute(Request::new(query).data(GraphqlRole(role)))
+            .await
+    }
+
+    fn data(response: Response) -> Value {
+        assert!(response.errors.is_empty(), "{:?}", response.errors);
+        response.data.into_json().unwrap()
+    }
+
+    fn create(penalty: &str) -> String {
+        format!(
+            r#"mutation {{ createEncounter(input: {{ name: "Tux", location: "Hobart",
+                penalty: {penalty}, dateTime: "2024-12-01T12:00:00Z" }}) {{ id penalty }} }}"#
+        )
+    }
+
+    #[tokio::test]
+    async fn queries_require_a_role() {
+        let schema = test_schema();
+        let query = "{ encounters { total } }";
+
+        let response = execute(&schema, None, query).await;
+        assert_eq!(response.errors.len(), 1);
+
+        let response = execute(&schema, Some(Role::Viewer), query).await;
+        assert_eq!(data(response), json!({ "encounters": { "total": 0 } }));
+    }
+
+    #[tokio::test]
+    async fn creating_requires_the_penalty_role() {
+        let schema = test_schema();
+
+        let response = execute(&schema, Some(Role::Viewer), &create("JAIL")).await;
+        assert_eq!(response.errors.len(), 1);
+
+        let response = execute(&schema, Some(Role::Recorder), &create("JAIL")).await;
+        assert_eq!(data(response)["createEncounter"]["penalty"], "JAIL");
+
+        let response = execute(&schema, Some(Role::Recorder), &create("SACRIFICE")).await;
+        assert_eq!(response.errors.len(), 1);
+
+        let response = execute(&schema, Some(Role::Admin), &create("SACRIFICE")).await;
+        assert_eq!(data(response)["createEncounter"]["penalty"], "SACRIFICE");
+    }
+
+    #[tokio::test]
+    async fn only_admins_can_take_away_a_sacrifice_or_delete() {
+        let schema = test_schema();
+        let created = data(execute(&schema, Some(Role::Admin), &create("SACRIFICE")).await);
+        let id = &created["createEncounter"]["id"];
+
+        let update = format!(
+            r#"mutation {{ updateEncounter(id: {id}, input: {{ name: "Tux", location: "Hobart",
+                penalty: JAIL, dateTime: "2024-12-01T12:00:00Z" }}) {{ penalty }} }}"#
+        );
+        let response = execute(&schema, Some(Role::Recorder), &update).await;
+        assert_eq!(response.errors.len(), 1);
+        let response = execute(&schema, Some(Role::Admin), &update).await;
+        assert_eq!(data(response)["updateEncounter"]["penalty"], "JAIL");
+
+        let delete = format!("mutation {{ deleteEncounter(id: {id}) }}");
+        let response = execute(&schema, Some(Role::Recorder), &delete).await;
+        assert_eq!(response.errors.len(), 1);
+        let response = execute(&schema, Some(Role::Admin), &delete).await;
+        assert_eq!(data(response)["deleteEncounter"], true);
+        let response = execute(&schema, Some(Role::Admin), &delete).await;
+        assert_eq!(data(response)["deleteEncounter"], false);
+    }
+
+    #[tokio::test]
+    async fn searches_by_penguin_and_paginates() {
+        let schema = test_schema();
+        for _ in 0..3 {
+            data(execute(&schema, Some(Role::Recorder), &create("JAIL")).await);
+        }
+
+        let query = r#"{ encounters(name: "Tux", limit: 2, offset: 2) { total items { id } } }"#;
+        let response = data(execute(&schema, Some(Role::Viewer), query).await);
+        assert_eq!(response["encounters"]["total"], 3);
+        assert_eq!(response["encounters"]["items"], json!([{ "id": 1 }]));
+
+        let query = r#"{ encounters(name: "Gentoo") { total } }"#;
+        let response = data(execute(&schema, Some(Role::Viewer), query).await);
+        assert_eq!(response["encounters"]["total"], 0);
+
+        let query = "{ encounters(limit: 0) { total } }";
+        let response = execute(&schema, Some(Role::Viewer), query).await;
+        assert_eq!(response.errors.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn rejects_deep_or_expensive_queries() {
+        let schema = test_schema();
+        data(execute(&schema, Some(Role::Recorder), &create("JAIL")).await);
+
+        let query = "{ penguins { name encounters { id penguin { name } } } }";
+        let response = data(execute(&schema, Some(Role::Viewer), query).await);
+        assert_eq!(
+            response["penguins"][0]["encounters"][0]["penguin"]["name"],
+            "Tux"
+        );
+
+        let mut query = "id".to_string();
+        for _ in 0..MAX_DEPTH / 2 {
+            query = format!("encounters(limit: 1) {{ penguin {{ {query} }} }}");
+        }
+        let query = format!("{{ penguins {{ {query} }} }}");
+        let response = execute(&schema, Some(Role::Viewer), &query).await;
+        assert_eq!(response.errors.len(), 1);
+        assert!(response.errors[0].message.contains("nested too deep"));
+
+        let query = "{ encounters(limit: 500) { items { penguin {
+            encounters(limit: 500) { id } } } } }";
+        let response = execute(&schema, Some(Role::Viewer), query).await;
+        assert_eq!(response.errors.len(), 1);
+        assert!(response.errors[0].message.contains("too complex"));
+    }
+}
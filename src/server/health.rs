@@ -0,0 +1,278 @@
+//! Health endpoints, nested under `/_health`.
+//!
+//! - `/_health/live` only checks the server is responding, for restarting it
+//!   if it stops.
+//! - `/_health/ready`, also served at `/_health`, checks the database is
+//!   migrated and answers a trivial query, for routing traffic to it.
+//! - `/_health/details` describes the server for people, and is slower. As
+//!   it gives away the version and database state, it needs the admin role.
+//!
+//! The systemd watchdog is only pinged while the whole app answers the
+//! liveness check, see [`answers_live`].
+
+use std::time::{Duration, Instant};
+
+use axum::body::Body;
+use axum::http::{Request, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Extension, Json, Router};
+use serde::Serialize;
+use tower::ServiceExt;
+use tracing::warn;
+
+use crate::model::{AccessError, Role};
+use crate::server::authz::{self, CurrentRole};
+use crate::server::database::{self, DatabasePool, DatabaseState};
+
+/// How long readiness waits for the database before failing.
+const READY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Where the liveness check is served in the app.
+const LIVE_PATH: &str = "/_health/live";
+
+/// How long details waits for the migration status.
+const DETAILS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What the health endpoints check, added as an extension.
+#[derive(Clone)]
+pub struct Health {
+    started: Instant,
+    /// The Postgres database, if one is configured.
+    database: Option<(DatabasePool, DatabaseState)>,
+}
+
+impl Health {
+    pub fn new(database: Option<(DatabasePool, DatabaseState)>) -> Self {
+        Health {
+            started: Instant::now(),
+            database,
+        }
+    }
+}
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/", get(ready))
+        .route("/live", get(live))
+        .route("/ready", get(ready))
+        .route("/details", get(details))
+}
+
+#[derive(Serialize)]
+struct Status {
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+async fn live() -> Json<Status> {
+    Json(Status {
+        status: "ok",
+        error: None,
+    })
+}
+
+/// Whether `app` answers the liveness check within `timeout`.
+pub async fn answers_live(app: Router, timeout: Duration) -> bool {
+    let request = Request::get(LIVE_PATH)
+        .body(Body::empty())
+        .expect("Liveness request is valid");
+
+    match tokio::time::timeout(timeout, app.oneshot(request)).await {
+        // Being rate limited is still an answer.
+        Ok(Ok(response)) => {
+            response.status().is_success() || response.status() == StatusCode::TOO_MANY_REQUESTS
+        }
+        Ok(Err(never)) => match never {},
+        Err(_) => false,
+    }
+}
+
+async fn check_ready(pool: &DatabasePool, state: &DatabaseState) -> Result<(), String> {
+    if !state.is_ready() {
+        return Err("Database not connected or not migrated".to_string());
+    }
+
+    let ping = async {
+        let mut conn = pool.get().await.map_err(|err| err.to_string())?;
+        database::ping(&mut conn)
+            .await
+            .map_err(|err| err.to_string())
+    };
+
+    tokio::time::timeout(READY_TIMEOUT, ping)
+        .await
+        .unwrap_or_else(|_| Err(format!("Database did not answer within {READY_TIMEOUT:?}")))
+}
+
+async fn ready(Extension(health): Extension<Health>) -> Response {
+    let Some((pool, state)) = &health.database else {
+        return live().await.into_response();
+    };
+
+    match check_ready(pool, state).await {
+        Ok(()) => live().await.into_response(),
+        Err(err) => {
+            warn!("Not ready: {err}");
+            let status = Status {
+                status: "error",
+                error: Some(err),
+            };
+            (StatusCode::SERVICE_UNAVAILABLE, Json(status)).into_response()
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Details {
+    status: &'static str,
+    version: &'static str,
+    uptime_secs: u64,
+    database: Option<DatabaseDetails>,
+}
+
+#[derive(Serialize)]
+struct DatabaseDetails {
+    ready: bool,
+    pool: PoolDetails,
+    /// The most recently applied migration.
+    migration: Option<String>,
+    pending_migrations: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct PoolDetails {
+    max_open: u64,
+    connections: u64,
+    in_use: u64,
+    idle: u64,
+}
+
+async fn database_details(pool: &DatabasePool, state: &DatabaseState) -> DatabaseDetails {
+    let pool_state = pool.state().await;
+    let pool_details = PoolDetails {
+        max_open: pool_state.max_open,
+        connections: pool_state.connections,
+        in_use: pool_state.in_use,
+        idle: pool_state.idle,
+    };
+
+    let migrations = tokio::time::timeout(DETAILS_TIMEOUT, database::migration_status(pool))
+        .await
+        .unwrap_or_else(|_| {
+            Err(database::DatabaseError::Unavailable(format!(
+                "no answer within {DETAILS_TIMEOUT:?}"
+            )))
+        });
+
+    match migrations {
+        Ok(migrations) => DatabaseDetails {
+            ready: state.is_ready(),
+            pool: pool_details,
+            migration: migrations.applied.last().cloned(),
+            pending_migrations: Some(migrations.pending),
+            error: None,
+        },
+        Err(err) => DatabaseDetails {
+            ready: state.is_ready(),
+            pool: pool_details,
+            migration: None,
+            pending_migrations: None,
+            error: Some(err.to_string()),
+        },
+    }
+}
+
+async fn details(Extension(health): Extension<Health>, CurrentRole(role): CurrentRole) -> Response {
+    match authz::check(role, Role::Admin) {
+        Ok(_) => Json(describe(&health).await).into_response(),
+        Err(err @ AccessError::NotLoggedIn) => {
+            (StatusCode::UNAUTHORIZED, err.to_string()).into_response()
+        }
+        Err(err @ AccessError::Forbidden(_)) => {
+            (StatusCode::FORBIDDEN, err.to_string()).into_response()
+        }
+    }
+}
+
+async fn describe(health: &Health) -> Details {
+    let database = match &health.database {
+        Some((pool, state)) => Some(database_details(pool, state).await),
+        None => None,
+    };
+    let healthy = database
+        .as_ref()
+        .is_none_or(|database| database.ready && database.error.is_none());
+
+    Details {
+        status: if healthy { "ok" } else { "error" },
+        version: env!("CARGO_PKG_VERSION"),
+        uptime_secs: health.started.elapsed().as_secs(),
+        database,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::server::config::{Config, Settings};
+
+    const ROLE_HEADER: &str = "x-role";
+
+    /// The health endpoints, with a database that isn't ready.
+    fn app() -> Router {
+        let config = Config::load(
+            Settings {
+                role_header: Some(ROLE_HEADER.to_string()),
+                ..Default::default()
+            },
+            None,
+        )
+        .unwrap();
+        let health = Health::new(Some((database::pool(""), DatabaseState::new(false))));
+
+        router()
+            .layer(Extension(health))
+            .layer(Extension(Arc::new(config)))
+    }
+
+    async fn status(uri: &str, role: Option<&str>) -> StatusCode {
+        let mut request = Request::get(uri);
+        if let Some(role) = role {
+            request = request.header(ROLE_HEADER, role);
+        }
+        app()
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn live_ignores_the_database() {
+        assert_eq!(status("/live", None).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn not_ready_until_the_database_is() {
+        assert_eq!(
+            status("/ready", None).await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(status("/", None).await, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn details_require_the_admin_role() {
+        assert_eq!(status("/details", None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            status("/details", Some("recorder")).await,
+            StatusCode::FORBIDDEN
+        );
+    }
+}
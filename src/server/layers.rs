@@ -0,0 +1,113 @@
+//! Middleware around every route: compression, security headers, body size
+//! limits and timeouts. The API also gets a CORS policy, see [`cors`].
+
+use std::time::Duration;
+
+use axum::extract::DefaultBodyLimit;
+use axum::http::header::{
+    HeaderName, InvalidHeaderValue, AUTHORIZATION, CONTENT_SECURITY_POLICY, CONTENT_TYPE,
+    REFERRER_POLICY, STRICT_TRANSPORT_SECURITY, X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS,
+};
+use axum::http::{HeaderValue, Method};
+use axum::Router;
+use tower_http::compression::CompressionLayer;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tower_http::limit::RequestBodyLimitLayer;
+use tower_http::set_header::SetResponseHeaderLayer;
+use tower_http::timeout::TimeoutLayer;
+
+use crate::server::config::Config;
+
+/// How long browsers may cache a CORS preflight response.
+const CORS_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid {setting}: {source}")]
+pub struct LayerError {
+    setting: &'static str,
+    source: InvalidHeaderValue,
+}
+
+/// `value` as a header, or `None` if empty.
+fn header_value(setting: &'static str, value: &str) -> Result<Option<HeaderValue>, LayerError> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+    HeaderValue::from_str(value)
+        .map(Some)
+        .map_err(|source| LayerError { setting, source })
+}
+
+/// Wrap `router` in the configured middleware.
+pub fn apply(router: Router, config: &Config) -> Result<Router, LayerError> {
+    let mut router = router
+        .layer(DefaultBodyLimit::max(config.max_body_bytes))
+        .layer(RequestBodyLimitLayer::new(config.max_body_bytes))
+        .layer(TimeoutLayer::new(config.request_timeout()));
+
+    // Handlers can set their own, as the GraphQL playground does.
+    let headers: [(HeaderName, &'static str, &str); 3] = [
+        (
+            CONTENT_SECURITY_POLICY,
+            "CONTENT_SECURITY_POLICY",
+            &config.content_security_policy,
+        ),
+        (X_FRAME_OPTIONS, "FRAME_OPTIONS", &config.frame_options),
+        (REFERRER_POLICY, "REFERRER_POLICY", &config.referrer_policy),
+    ];
+    for (name, setting, value) in headers {
+        if let Some(value) = header_value(setting, value)? {
+            router = router.layer(SetResponseHeaderLayer::if_not_present(name, value));
+        }
+    }
+    router = router.layer(SetResponseHeaderLayer::if_not_present(
+        X_CONTENT_TYPE_OPTIONS,
+        HeaderValue::from_static("nosniff"),
+    ));
+
+    if config.hsts_max_age_secs > 0 {
+        let value = format!("max-age={}; includeSubDomains", config.hsts_max_age_secs);
+        router = router.layer(SetResponseHeaderLayer::overriding(
+            STRICT_TRANSPORT_SECURITY,
+            HeaderValue::from_str(&value).expect("max-age is a valid header value"),
+        ));
+    }
+
+    if config.compression {
+        router = router.layer(CompressionLayer::new().gzip(true).br(true));
+    }
+
+    Ok(router)
+}
+
+/// Which other sites may call the API from the browser: only those in
+/// `cors_origins`, with bearer tokens rather than cookies.
+pub fn cors(config: &Config) -> Result<CorsLayer, LayerError> {
+    let origins: Vec<&str> = config
+        .cors_origins
+        .split(',')
+        .map(str::trim)
+        .filter(|origin| !origin.is_empty())
+        .collect();
+
+    let allow_origin = if origins == ["*"] {
+        AllowOrigin::any()
+    } else {
+        let origins = origins
+            .into_iter()
+            .map(|origin| {
+                HeaderValue::from_str(origin).map_err(|source| LayerError {
+                    setting: "CORS_ORIGINS",
+                    source,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        AllowOrigin::list(origins)
+    };
+
+    Ok(CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
+        .allow_headers([AUTHORIZATION, CONTENT_TYPE])
+        .max_age(CORS_MAX_AGE))
+}
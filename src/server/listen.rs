@@ -0,0 +1,214 @@
+//! Where the server listens.
+//!
+//! In order of preference: a socket passed by systemd socket activation, the
+//! Unix socket `bind_unix`, or the TCP address `bind_address`.
+//!
+//! Connections are served with hyper directly, rather than `axum::serve`,
+//! which only takes a plain `TcpListener`.
+
+use std::io;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::Path;
+use std::time::Duration;
+
+use axum::extract::ConnectInfo;
+use axum::http::Request;
+use axum::Router;
+use hyper::body::Incoming;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto::Builder;
+use hyper_util::service::TowerToHyperService;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinSet;
+use tower::ServiceExt;
+use tracing::{debug, info, warn};
+
+use crate::server::config::Config;
+use crate::server::shutdown::Shutdown;
+#[cfg(unix)]
+use crate::server::systemd;
+use crate::server::tls::Tls;
+
+/// How long a client has to finish the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl Listener {
+    pub async fn open(config: &Config) -> io::Result<Self> {
+        #[cfg(unix)]
+        if let Some(fd) = systemd::listen_fds().into_iter().next() {
+            return Self::from_fd(fd);
+        }
+
+        #[cfg(unix)]
+        if let Some(path) = &config.bind_unix {
+            return Self::bind_unix(path);
+        }
+
+        info!("Listening on {}", config.bind_address);
+        Ok(Listener::Tcp(TcpListener::bind(config.bind_address).await?))
+    }
+
+    #[cfg(unix)]
+    fn from_fd(fd: std::os::fd::OwnedFd) -> io::Result<Self> {
+        // Only a TCP socket has an IP address.
+        let tcp = std::net::TcpListener::from(fd);
+        if let Ok(address) = tcp.local_addr() {
+            info!("Listening on {address} from systemd");
+            tcp.set_nonblocking(true)?;
+            return Ok(Listener::Tcp(TcpListener::from_std(tcp)?));
+        }
+
+        let unix = std::os::unix::net::UnixListener::from(std::os::fd::OwnedFd::from(tcp));
+        info!("Listening on {:?} from systemd", unix.local_addr()?);
+        unix.set_nonblocking(true)?;
+        Ok(Listener::Unix(tokio::net::UnixListener::from_std(unix)?))
+    }
+
+    #[cfg(unix)]
+    fn bind_unix(path: &Path) -> io::Result<Self> {
+        use std::os::unix::fs::FileTypeExt;
+
+        // Left behind if the server didn't shut down cleanly.
+        if std::fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+            std::fs::remove_file(path)?;
+        }
+
+        info!("Listening on {}", path.display());
+        Ok(Listener::Unix(tokio::net::UnixListener::bind(path)?))
+    }
+
+    /// The TCP port, if listening on one.
+    pub fn port(&self) -> Option<u16> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().ok().map(|address| address.port()),
+            #[cfg(unix)]
+            Listener::Unix(_) => None,
+        }
+    }
+}
+
+/// Serve `router` until `shutdown`, then wait for open connections. With
+/// `tls`, connections are HTTPS.
+pub async fn serve(
+    listener: Listener,
+    tls: Option<Tls>,
+    router: Router,
+    shutdown: Shutdown,
+) -> io::Result<()> {
+    let mut connections = JoinSet::new();
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = accept(&listener) => accepted,
+            _ = shutdown.wait() => break,
+            Some(_) = connections.join_next() => continue,
+        };
+
+        let connection = match accepted {
+            Ok(connection) => connection,
+            Err(err) => {
+                // Usually out of file descriptors, so give some a chance to
+                // close, as `axum::serve` does.
+                warn!("Failed to accept connection: {err}");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        let router = router.clone();
+        let shutdown = shutdown.clone();
+        let tls = tls.clone();
+        connections.spawn(async move {
+            match connection {
+                Connection::Tcp(stream, peer) => {
+                    serve_stream(stream, Some(peer), tls, router, shutdown).await
+                }
+                #[cfg(unix)]
+                Connection::Unix(stream) => serve_stream(stream, None, tls, router, shutdown).await,
+            }
+        });
+    }
+
+    while connections.join_next().await.is_some() {}
+    Ok(())
+}
+
+enum Connection {
+    Tcp(TcpStream, SocketAddr),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+}
+
+async fn accept(listener: &Listener) -> io::Result<Connection> {
+    match listener {
+        Listener::Tcp(listener) => {
+            let (stream, peer) = listener.accept().await?;
+            stream.set_nodelay(true)?;
+            Ok(Connection::Tcp(stream, peer))
+        }
+        #[cfg(unix)]
+        Listener::Unix(listener) => Ok(Connection::Unix(listener.accept().await?.0)),
+    }
+}
+
+async fn serve_stream<S>(
+    stream: S,
+    peer: Option<SocketAddr>,
+    tls: Option<Tls>,
+    router: Router,
+    shutdown: Shutdown,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let Some(tls) = tls else {
+        return serve_connection(stream, peer, router, shutdown).await;
+    };
+
+    match tokio::time::timeout(HANDSHAKE_TIMEOUT, tls.acceptor().accept(stream)).await {
+        Ok(Ok(stream)) => serve_connection(stream, peer, router, shutdown).await,
+        Ok(Err(err)) => debug!("TLS handshake failed: {err}"),
+        Err(_) => debug!("TLS handshake timed out"),
+    }
+}
+
+/// Serve HTTP/1 or HTTP/2 on one connection, allowing upgrades to
+/// websockets, and finishing the requests in flight on shutdown. Requests
+/// get the peer's address as `ConnectInfo`, as with `axum::serve`.
+async fn serve_connection<S>(
+    stream: S,
+    peer: Option<SocketAddr>,
+    router: Router,
+    shutdown: Shutdown,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = router.map_request(move |mut request: Request<Incoming>| {
+        if let Some(peer) = peer {
+            request.extensions_mut().insert(ConnectInfo(peer));
+        }
+        request
+    });
+    let builder = Builder::new(TokioExecutor::new());
+    let connection = builder
+        .serve_connection_with_upgrades(TokioIo::new(stream), TowerToHyperService::new(service));
+    tokio::pin!(connection);
+
+    let result = tokio::select! {
+        result = connection.as_mut() => result,
+        _ = shutdown.wait() => {
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    };
+    if let Err(err) = result {
+        debug!("Connection failed: {err}");
+    }
+}
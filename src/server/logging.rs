@@ -0,0 +1,143 @@
+//! Logging setup, request ids, and changing what is logged while running.
+//!
+//! Every HTTP request is logged within a `request` span carrying its id,
+//! which is taken from the `X-Request-Id` header if the client sent a
+//! sensible one, and is returned in the same header. Websocket connections
+//! stay within the span of the request that opened them. A `traceparent`
+//! header makes the span part of the caller's trace.
+
+use axum::extract::Request;
+use axum::http::HeaderValue;
+use axum::middleware::Next;
+use axum::response::Response;
+use axum::routing::get;
+use axum::{Extension, Router};
+use opentelemetry::trace::TraceError;
+use tracing::{info, info_span, Instrument};
+use tracing_subscriber::filter::ParseError;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Registry};
+
+use crate::model::Role;
+use crate::server::api::ApiError;
+use crate::server::authz::{self, CurrentRole};
+use crate::server::config::{Config, LogFormat};
+use crate::server::telemetry;
+
+pub const X_REQUEST_ID: &str = "x-request-id";
+
+/// Longest request id accepted from a client.
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+#[derive(Debug, thiserror::Error)]
+pub enum LoggingError {
+    #[error("Invalid log filter: {0}")]
+    Filter(#[from] ParseError),
+    #[error("Failed to set up trace export: {0}")]
+    Telemetry(#[from] TraceError),
+}
+
+/// Changes which logs are written.
+#[derive(Clone)]
+pub struct LogFilter {
+    handle: reload::Handle<EnvFilter, Registry>,
+}
+
+impl LogFilter {
+    pub fn current(&self) -> String {
+        self.handle
+            .with_current(|filter| filter.to_string())
+            .unwrap_or_default()
+    }
+
+    pub fn set(&self, directives: &str) -> Result<(), String> {
+        let filter = EnvFilter::try_new(directives).map_err(|err| err.to_string())?;
+        self.handle.reload(filter).map_err(|err| err.to_string())
+    }
+}
+
+/// Install the global subscriber, exporting spans if configured.
+pub fn init(config: &Config) -> Result<LogFilter, LoggingError> {
+    let (filter, handle) = reload::Layer::new(EnvFilter::try_new(&config.log_filter)?);
+    let telemetry =
+        telemetry::init(config)?.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer));
+    let registry = tracing_subscriber::registry().with(filter).with(telemetry);
+
+    match config.log_format {
+        LogFormat::Pretty => registry.with(fmt::layer()).init(),
+        LogFormat::Json => registry
+            .with(fmt::layer().json().with_current_span(true))
+            .init(),
+    }
+
+    Ok(LogFilter { handle })
+}
+
+fn new_request_id() -> String {
+    let mut bytes = [0u8; 8];
+    getrandom::getrandom(&mut bytes).expect("Failed to get random bytes");
+    hex::encode(bytes)
+}
+
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Middleware running each request in a span with its id.
+pub async fn request_id(request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(X_REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| is_valid_request_id(id))
+        .map(str::to_string)
+        .unwrap_or_else(new_request_id);
+
+    let span = info_span!(
+        "request",
+        id = %id,
+        method = %request.method(),
+        uri = %request.uri(),
+    );
+    telemetry::set_parent(&span, request.headers());
+
+    let mut response = next.run(request).instrument(span).await;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(X_REQUEST_ID, value);
+    }
+    response
+}
+
+pub fn router() -> Router {
+    Router::new().route("/log-filter", get(get_log_filter).put(set_log_filter))
+}
+
+/// The current filter. Requires the admin role.
+async fn get_log_filter(
+    Extension(log_filter): Extension<LogFilter>,
+    CurrentRole(role): CurrentRole,
+) -> Result<String, ApiError> {
+    authz::check(role, Role::Admin)?;
+    Ok(log_filter.current())
+}
+
+/// Replace the filter with the request body, in `RUST_LOG` syntax. Requires
+/// the admin role.
+async fn set_log_filter(
+    Extension(log_filter): Extension<LogFilter>,
+    CurrentRole(role): CurrentRole,
+    directives: String,
+) -> Result<String, ApiError> {
+    authz::check(role, Role::Admin)?;
+
+    let directives = directives.trim();
+    log_filter.set(directives).map_err(ApiError::BadRequest)?;
+
+    info!("Log filter changed to {directives}");
+    Ok(log_filter.current())
+}
@@ -0,0 +1,354 @@
+//! Prometheus metrics, served at `/metrics`.
+//!
+//! Server functions are counted from their HTTP requests, and from calls over
+//! the rpc websocket, labelled by the name used in `#[server(...)]`. Only
+//! admins, and the addresses in `metrics_addresses`, may read the metrics.
+
+use std::sync::Arc;
+use std::time::Instant;
+
+use axum::extract::{MatchedPath, Request};
+use axum::http::header::CONTENT_TYPE;
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Extension;
+use lazy_static::lazy_static;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use tracing::error;
+
+use crate::error_reporting::ReportLevel;
+use crate::model::{PenaltyEnum, Role};
+use crate::server::api::ApiError;
+use crate::server::authz::{self, CurrentRole};
+use crate::server::config::Config;
+use crate::server::database::DatabasePool;
+use crate::server::rate_limit::{ClientKey, IpRange, RateLimiter};
+
+lazy_static! {
+    static ref METRICS: Metrics = Metrics::new();
+}
+
+struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_request_duration: HistogramVec,
+    server_function_calls: IntCounterVec,
+    server_function_errors: IntCounterVec,
+    database_connections: IntGaugeVec,
+    websocket_connections: IntGaugeVec,
+    encounters_created: IntCounterVec,
+    client_errors: IntCounterVec,
+    client_errors_dropped: IntCounter,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests by route and status."),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        let http_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time taken to respond to HTTP requests.",
+            ),
+            &["method", "route"],
+        )
+        .unwrap();
+        let server_function_calls = IntCounterVec::new(
+            Opts::new("server_function_calls_total", "Server function calls."),
+            &["name", "transport"],
+        )
+        .unwrap();
+        let server_function_errors = IntCounterVec::new(
+            Opts::new(
+                "server_function_errors_total",
+                "Server function calls that returned an error.",
+            ),
+            &["name", "transport"],
+        )
+        .unwrap();
+        let database_connections = IntGaugeVec::new(
+            Opts::new(
+                "database_pool_connections",
+                "Connections in the database pool.",
+            ),
+            &["state"],
+        )
+        .unwrap();
+        let websocket_connections = IntGaugeVec::new(
+            Opts::new("websocket_connections", "Open websocket connections."),
+            &["endpoint"],
+        )
+        .unwrap();
+        let encounters_created = IntCounterVec::new(
+            Opts::new("encounters_created_total", "Encounters created."),
+            &["penalty"],
+        )
+        .unwrap();
+        let client_errors = IntCounterVec::new(
+            Opts::new("client_errors_total", "Errors reported by clients."),
+            &["level"],
+        )
+        .unwrap();
+        let client_errors_dropped = IntCounter::new(
+            "client_errors_dropped_total",
+            "Errors reported by clients that were not logged.",
+        )
+        .unwrap();
+
+        registry.register(Box::new(http_requests.clone())).unwrap();
+        registry
+            .register(Box::new(http_request_duration.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(server_function_calls.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(server_function_errors.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(database_connections.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(websocket_connections.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(encounters_created.clone()))
+            .unwrap();
+        registry.register(Box::new(client_errors.clone())).unwrap();
+        registry
+            .register(Box::new(client_errors_dropped.clone()))
+            .unwrap();
+
+        Metrics {
+            registry,
+            http_requests,
+            http_request_duration,
+            server_function_calls,
+            server_function_errors,
+            database_connections,
+            websocket_connections,
+            encounters_created,
+            client_errors,
+            client_errors_dropped,
+        }
+    }
+}
+
+/// The `#[server(...)]` name of the server function at `route`, for example
+/// `GetPenguinEncounters` for `/api/get_penguin_encounters1234...`.
+pub fn server_function_name(route: &str) -> Option<String> {
+    let name = route
+        .strip_prefix("/api/")?
+        .trim_end_matches(|c: char| c.is_ascii_digit());
+    if name.is_empty() || name.contains('/') {
+        return None;
+    }
+
+    let name = name
+        .split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            chars
+                .next()
+                .map(|first| first.to_ascii_uppercase().to_string() + chars.as_str())
+                .unwrap_or_default()
+        })
+        .collect();
+    Some(name)
+}
+
+/// Count a server function call, however it was made.
+pub fn server_function_called(name: &str, transport: &str, failed: bool) {
+    METRICS
+        .server_function_calls
+        .with_label_values(&[name, transport])
+        .inc();
+    if failed {
+        METRICS
+            .server_function_errors
+            .with_label_values(&[name, transport])
+            .inc();
+    }
+}
+
+pub fn encounter_created(penalty: PenaltyEnum) {
+    METRICS
+        .encounters_created
+        .with_label_values(&[&format!("{penalty:?}")])
+        .inc();
+}
+
+pub fn client_error_reported(level: ReportLevel) {
+    let level = match level {
+        ReportLevel::Warn => "warn",
+        ReportLevel::Error => "error",
+        ReportLevel::Panic => "panic",
+    };
+    METRICS.client_errors.with_label_values(&[level]).inc();
+}
+
+pub fn client_errors_dropped(count: u64) {
+    METRICS.client_errors_dropped.inc_by(count);
+}
+
+/// Counts an open websocket until dropped.
+pub struct WebsocketGuard {
+    endpoint: &'static str,
+}
+
+impl WebsocketGuard {
+    pub fn new(endpoint: &'static str) -> Self {
+        METRICS
+            .websocket_connections
+            .with_label_values(&[endpoint])
+            .inc();
+        WebsocketGuard { endpoint }
+    }
+}
+
+impl Drop for WebsocketGuard {
+    fn drop(&mut self) {
+        METRICS
+            .websocket_connections
+            .with_label_values(&[self.endpoint])
+            .dec();
+    }
+}
+
+/// Middleware recording every request.
+pub async fn track_requests(request: Request, next: Next) -> Response {
+    // Anything without a route, such as pages rendered by the fallback, is
+    // grouped together to keep the number of labels down.
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "other".to_string());
+    let method = request.method().to_string();
+    let started = Instant::now();
+
+    let response = next.run(request).await;
+
+    let status = response.status();
+    METRICS
+        .http_requests
+        .with_label_values(&[&method, &route, status.as_str()])
+        .inc();
+    METRICS
+        .http_request_duration
+        .with_label_values(&[&method, &route])
+        .observe(started.elapsed().as_secs_f64());
+
+    if let Some(name) = server_function_name(&route) {
+        let failed = status.is_client_error() || status.is_server_error();
+        server_function_called(&name, "http", failed);
+    }
+
+    response
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid metrics address {0}, expected an IP address or range")]
+pub struct InvalidAddress(String);
+
+/// The addresses that may read the metrics without logging in.
+#[derive(Clone)]
+pub struct MetricsAccess {
+    addresses: Arc<Vec<IpRange>>,
+}
+
+impl MetricsAccess {
+    pub fn from_config(config: &Config) -> Result<Self, InvalidAddress> {
+        let addresses = config
+            .metrics_addresses
+            .split(',')
+            .map(str::trim)
+            .filter(|address| !address.is_empty())
+            .map(|address| {
+                address
+                    .parse()
+                    .map_err(|_| InvalidAddress(address.to_string()))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(MetricsAccess {
+            addresses: Arc::new(addresses),
+        })
+    }
+
+    fn allows(&self, client: &ClientKey) -> bool {
+        match client {
+            ClientKey::Ip(address) => self.addresses.iter().any(|range| range.contains(*address)),
+            ClientKey::User(_) | ClientKey::Unknown => false,
+        }
+    }
+}
+
+/// Serve the metrics to admins, and to the addresses in `metrics_addresses`.
+pub async fn metrics_handler(
+    Extension(pool): Extension<DatabasePool>,
+    Extension(access): Extension<MetricsAccess>,
+    Extension(limiter): Extension<RateLimiter>,
+    CurrentRole(role): CurrentRole,
+    request: Request,
+) -> Result<Response, ApiError> {
+    let client = limiter.client_address(request.extensions(), request.headers());
+    if !access.allows(&client) {
+        authz::check(role, Role::Admin)?;
+    }
+
+    let state = pool.state().await;
+    let connections = &METRICS.database_connections;
+    connections
+        .with_label_values(&["in_use"])
+        .set(state.in_use as i64);
+    connections
+        .with_label_values(&["idle"])
+        .set(state.idle as i64);
+
+    let mut buffer = Vec::new();
+    if let Err(err) = TextEncoder::new().encode(&METRICS.registry.gather(), &mut buffer) {
+        error!("Failed to encode metrics: {err}");
+        return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+    }
+
+    Ok(([(CONTENT_TYPE, prometheus::TEXT_FORMAT)], buffer).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_server_functions_by_their_routes() {
+        assert_eq!(
+            server_function_name("/api/get_penguin_encounters5309").as_deref(),
+            Some("GetPenguinEncounters")
+        );
+        assert_eq!(
+            server_function_name("/api/begin_totp_enrolment").as_deref(),
+            Some("BeginTotpEnrolment")
+        );
+        assert_eq!(
+            server_function_name("/api/login42").as_deref(),
+            Some("Login")
+        );
+    }
+
+    #[test]
+    fn ignores_other_routes() {
+        assert_eq!(server_function_name("/api/v1/encounters"), None);
+        assert_eq!(server_function_name("/api/"), None);
+        assert_eq!(server_function_name("/api/123"), None);
+        assert_eq!(server_function_name("/echo"), None);
+        assert_eq!(server_function_name("other"), None);
+    }
+}
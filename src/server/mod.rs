@@ -2,11 +2,36 @@ use std::{any::Any, sync::Arc};
 
 use dioxus::prelude::*;
 
+pub mod api;
+pub mod api_tokens;
+pub mod auth;
+pub mod authz;
+pub mod cli;
+pub mod config;
 pub mod database;
+pub mod error_reporting;
+pub mod events;
+pub mod functions;
+pub mod graphql;
 mod handlers;
+pub mod health;
+pub mod layers;
+pub mod listen;
+pub mod logging;
+pub mod metrics;
+pub mod oidc;
+pub mod rate_limit;
+pub mod repository;
+mod rpc;
 pub mod schema;
+pub mod shutdown;
+pub mod systemd;
+pub mod telemetry;
+pub mod tls;
+pub mod totp;
 
 use handlers::{dioxus_handler, ws_echo_server};
+use rpc::ws_rpc_server;
 
 #[derive(Debug, Clone)]
 pub struct MyContext {
@@ -16,30 +41,151 @@ pub struct MyContext {
 // The entry point for the server
 #[cfg(feature = "server")]
 pub async fn init(app: fn() -> Element) {
-    use axum::{routing::get, Extension};
-    use handlers::health_check;
+    use clap::Parser;
 
-    tracing_subscriber::fmt::init();
+    let args = config::Args::parse();
+    let config = match config::Config::load(args.settings, args.config.as_deref()) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+    };
+    if args.print_config {
+        print!("{}", config.to_redacted_toml());
+        return;
+    }
+
+    let log_filter = match logging::init(&config) {
+        Ok(log_filter) => log_filter,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+    };
+
+    let result = match args.command.unwrap_or_default() {
+        cli::Command::Serve => serve(app, config, log_filter).await,
+        cli::Command::Migrate { action } => cli::migrate(&config, action).await,
+        cli::Command::CheckDb => cli::check_db(&config).await,
+        cli::Command::Seed { count } => cli::seed(&config, count).await,
+        cli::Command::Export { path } => cli::export(&config, path).await,
+        cli::Command::Import { path } => cli::import(&config, path).await,
+    };
+
+    telemetry::shutdown();
+
+    if let Err(err) = result {
+        eprintln!("{err}");
+        std::process::exit(1);
+    }
+}
+
+#[cfg(feature = "server")]
+async fn serve(
+    app: fn() -> Element,
+    config: config::Config,
+    log_filter: logging::LogFilter,
+) -> Result<(), cli::CliError> {
+    use axum::routing::{get, post};
+    use axum::Extension;
+
+    let store = repository::Store::from_config(&config)?;
+    let config = Arc::new(config);
+
+    // Accounts live alongside the encounters, except with the in-memory
+    // store where Postgres is optional, and only needed to log in.
+    let (database_url, local_accounts) = match store {
+        repository::Store::Postgres => (
+            Some(
+                config
+                    .database_url
+                    .as_ref()
+                    .ok_or("DATABASE_URL must be set")?,
+            ),
+            false,
+        ),
+        repository::Store::Memory => (config.database_url.as_ref(), false),
+        #[cfg(feature = "sqlite")]
+        repository::Store::Sqlite(_) => (None, true),
+    };
+
+    // Start even if the database is down, so the UI can be served. Until it
+    // comes back, anything that needs it fails with "Database unavailable".
+    // SQLite is opened before serving, so is ready from the start.
+    let database_state = database::DatabaseState::new(local_accounts);
+    let (database, supervisor, health) = match database_url {
+        Some(database_url) => {
+            let database = database::pool(database_url.expose());
+            let supervisor = tokio::spawn(database::supervise(
+                database.clone(),
+                database_state.clone(),
+                config.clone(),
+            ));
+            let health = health::Health::new(Some((database.clone(), database_state.clone())));
+            (database, Some(supervisor), health)
+        }
+        None if local_accounts => (database::pool(""), None, health::Health::new(None)),
+        None => {
+            if config.anonymous_role.is_none() && config.role_header.is_none() {
+                tracing::warn!(
+                    "No Postgres database and no ANONYMOUS_ROLE or ROLE_HEADER, \
+                     so nobody can see or record encounters"
+                );
+            } else {
+                tracing::warn!("No Postgres database, logging in is disabled");
+            }
+            (database::pool(""), None, health::Health::new(None))
+        }
+    };
+    let (encounters, accounts) = repository::open(store, &database, &database_state).await?;
+    let encounters_clone = encounters.clone();
+    let accounts_clone = accounts.clone();
 
-    let database = database::init().await;
+    tokio::spawn({
+        let accounts = accounts.clone();
+        let database_state = database_state.clone();
+        let config = config.clone();
+        async move {
+            database_state.wait_ready().await;
+            if let Err(err) = auth::bootstrap(&accounts, &config).await {
+                tracing::error!("Failed to create initial user: {err}");
+            }
+        }
+    });
+
+    let oidc = oidc::init(&config).unwrap_or_else(|err| {
+        tracing::error!("OpenID Connect login disabled: {err}");
+        oidc::OidcState::default()
+    });
     let database_clone = database.clone();
 
+    let events = events::EncounterEvents::default();
+    let events_clone = events.clone();
+    let graphql_schema = graphql::schema(encounters.clone(), events.clone());
+
     let context = MyContext {
-        title: "Dioxus Context".to_string(),
+        title: config.title.clone(),
     };
+    let context_clone = context.clone();
 
-    // Get the address the server should run on. If the CLI is running, the CLI proxies fullstack into the main address
-    // and we use the generated address the CLI gives us
-    let address = dioxus_cli_config::fullstack_address_or_localhost();
+    let shutdown_timeout = config.shutdown_timeout();
+    let shutdown = shutdown::Shutdown::default();
+    let rate_limiter = rate_limit::RateLimiter::from_config(&config)?;
+    let metrics_access = metrics::MetricsAccess::from_config(&config)?;
 
     let provider_1 = move || Box::new(context.clone()) as Box<dyn Any>;
-    let provider_2 = move || Box::new(42u32) as Box<dyn Any>;
-    let provider_3 = move || Box::new(database.clone()) as Box<dyn Any>;
+    let provider_2 = move || Box::new(functions::MAGIC_NUMBER) as Box<dyn Any>;
+    let provider_3 = move || Box::new(accounts.clone()) as Box<dyn Any>;
+    let provider_4 = move || Box::new(events.clone()) as Box<dyn Any>;
+    let provider_5 = move || Box::new(encounters.clone()) as Box<dyn Any>;
 
     let cfg = ServeConfigBuilder::default().context_providers(Arc::new(vec![
         Box::new(provider_1),
         Box::new(provider_2),
         Box::new(provider_3),
+        Box::new(provider_4),
+        Box::new(provider_5),
     ]));
 
     // Set up the axum router
@@ -47,13 +193,125 @@ pub async fn init(app: fn() -> Element) {
         // You can add a dioxus application to the router with the `serve_dioxus_application` method
         // This will add a fallback route to the router that will serve your component and server functions
         .serve_dioxus_application(cfg, app)
-        .route("/_health", get(health_check))
+        .nest("/_health", health::router())
         .route("/_dioxus", get(dioxus_handler))
         .route("/echo", get(ws_echo_server))
-        .layer(Extension(database_clone));
+        .route("/_rpc", get(ws_rpc_server))
+        .route("/auth/oidc/login", get(oidc::oidc_login))
+        .route("/auth/oidc/callback", get(oidc::oidc_callback))
+        .nest("/api/v1", api::router().layer(layers::cors(&config)?))
+        .route(
+            "/graphql",
+            get(graphql::graphiql).post(graphql::graphql_handler),
+        )
+        .route("/graphql/ws", get(graphql::graphql_ws_handler))
+        .route("/metrics", get(metrics::metrics_handler))
+        .route("/_telemetry/spans", post(telemetry::client_spans))
+        .route("/_telemetry/errors", post(error_reporting::client_errors))
+        .nest("/admin", logging::router())
+        .layer(axum::middleware::from_fn(rate_limit::limit_requests))
+        .layer(axum::middleware::from_fn(metrics::track_requests))
+        .layer(axum::middleware::from_fn(api_tokens::bearer_auth))
+        .layer(Extension(database_clone.clone()))
+        .layer(Extension(database_state.clone()))
+        .layer(Extension(health))
+        .layer(Extension(encounters_clone))
+        .layer(Extension(accounts_clone))
+        .layer(Extension(events_clone))
+        .layer(Extension(graphql_schema))
+        .layer(Extension(oidc))
+        .layer(Extension(config.clone()))
+        .layer(Extension(context_clone))
+        .layer(Extension(log_filter))
+        .layer(Extension(error_reporting::ErrorReporting::default()))
+        .layer(Extension(shutdown.clone()))
+        .layer(Extension(metrics_access))
+        .layer(Extension(rate_limiter));
+    let router =
+        layers::apply(router, &config)?.layer(axum::middleware::from_fn(logging::request_id));
 
     // Finally, we can launch the server
-    let router = router.into_make_service();
-    let listener = tokio::net::TcpListener::bind(address).await.unwrap();
-    axum::serve(listener, router).await.unwrap();
+    let tls = tls::Tls::from_config(&config)?;
+    let listener = listen::Listener::open(&config).await?;
+    tokio::spawn(shutdown.clone().on_signal());
+
+    if let Some(tls) = &tls {
+        tokio::spawn(tls.clone().reload_on_change());
+
+        if let Some(address) = config.http_redirect_address {
+            let https_port = listener.port().unwrap_or(config.bind_address.port());
+            let redirect = tls::redirect_http(address, https_port, shutdown.clone());
+            tokio::spawn(async move {
+                if let Err(err) = redirect.await {
+                    tracing::error!("Failed to redirect HTTP to HTTPS: {err}");
+                }
+            });
+        }
+    }
+
+    // With a database, ready once it has first been connected and migrated,
+    // so units ordered after this one can use it. If it never comes up,
+    // systemd gives up after `TimeoutStartSec`. Later outages are reported
+    // in the status instead.
+    if supervisor.is_some() {
+        let mut ready = database_state.subscribe();
+        tokio::spawn(async move {
+            let mut started = false;
+            loop {
+                let connected = *ready.borrow_and_update();
+                if connected && !started {
+                    systemd::notify("READY=1");
+                    started = true;
+                }
+                let status = match (connected, started) {
+                    (true, _) => "STATUS=Serving",
+                    (false, false) => "STATUS=Waiting for the database",
+                    (false, true) => "STATUS=Serving, database unavailable",
+                };
+                systemd::notify(status);
+                if ready.changed().await.is_err() {
+                    break;
+                }
+            }
+        });
+    } else {
+        systemd::notify("READY=1");
+        systemd::notify("STATUS=Serving without a database");
+    }
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            shutdown.wait().await;
+            systemd::notify("STOPPING=1");
+        }
+    });
+    systemd::spawn_watchdog({
+        let router = router.clone();
+        move |timeout| health::answers_live(router.clone(), timeout)
+    });
+
+    let server = async {
+        listen::serve(listener, tls, router, shutdown.clone()).await?;
+        shutdown.wait_for_tasks().await;
+        Ok::<_, std::io::Error>(())
+    };
+
+    // Once shutting down, give requests and websockets in flight until the
+    // deadline.
+    tokio::select! {
+        result = server => result?,
+        () = async {
+            shutdown.wait().await;
+            tokio::time::sleep(shutdown_timeout).await;
+        } => {
+            tracing::warn!("Requests still running after {shutdown_timeout:?}, stopping anyway");
+        }
+    }
+
+    if let Some(supervisor) = supervisor {
+        supervisor.abort();
+    }
+    database::close(&database_clone).await;
+    tracing::info!("Shut down");
+    Ok(())
 }
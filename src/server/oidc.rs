@@ -0,0 +1,717 @@
+//! OpenID Connect login using the authorization code flow with PKCE.
+//!
+//! Enabled by setting `OIDC_ISSUER_URL`, `OIDC_CLIENT_ID` and
+//! `OIDC_REDIRECT_URL`. `OIDC_CLIENT_SECRET` is optional for public clients.
+//! Users are created on their first login, and their role is taken from the
+//! `groups` claim on every login using `OIDC_ROLE_MAP`, for example
+//! `penguin-admins=admin,penguin-staff=recorder`.
+//!
+//! The login state is bound to the browser that started the login with a
+//! short lived cookie, so a callback URL can't be used to log someone else
+//! in. Two factor authentication is left to the identity provider: these
+//! accounts can't enrol in TOTP here.
+//!
+//! The provider is discovered in the background at startup, and again every
+//! so often until that works, so one that was down doesn't need a restart.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::extract::Query;
+use axum::http::header::SET_COOKIE;
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::Extension;
+use openidconnect::core::{
+    CoreAuthDisplay, CoreAuthPrompt, CoreAuthenticationFlow, CoreErrorResponseType,
+    CoreGenderClaim, CoreJsonWebKey, CoreJsonWebKeyType, CoreJsonWebKeyUse,
+    CoreJweContentEncryptionAlgorithm, CoreJwsSigningAlgorithm, CoreProviderMetadata,
+    CoreRevocableToken, CoreRevocationErrorResponse, CoreTokenIntrospectionResponse, CoreTokenType,
+};
+use openidconnect::reqwest::async_http_client;
+use openidconnect::{
+    AdditionalClaims, AuthorizationCode, Client, ClientId, ClientSecret, CsrfToken,
+    EmptyExtraTokenFields, IdTokenFields, IssuerUrl, Nonce, PkceCodeChallenge, PkceCodeVerifier,
+    RedirectUrl, Scope, StandardErrorResponse, StandardTokenResponse, TokenResponse,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::OnceCell;
+use tracing::{error, info};
+
+use crate::model::{Role, User};
+use crate::server::auth;
+use crate::server::config::Config;
+use crate::server::repository::{Accounts, RepositoryError};
+
+/// Holds a hash of the login state, so the callback can check that it was
+/// this browser that started the login.
+const STATE_COOKIE: &str = "oidc_state";
+
+/// How long after failing to discover the provider before trying again.
+const DISCOVERY_RETRY: Duration = Duration::from_secs(30);
+
+/// How long a user has to complete the login at the identity provider.
+fn login_state_lifetime() -> chrono::Duration {
+    chrono::Duration::minutes(10)
+}
+
+fn state_cookie(state: &str) -> HeaderValue {
+    let max_age = login_state_lifetime().num_seconds();
+    let cookie = format!(
+        "{STATE_COOKIE}={}; Path=/auth/oidc; HttpOnly; Secure; SameSite=Lax; Max-Age={max_age}",
+        auth::hash_token(state)
+    );
+    HeaderValue::from_str(&cookie).expect("State cookie is a valid header value")
+}
+
+fn clear_state_cookie() -> HeaderValue {
+    let cookie =
+        format!("{STATE_COOKIE}=; Path=/auth/oidc; HttpOnly; Secure; SameSite=Lax; Max-Age=0");
+    HeaderValue::from_str(&cookie).expect("State cookie is a valid header value")
+}
+
+/// Whether the browser's state cookie is for `state`.
+fn state_cookie_matches(headers: &HeaderMap, state: &str) -> bool {
+    auth::cookie(headers, STATE_COOKIE).is_some_and(|hash| hash == auth::hash_token(state))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct GroupsClaim {
+    #[serde(default)]
+    groups: Vec<String>,
+}
+
+impl AdditionalClaims for GroupsClaim {}
+
+type GroupsIdTokenFields = IdTokenFields<
+    GroupsClaim,
+    EmptyExtraTokenFields,
+    CoreGenderClaim,
+    CoreJweContentEncryptionAlgorithm,
+    CoreJwsSigningAlgorithm,
+    CoreJsonWebKeyType,
+>;
+
+type GroupsTokenResponse = StandardTokenResponse<GroupsIdTokenFields, CoreTokenType>;
+
+type GroupsClient = Client<
+    GroupsClaim,
+    CoreAuthDisplay,
+    CoreGenderClaim,
+    CoreJweContentEncryptionAlgorithm,
+    CoreJwsSigningAlgorithm,
+    CoreJsonWebKeyType,
+    CoreJsonWebKeyUse,
+    CoreJsonWebKey,
+    CoreAuthPrompt,
+    StandardErrorResponse<CoreErrorResponseType>,
+    GroupsTokenResponse,
+    CoreTokenType,
+    CoreTokenIntrospectionResponse,
+    CoreRevocableToken,
+    CoreRevocationErrorResponse,
+>;
+
+#[derive(thiserror::Error, Debug)]
+pub enum OidcError {
+    #[error("OpenID Connect login is not configured")]
+    NotConfigured,
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+    #[error("Identity provider returned an error: {0}")]
+    Provider(String),
+    #[error("Identity provider unavailable: {0}")]
+    Unavailable(String),
+    #[error("Login request is invalid or has expired")]
+    InvalidState,
+    #[error("Two factor authentication is enabled for this account, ask an admin to reset it")]
+    TotpEnabled,
+    #[error("Failed to verify login: {0}")]
+    Verification(String),
+    #[error("Database error: {0}")]
+    Database(String),
+}
+
+impl From<RepositoryError> for OidcError {
+    fn from(err: RepositoryError) -> Self {
+        OidcError::Database(err.to_string())
+    }
+}
+
+impl From<auth::AuthError> for OidcError {
+    fn from(err: auth::AuthError) -> Self {
+        OidcError::Database(err.to_string())
+    }
+}
+
+impl IntoResponse for OidcError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            OidcError::NotConfigured => StatusCode::NOT_FOUND,
+            OidcError::InvalidState
+            | OidcError::TotpEnabled
+            | OidcError::Verification(_)
+            | OidcError::Provider(_) => StatusCode::UNAUTHORIZED,
+            OidcError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            OidcError::Config(_) | OidcError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OidcConfig {
+    pub issuer_url: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub redirect_url: String,
+    pub scopes: Vec<String>,
+    pub role_map: Vec<(String, Role)>,
+}
+
+impl OidcConfig {
+    /// Get the OIDC settings from the configuration, if OIDC is enabled.
+    pub fn from_config(config: &Config) -> Result<Option<Self>, OidcError> {
+        let Some(issuer_url) = config.oidc_issuer_url.clone() else {
+            return Ok(None);
+        };
+
+        let client_id = config
+            .oidc_client_id
+            .clone()
+            .ok_or_else(|| OidcError::Config("OIDC_CLIENT_ID must be set".to_string()))?;
+        let redirect_url = config
+            .oidc_redirect_url
+            .clone()
+            .ok_or_else(|| OidcError::Config("OIDC_REDIRECT_URL must be set".to_string()))?;
+        let client_secret = config
+            .oidc_client_secret
+            .as_ref()
+            .map(|secret| secret.expose().to_string());
+
+        let scopes = config
+            .oidc_scopes
+            .split_whitespace()
+            .map(str::to_string)
+            .collect();
+
+        let role_map = config
+            .oidc_role_map
+            .split(',')
+            .filter(|entry| !entry.trim().is_empty())
+            .map(|entry| {
+                let (group, role) = entry.trim().split_once('=').ok_or_else(|| {
+                    OidcError::Config(format!("Invalid OIDC_ROLE_MAP entry: {entry}"))
+                })?;
+                let role = role.parse().map_err(OidcError::Config)?;
+                Ok((group.to_string(), role))
+            })
+            .collect::<Result<_, OidcError>>()?;
+
+        Ok(Some(OidcConfig {
+            issuer_url,
+            client_id,
+            client_secret,
+            redirect_url,
+            scopes,
+            role_map,
+        }))
+    }
+
+    /// The most privileged role granted by any of the groups.
+    ///
+    /// Users not in any mapped group are viewers.
+    pub fn role_for_groups(&self, groups: &[String]) -> Role {
+        self.role_map
+            .iter()
+            .filter(|(group, _)| groups.contains(group))
+            .map(|(_, role)| *role)
+            .max()
+            .unwrap_or(Role::Viewer)
+    }
+}
+
+pub struct Oidc {
+    client: GroupsClient,
+    config: OidcConfig,
+}
+
+/// OpenID Connect login, if configured, shared by all requests.
+#[derive(Clone, Default)]
+pub struct OidcState {
+    discovery: Option<Arc<Discovery>>,
+}
+
+struct Discovery {
+    config: OidcConfig,
+    oidc: OnceCell<Oidc>,
+    failed_at: Mutex<Option<Instant>>,
+}
+
+impl OidcState {
+    /// Whether login is configured, even if the provider hasn't been reached.
+    pub fn is_configured(&self) -> bool {
+        self.discovery.is_some()
+    }
+
+    /// The provider, discovering it now if that hasn't worked yet, unless
+    /// it failed less than [`DISCOVERY_RETRY`] ago.
+    async fn get(&self) -> Result<&Oidc, OidcError> {
+        let discovery = self.discovery.as_ref().ok_or(OidcError::NotConfigured)?;
+        if let Some(oidc) = discovery.oidc.get() {
+            return Ok(oidc);
+        }
+
+        let failed_at = *discovery.failed_at.lock().unwrap();
+        if let Some(retry_after) =
+            failed_at.and_then(|failed_at| DISCOVERY_RETRY.checked_sub(failed_at.elapsed()))
+        {
+            return Err(OidcError::Unavailable(format!(
+                "retrying in {}s",
+                retry_after.as_secs() + 1
+            )));
+        }
+
+        discovery
+            .oidc
+            .get_or_try_init(|| async {
+                let oidc = Oidc::discover(discovery.config.clone()).await?;
+                info!(
+                    "OpenID Connect login enabled for {}",
+                    oidc.config.issuer_url
+                );
+                Ok::<_, OidcError>(oidc)
+            })
+            .await
+            .inspect_err(|_| *discovery.failed_at.lock().unwrap() = Some(Instant::now()))
+    }
+}
+
+/// Who the identity provider says logged in.
+#[derive(Debug)]
+struct Identity {
+    subject: String,
+    preferred_username: Option<String>,
+    role: Role,
+}
+
+impl Oidc {
+    async fn discover(config: OidcConfig) -> Result<Self, OidcError> {
+        let issuer_url = IssuerUrl::new(config.issuer_url.clone())
+            .map_err(|err| OidcError::Config(err.to_string()))?;
+        let redirect_url = RedirectUrl::new(config.redirect_url.clone())
+            .map_err(|err| OidcError::Config(err.to_string()))?;
+
+        let provider_metadata = CoreProviderMetadata::discover_async(issuer_url, async_http_client)
+            .await
+            .map_err(|err| OidcError::Unavailable(err.to_string()))?;
+
+        let client = GroupsClient::from_provider_metadata(
+            provider_metadata,
+            ClientId::new(config.client_id.clone()),
+            config.client_secret.clone().map(ClientSecret::new),
+        )
+        .set_redirect_uri(redirect_url);
+
+        Ok(Oidc { client, config })
+    }
+
+    /// Exchange the authorization code and verify the ID token.
+    async fn identify(
+        &self,
+        code: String,
+        pkce_verifier: String,
+        nonce: String,
+    ) -> Result<Identity, OidcError> {
+        let token_response = self
+            .client
+            .exchange_code(AuthorizationCode::new(code))
+            .set_pkce_verifier(PkceCodeVerifier::new(pkce_verifier))
+            .request_async(async_http_client)
+            .await
+            .map_err(|err| OidcError::Provider(err.to_string()))?;
+
+        let id_token = token_response
+            .id_token()
+            .ok_or_else(|| OidcError::Verification("No ID token in response".to_string()))?;
+        let claims = id_token
+            .claims(&self.client.id_token_verifier(), &Nonce::new(nonce))
+            .map_err(|err| OidcError::Verification(err.to_string()))?;
+
+        Ok(Identity {
+            subject: claims.subject().as_str().to_string(),
+            preferred_username: claims
+                .preferred_username()
+                .map(|username| username.as_str().to_string()),
+            role: self
+                .config
+                .role_for_groups(&claims.additional_claims().groups),
+        })
+    }
+}
+
+/// Start discovering the provider in the background, if OIDC is configured.
+pub fn init(config: &Config) -> Result<OidcState, OidcError> {
+    let Some(config) = OidcConfig::from_config(config)? else {
+        return Ok(OidcState::default());
+    };
+
+    let state = OidcState {
+        discovery: Some(Arc::new(Discovery {
+            config,
+            oidc: OnceCell::new(),
+            failed_at: Mutex::new(None),
+        })),
+    };
+    tokio::spawn({
+        let state = state.clone();
+        async move {
+            loop {
+                match state.get().await {
+                    Ok(_) => break,
+                    Err(err @ OidcError::Config(_)) => {
+                        error!("OpenID Connect login disabled: {err}");
+                        break;
+                    }
+                    Err(err) => {
+                        error!("OpenID Connect login unavailable, retrying: {err}");
+                        tokio::time::sleep(DISCOVERY_RETRY).await;
+                    }
+                }
+            }
+        }
+    });
+    Ok(state)
+}
+
+/// Start a login by redirecting to the identity provider.
+#[axum::debug_handler]
+pub async fn oidc_login(
+    Extension(oidc): Extension<OidcState>,
+    Extension(accounts): Extension<Accounts>,
+) -> Result<Response, OidcError> {
+    let oidc = oidc.get().await?;
+
+    let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+
+    let mut request = oidc.client.authorize_url(
+        CoreAuthenticationFlow::AuthorizationCode,
+        CsrfToken::new_random,
+        Nonce::new_random,
+    );
+    for scope in &oidc.config.scopes {
+        request = request.add_scope(Scope::new(scope.clone()));
+    }
+    let (auth_url, csrf_state, nonce) = request.set_pkce_challenge(pkce_challenge).url();
+
+    accounts
+        .create_oidc_login_state(
+            csrf_state.secret(),
+            pkce_verifier.secret(),
+            nonce.secret(),
+            chrono::Utc::now() - login_state_lifetime(),
+        )
+        .await?;
+
+    Ok((
+        [(SET_COOKIE, state_cookie(csrf_state.secret()))],
+        Redirect::to(auth_url.as_str()),
+    )
+        .into_response())
+}
+
+#[derive(Deserialize)]
+pub struct CallbackParams {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+/// Complete a login when the identity provider redirects back.
+#[axum::debug_handler]
+pub async fn oidc_callback(
+    Extension(oidc): Extension<OidcState>,
+    Extension(accounts): Extension<Accounts>,
+    headers: HeaderMap,
+    Query(params): Query<CallbackParams>,
+) -> Result<Response, OidcError> {
+    let oidc = oidc.get().await?;
+
+    if let Some(err) = params.error {
+        let description = params.error_description.unwrap_or_default();
+        return Err(OidcError::Provider(format!("{err} {description}")));
+    }
+
+    let (Some(code), Some(state)) = (params.code, params.state) else {
+        return Err(OidcError::InvalidState);
+    };
+    if !state_cookie_matches(&headers, &state) {
+        return Err(OidcError::InvalidState);
+    }
+
+    let not_before = chrono::Utc::now() - login_state_lifetime();
+    let (pkce_verifier, nonce) = accounts
+        .take_oidc_login_state(&state, not_before)
+        .await?
+        .ok_or(OidcError::InvalidState)?;
+
+    let identity = oidc.identify(code, pkce_verifier, nonce).await?;
+    let user = provision_user(
+        &accounts,
+        &identity.subject,
+        identity.preferred_username,
+        identity.role,
+    )
+    .await?;
+
+    // Accounts could enrol before that was refused for them, and logging in
+    // here would skip the code.
+    if user.totp_enabled {
+        return Err(OidcError::TotpEnabled);
+    }
+
+    let token = auth::start_session(&accounts, &user).await?;
+
+    info!("User {} logged in with OpenID Connect", user.username);
+    Ok((
+        [
+            (SET_COOKIE, auth::session_cookie(&token)),
+            (SET_COOKIE, clear_state_cookie()),
+        ],
+        Redirect::to("/"),
+    )
+        .into_response())
+}
+
+/// Find the user for an OIDC subject, creating them on first login.
+async fn provision_user(
+    accounts: &Accounts,
+    subject: &str,
+    preferred_username: Option<String>,
+    role: Role,
+) -> Result<User, OidcError> {
+    if let Some(user) = accounts.get_user_by_oidc_subject(subject).await? {
+        if user.role == role {
+            return Ok(user);
+        }
+        return Ok(accounts.set_user_role(user.id, role).await?);
+    }
+
+    // Don't take over an existing local account with the same name.
+    let username = match preferred_username {
+        Some(username) if !accounts.username_exists(&username).await? => username,
+        _ => format!("oidc:{subject}"),
+    };
+
+    let user = accounts
+        .create_oidc_user(&username, subject, role)
+        .await
+        .inspect_err(|err| error!("Failed to create user {username}: {err}"))?;
+    info!("Created user {username} from OpenID Connect login");
+    Ok(user)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    use axum::extract::State;
+    use axum::http::header::COOKIE;
+    use axum::routing::{get, post};
+    use axum::{Form, Json, Router};
+    use openidconnect::core::{
+        CoreJsonWebKeySet, CoreResponseType, CoreRsaPrivateSigningKey, CoreSubjectIdentifierType,
+    };
+    use openidconnect::{
+        AccessToken, Audience, AuthUrl, EmptyAdditionalProviderMetadata, EndUserUsername, IdToken,
+        IdTokenClaims, JsonWebKeyId, JsonWebKeySetUrl, PrivateSigningKey, ResponseTypes,
+        StandardClaims, SubjectIdentifier, TokenUrl,
+    };
+
+    use super::*;
+
+    const CLIENT_ID: &str = "dioxus-fs-demo";
+    const CODE: &str = "authorization-code";
+
+    /// Just enough of an identity provider to log in: discovery, keys and a
+    /// token endpoint that signs an ID token for `CODE`.
+    struct MockIdp {
+        issuer: String,
+        key: CoreRsaPrivateSigningKey,
+        nonce: Mutex<String>,
+        groups: Vec<String>,
+    }
+
+    async fn discovery(State(idp): State<Arc<MockIdp>>) -> Json<CoreProviderMetadata> {
+        let metadata = CoreProviderMetadata::new(
+            IssuerUrl::new(idp.issuer.clone()).unwrap(),
+            AuthUrl::new(format!("{}/authorize", idp.issuer)).unwrap(),
+            JsonWebKeySetUrl::new(format!("{}/jwks", idp.issuer)).unwrap(),
+            vec![ResponseTypes::new(vec![CoreResponseType::Code])],
+            vec![CoreSubjectIdentifierType::Public],
+            vec![CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256],
+            EmptyAdditionalProviderMetadata {},
+        )
+        .set_token_endpoint(Some(
+            TokenUrl::new(format!("{}/token", idp.issuer)).unwrap(),
+        ));
+        Json(metadata)
+    }
+
+    async fn jwks(State(idp): State<Arc<MockIdp>>) -> Json<CoreJsonWebKeySet> {
+        Json(CoreJsonWebKeySet::new(vec![idp.key.as_verification_key()]))
+    }
+
+    async fn token(
+        State(idp): State<Arc<MockIdp>>,
+        Form(form): Form<HashMap<String, String>>,
+    ) -> Result<Json<GroupsTokenResponse>, StatusCode> {
+        if form.get("code").map(String::as_str) != Some(CODE) || !form.contains_key("code_verifier")
+        {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+
+        let now = chrono::Utc::now();
+        let claims = IdTokenClaims::new(
+            IssuerUrl::new(idp.issuer.clone()).unwrap(),
+            vec![Audience::new(CLIENT_ID.to_string())],
+            now + chrono::Duration::minutes(5),
+            now,
+            StandardClaims::new(SubjectIdentifier::new("tux-subject".to_string()))
+                .set_preferred_username(Some(EndUserUsername::new("tux".to_string()))),
+            GroupsClaim {
+                groups: idp.groups.clone(),
+            },
+        )
+        .set_nonce(Some(Nonce::new(idp.nonce.lock().unwrap().clone())));
+        let id_token = IdToken::new(
+            claims,
+            &idp.key,
+            CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256,
+            None,
+            None,
+        )
+        .unwrap();
+
+        Ok(Json(GroupsTokenResponse::new(
+            AccessToken::new("access-token".to_string()),
+            CoreTokenType::Bearer,
+            GroupsIdTokenFields::new(Some(id_token), EmptyExtraTokenFields {}),
+        )))
+    }
+
+    async fn mock_idp(groups: &[&str]) -> Arc<MockIdp> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let key = CoreRsaPrivateSigningKey::from_pem(
+            include_str!("testdata/oidc_signing_key.pem"),
+            Some(JsonWebKeyId::new("test".to_string())),
+        )
+        .unwrap();
+
+        let idp = Arc::new(MockIdp {
+            issuer,
+            key,
+            nonce: Mutex::new(String::new()),
+            groups: groups.iter().map(|group| group.to_string()).collect(),
+        });
+        let router = Router::new()
+            .route("/.well-known/openid-configuration", get(discovery))
+            .route("/jwks", get(jwks))
+            .route("/token", post(token))
+            .with_state(idp.clone());
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        idp
+    }
+
+    async fn client_for(idp: &MockIdp) -> Oidc {
+        Oidc::discover(OidcConfig {
+            issuer_url: idp.issuer.clone(),
+            client_id: CLIENT_ID.to_string(),
+            client_secret: None,
+            redirect_url: "http://localhost:8080/auth/oidc/callback".to_string(),
+            scopes: vec!["openid".to_string()],
+            role_map: vec![
+                ("penguin-admins".to_string(), Role::Admin),
+                ("penguin-staff".to_string(), Role::Recorder),
+            ],
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn identifies_the_user_from_the_id_token() {
+        let idp = mock_idp(&["penguin-staff"]).await;
+        let oidc = client_for(&idp).await;
+        *idp.nonce.lock().unwrap() = "nonce".to_string();
+
+        let identity = oidc
+            .identify(
+                CODE.to_string(),
+                "verifier".to_string(),
+                "nonce".to_string(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(identity.subject, "tux-subject");
+        assert_eq!(identity.preferred_username.as_deref(), Some("tux"));
+        assert_eq!(identity.role, Role::Recorder);
+    }
+
+    #[tokio::test]
+    async fn rejects_an_id_token_for_another_login() {
+        let idp = mock_idp(&[]).await;
+        let oidc = client_for(&idp).await;
+        *idp.nonce.lock().unwrap() = "someone else's nonce".to_string();
+
+        let result = oidc
+            .identify(
+                CODE.to_string(),
+                "verifier".to_string(),
+                "nonce".to_string(),
+            )
+            .await;
+
+        assert!(matches!(result, Err(OidcError::Verification(_))));
+    }
+
+    #[tokio::test]
+    async fn rejects_an_unknown_code() {
+        let idp = mock_idp(&[]).await;
+        let oidc = client_for(&idp).await;
+
+        let result = oidc
+            .identify(
+                "stolen".to_string(),
+                "verifier".to_string(),
+                "nonce".to_string(),
+            )
+            .await;
+
+        assert!(matches!(result, Err(OidcError::Provider(_))));
+    }
+
+    fn cookie_headers(set_cookie: &HeaderValue) -> HeaderMap {
+        let cookie = set_cookie.to_str().unwrap().split(';').next().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, HeaderValue::from_str(cookie).unwrap());
+        headers
+    }
+
+    #[test]
+    fn state_cookie_binds_the_login_to_the_browser() {
+        let headers = cookie_headers(&state_cookie("state"));
+
+        assert!(state_cookie_matches(&headers, "state"));
+        assert!(!state_cookie_matches(&headers, "other state"));
+        assert!(!state_cookie_matches(&HeaderMap::new(), "state"));
+        assert!(!state_cookie_matches(
+            &cookie_headers(&clear_state_cookie()),
+            "state"
+        ));
+    }
+}
@@ -0,0 +1,597 @@
+//! Rate limiting.
+//!
+//! Requests are limited per client by [`limit_requests`], with a token bucket
+//! for each rule in `rate_limits` that a client has used. A client is the
+//! user, when logged in or using an API token, or else the IP address, taken
+//! from `X-Forwarded-For` when the request came through a trusted proxy.
+//! Clients can also only have so many websockets open at once, see
+//! [`WebsocketPermit`].
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use axum::extract::{ConnectInfo, FromRequestParts, MatchedPath, Request};
+use axum::http::header::RETRY_AFTER;
+use axum::http::request::Parts;
+use axum::http::{Extensions, HeaderMap, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use tracing::{debug, warn};
+
+use crate::server::api_tokens::TokenAuth;
+use crate::server::auth;
+use crate::server::config::Config;
+use crate::server::metrics;
+use crate::server::repository::Accounts;
+
+/// How often buckets that have refilled, and sessions, are forgotten.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long a session cookie is trusted to belong to the same user.
+const SESSION_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// How many sessions are remembered at once.
+const MAX_CACHED_SESSIONS: usize = 10_000;
+
+/// How often each address may have a session cookie looked up in the
+/// database, so made up cookies can't be used to flood it.
+const SESSION_LOOKUPS: Limit = Limit {
+    requests: 30,
+    seconds: 60.0,
+};
+
+/// Allows `capacity` requests at once, refilling at `per_second`.
+pub struct TokenBucket {
+    capacity: f64,
+    per_second: f64,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(capacity: u32, per_second: f64) -> Self {
+        TokenBucket {
+            capacity: capacity.into(),
+            per_second,
+            state: Mutex::new(BucketState {
+                tokens: capacity.into(),
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take `count` tokens, or if there aren't enough, return how long until
+    /// there will be.
+    pub fn take(&self, count: u32) -> Result<(), Duration> {
+        let count = f64::from(count);
+        let mut state = self.refilled();
+        self.wait_for(&state, count)?;
+        state.tokens -= count;
+        Ok(())
+    }
+
+    /// Like [`take`](Self::take), but leaves the tokens in the bucket.
+    pub fn check(&self, count: u32) -> Result<(), Duration> {
+        self.wait_for(&self.refilled(), f64::from(count))
+    }
+
+    fn refilled(&self) -> MutexGuard<'_, BucketState> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.updated).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * self.per_second).min(self.capacity);
+        state.updated = now;
+        state
+    }
+
+    fn wait_for(&self, state: &BucketState, count: f64) -> Result<(), Duration> {
+        if state.tokens >= count {
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (count - state.tokens) / self.per_second,
+            ))
+        }
+    }
+
+    /// Whether the bucket has refilled, so forgetting it changes nothing.
+    pub fn is_full(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        let elapsed = state.updated.elapsed().as_secs_f64();
+        state.tokens + elapsed * self.per_second >= self.capacity
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RateLimitError {
+    #[error("Invalid rate limit {0}, expected name=requests/seconds")]
+    Rule(String),
+    #[error("Invalid trusted proxy {0}, expected an IP address or range")]
+    Proxy(String),
+}
+
+/// `requests` in any `seconds`.
+#[derive(Debug, Clone, Copy)]
+struct Limit {
+    requests: u32,
+    seconds: f64,
+}
+
+impl Limit {
+    fn bucket(&self) -> TokenBucket {
+        TokenBucket::new(self.requests, f64::from(self.requests) / self.seconds)
+    }
+}
+
+/// Attempts that are limited by how often they fail, rather than how often
+/// they are made, so clients making them correctly aren't slowed down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Attempt {
+    /// Using an API token, counted against the client's address.
+    ApiToken,
+    /// Logging in with a password, counted against the username.
+    Password,
+    /// A TOTP or recovery code, counted against the user's ID.
+    SecondFactor,
+}
+
+impl Attempt {
+    fn limit(self) -> Limit {
+        match self {
+            Attempt::ApiToken => Limit {
+                requests: 10,
+                seconds: 60.0,
+            },
+            Attempt::Password => Limit {
+                requests: 10,
+                seconds: 300.0,
+            },
+            Attempt::SecondFactor => Limit {
+                requests: 5,
+                seconds: 300.0,
+            },
+        }
+    }
+}
+
+/// Parse `name=requests/seconds` pairs, separated by commas.
+fn parse_rules(rules: &str) -> Result<HashMap<String, Limit>, RateLimitError> {
+    rules
+        .split(',')
+        .map(str::trim)
+        .filter(|rule| !rule.is_empty())
+        .map(|rule| {
+            let invalid = || RateLimitError::Rule(rule.to_string());
+            let (name, limit) = rule.split_once('=').ok_or_else(invalid)?;
+            let (requests, seconds) = limit.split_once('/').ok_or_else(invalid)?;
+            let limit = Limit {
+                requests: requests.trim().parse().map_err(|_| invalid())?,
+                seconds: seconds.trim().parse().map_err(|_| invalid())?,
+            };
+            if limit.requests == 0 || !limit.seconds.is_finite() || limit.seconds <= 0.0 {
+                return Err(invalid());
+            }
+            Ok((name.trim().to_string(), limit))
+        })
+        .collect()
+}
+
+/// An address, or a range of them such as `10.0.0.0/8`.
+#[derive(Debug, Clone, Copy)]
+pub struct IpRange {
+    address: IpAddr,
+    prefix: u32,
+}
+
+impl FromStr for IpRange {
+    type Err = RateLimitError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || RateLimitError::Proxy(s.to_string());
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+        let address: IpAddr = address.parse().map_err(|_| invalid())?;
+        let bits = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+            None => bits,
+        };
+        if prefix > bits {
+            return Err(invalid());
+        }
+        Ok(IpRange { address, prefix })
+    }
+}
+
+impl IpRange {
+    pub fn contains(&self, address: IpAddr) -> bool {
+        let address = match address {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(address),
+            IpAddr::V4(_) => address,
+        };
+        match (self.address, address) {
+            (IpAddr::V4(range), IpAddr::V4(address)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(range) & mask == u32::from(address) & mask
+            }
+            (IpAddr::V6(range), IpAddr::V6(address)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(range) & mask == u128::from(address) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Who a request is counted against.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ClientKey {
+    User(i32),
+    Ip(IpAddr),
+    /// No address, as on a Unix socket without a proxy header.
+    Unknown,
+}
+
+impl std::fmt::Display for ClientKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientKey::User(id) => write!(f, "user {id}"),
+            ClientKey::Ip(address) => write!(f, "{address}"),
+            ClientKey::Unknown => f.write_str("unknown client"),
+        }
+    }
+}
+
+/// The client's address: the peer, unless that is a trusted proxy, in which
+/// case the last address in `X-Forwarded-For` not added by a trusted proxy.
+/// Requests on a Unix socket have no peer address, so are always from a
+/// proxy on the same machine.
+fn client_ip(peer: Option<IpAddr>, headers: &HeaderMap, proxies: &[IpRange]) -> Option<IpAddr> {
+    let trusted = |address: IpAddr| proxies.iter().any(|range| range.contains(address));
+    if peer.is_some_and(|peer| !trusted(peer)) {
+        return peer;
+    }
+
+    let mut client = peer;
+    let forwarded = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect::<Vec<_>>();
+    for address in forwarded.into_iter().rev() {
+        let Ok(address) = address.parse::<IpAddr>() else {
+            break;
+        };
+        client = Some(address);
+        if !trusted(address) {
+            break;
+        }
+    }
+    client
+}
+
+fn client_address(extensions: &Extensions, headers: &HeaderMap, proxies: &[IpRange]) -> ClientKey {
+    let peer = extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(address)| address.ip());
+    client_ip(peer, headers, proxies)
+        .map(ClientKey::Ip)
+        .unwrap_or(ClientKey::Unknown)
+}
+
+struct Limiter {
+    rules: HashMap<String, Limit>,
+    trusted_proxies: Vec<IpRange>,
+    max_websockets: u32,
+    state: Mutex<LimiterState>,
+}
+
+struct LimiterState {
+    buckets: HashMap<(String, ClientKey), TokenBucket>,
+    /// Users of session tokens, by hash, and when they were looked up.
+    sessions: HashMap<String, (Option<i32>, Instant)>,
+    /// How many more sessions each address may have looked up.
+    session_lookups: HashMap<ClientKey, TokenBucket>,
+    /// How many more times each attempt may fail, by who made it.
+    failures: HashMap<(Attempt, String), TokenBucket>,
+    websockets: HashMap<ClientKey, u32>,
+    pruned: Instant,
+}
+
+/// Rate limits for every client, shared by all requests.
+#[derive(Clone)]
+pub struct RateLimiter {
+    inner: Arc<Limiter>,
+}
+
+impl RateLimiter {
+    pub fn from_config(config: &Config) -> Result<Self, RateLimitError> {
+        let trusted_proxies = config
+            .trusted_proxies
+            .split(',')
+            .map(str::trim)
+            .filter(|proxy| !proxy.is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()?;
+
+        Ok(RateLimiter {
+            inner: Arc::new(Limiter {
+                rules: parse_rules(&config.rate_limits)?,
+                trusted_proxies,
+                max_websockets: config.max_websockets_per_client,
+                state: Mutex::new(LimiterState {
+                    buckets: HashMap::new(),
+                    sessions: HashMap::new(),
+                    session_lookups: HashMap::new(),
+                    failures: HashMap::new(),
+                    websockets: HashMap::new(),
+                    pruned: Instant::now(),
+                }),
+            }),
+        })
+    }
+
+    /// Count a call to the server function `name`, or a request to `route`,
+    /// against the most specific rule: the server function's, the route's,
+    /// or `*`. Fails with how long until the client may try again.
+    pub fn check(
+        &self,
+        name: Option<&str>,
+        route: Option<&str>,
+        client: &ClientKey,
+    ) -> Result<(), Duration> {
+        let rules = &self.inner.rules;
+        let Some((rule, limit)) = [name, route, Some("*")]
+            .into_iter()
+            .flatten()
+            .find_map(|key| rules.get_key_value(key))
+        else {
+            return Ok(());
+        };
+
+        let mut state = self.inner.state.lock().unwrap();
+        state.prune();
+        state
+            .buckets
+            .entry((rule.clone(), client.clone()))
+            .or_insert_with(|| limit.bucket())
+            .take(1)
+            .inspect_err(|_| debug!("Rate limited {client} on {rule}"))
+    }
+
+    /// The client's address, ignoring who they are logged in as.
+    pub fn client_address(&self, extensions: &Extensions, headers: &HeaderMap) -> ClientKey {
+        client_address(extensions, headers, &self.inner.trusted_proxies)
+    }
+
+    /// Fails with how long until `key` may make `attempt` again, if it has
+    /// failed too often.
+    pub fn check_attempt(&self, attempt: Attempt, key: &str) -> Result<(), Duration> {
+        let mut state = self.inner.state.lock().unwrap();
+        state.prune();
+        match state.failures.get(&(attempt, key.to_string())) {
+            Some(bucket) => bucket
+                .check(1)
+                .inspect_err(|_| debug!("Too many failed {attempt:?} attempts by {key}")),
+            None => Ok(()),
+        }
+    }
+
+    /// Count a failed `attempt` by `key`.
+    pub fn attempt_failed(&self, attempt: Attempt, key: &str) {
+        let mut state = self.inner.state.lock().unwrap();
+        let _ = state
+            .failures
+            .entry((attempt, key.to_string()))
+            .or_insert_with(|| attempt.limit().bucket())
+            .take(1);
+    }
+
+    /// The user with the session `token_hash`, if still cached.
+    fn cached_session(&self, token_hash: &str) -> Option<Option<i32>> {
+        let state = self.inner.state.lock().unwrap();
+        let (user, looked_up) = state.sessions.get(token_hash)?;
+        (looked_up.elapsed() < SESSION_CACHE_TTL).then_some(*user)
+    }
+
+    /// Whether `client` may have a session looked up now.
+    fn allow_session_lookup(&self, client: &ClientKey) -> bool {
+        let mut state = self.inner.state.lock().unwrap();
+        state.prune();
+        state
+            .session_lookups
+            .entry(client.clone())
+            .or_insert_with(|| SESSION_LOOKUPS.bucket())
+            .take(1)
+            .is_ok()
+    }
+
+    fn cache_session(&self, token_hash: String, user: Option<i32>) {
+        let mut state = self.inner.state.lock().unwrap();
+        if state.sessions.len() >= MAX_CACHED_SESSIONS {
+            state.forget_expired_sessions();
+            if state.sessions.len() >= MAX_CACHED_SESSIONS {
+                return;
+            }
+        }
+        state.sessions.insert(token_hash, (user, Instant::now()));
+    }
+
+    fn open_websocket(&self, client: ClientKey) -> Option<WebsocketPermit> {
+        let max = self.inner.max_websockets;
+        let mut state = self.inner.state.lock().unwrap();
+        let open = state.websockets.entry(client.clone()).or_default();
+        if max > 0 && *open >= max {
+            debug!("Too many websockets open for {client}");
+            return None;
+        }
+        *open += 1;
+        Some(WebsocketPermit {
+            limiter: self.clone(),
+            client,
+        })
+    }
+}
+
+impl LimiterState {
+    fn prune(&mut self) {
+        if self.pruned.elapsed() < PRUNE_INTERVAL {
+            return;
+        }
+        self.pruned = Instant::now();
+        self.buckets.retain(|_, bucket| !bucket.is_full());
+        self.session_lookups.retain(|_, bucket| !bucket.is_full());
+        self.failures.retain(|_, bucket| !bucket.is_full());
+        self.forget_expired_sessions();
+    }
+
+    fn forget_expired_sessions(&mut self) {
+        self.sessions
+            .retain(|_, (_, looked_up)| looked_up.elapsed() < SESSION_CACHE_TTL);
+    }
+}
+
+#[axum::async_trait]
+impl<S> FromRequestParts<S> for ClientKey
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(token_auth) = parts.extensions.get::<TokenAuth>() {
+            return Ok(ClientKey::User(token_auth.user.id));
+        }
+
+        let limiter = parts.extensions.get::<RateLimiter>().cloned();
+
+        let proxies = limiter
+            .as_ref()
+            .map(|limiter| limiter.inner.trusted_proxies.as_slice())
+            .unwrap_or_default();
+        let address = client_address(&parts.extensions, &parts.headers, proxies);
+
+        // Sessions are remembered for a minute, and only so many are looked
+        // up for each address, so made up cookies fall back to the address
+        // rather than reaching the database.
+        let token = auth::session_token(&parts.headers).filter(|token| auth::is_token(token));
+        if let (Some(limiter), Some(token)) = (&limiter, token) {
+            let token_hash = auth::hash_token(&token);
+            let user = match limiter.cached_session(&token_hash) {
+                Some(user) => user,
+                None if limiter.allow_session_lookup(&address) => {
+                    let user = match parts.extensions.get::<Accounts>() {
+                        Some(accounts) => match auth::session_user(accounts, &token).await {
+                            Ok(user) => user.map(|user| user.id),
+                            Err(err) => {
+                                warn!("Failed to look up session for rate limiting: {err}");
+                                None
+                            }
+                        },
+                        None => None,
+                    };
+                    limiter.cache_session(token_hash, user);
+                    user
+                }
+                None => None,
+            };
+            if let Some(id) = user {
+                return Ok(ClientKey::User(id));
+            }
+        }
+
+        Ok(address)
+    }
+}
+
+pub fn too_many_requests(retry_after: Option<Duration>) -> Response {
+    match retry_after {
+        Some(retry_after) => {
+            // Rounded up, so trying again then succeeds.
+            let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(RETRY_AFTER, seconds.to_string())],
+                "Too many requests",
+            )
+                .into_response()
+        }
+        None => (StatusCode::TOO_MANY_REQUESTS, "Too many requests").into_response(),
+    }
+}
+
+/// Middleware applying `rate_limits` to every request.
+pub async fn limit_requests(request: Request, next: Next) -> Response {
+    let Some(limiter) = request.extensions().get::<RateLimiter>().cloned() else {
+        return next.run(request).await;
+    };
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string());
+    let name = route.as_deref().and_then(metrics::server_function_name);
+
+    let (mut parts, body) = request.into_parts();
+    let client = ClientKey::from_request_parts(&mut parts, &())
+        .await
+        .unwrap_or_else(|never| match never {});
+
+    if let Err(retry_after) = limiter.check(name.as_deref(), route.as_deref(), &client) {
+        return too_many_requests(Some(retry_after));
+    }
+    next.run(Request::from_parts(parts, body)).await
+}
+
+/// Counts an open websocket against the client's limit until dropped.
+///
+/// Taken as an extractor by websocket handlers, which reject the upgrade
+/// with 429 when the client has too many open, and moved into the socket's
+/// task.
+pub struct WebsocketPermit {
+    limiter: RateLimiter,
+    client: ClientKey,
+}
+
+impl Drop for WebsocketPermit {
+    fn drop(&mut self) {
+        let mut state = self.limiter.inner.state.lock().unwrap();
+        if let Some(open) = state.websockets.get_mut(&self.client) {
+            *open = open.saturating_sub(1);
+            if *open == 0 {
+                state.websockets.remove(&self.client);
+            }
+        }
+    }
+}
+
+#[axum::async_trait]
+impl<S> FromRequestParts<S> for WebsocketPermit
+where
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Some(limiter) = parts.extensions.get::<RateLimiter>().cloned() else {
+            return Err(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+        };
+        let client = ClientKey::from_request_parts(parts, state)
+            .await
+            .unwrap_or_else(|never| match never {});
+        limiter
+            .open_websocket(client)
+            .ok_or_else(|| too_many_requests(None))
+    }
+}
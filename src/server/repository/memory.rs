@@ -0,0 +1,283 @@
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+use async_trait::async_trait;
+
+use super::{EncounterFilter, EncounterRepository, RepositoryError};
+use crate::model::{CreatePenguinEncounter, PenguinEncounter};
+
+/// Encounters kept in memory, lost when the server stops.
+#[derive(Default)]
+pub struct InMemoryEncounterRepository {
+    state: RwLock<State>,
+}
+
+#[derive(Default)]
+struct State {
+    next_id: i32,
+    encounters: BTreeMap<i32, PenguinEncounter>,
+}
+
+impl InMemoryEncounterRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+fn to_encounter(id: i32, encounter: &CreatePenguinEncounter<'_>) -> PenguinEncounter {
+    PenguinEncounter {
+        id,
+        name: encounter.name.to_string(),
+        location: encounter.location.to_string(),
+        penalty: encounter.penalty,
+        date_time: encounter.date_time,
+    }
+}
+
+#[async_trait]
+impl EncounterRepository for InMemoryEncounterRepository {
+    async fn list(&self) -> Result<Vec<PenguinEncounter>, RepositoryError> {
+        let state = self.state.read().unwrap();
+        Ok(state.encounters.values().cloned().collect())
+    }
+
+    async fn search(
+        &self,
+        filter: &EncounterFilter,
+        limit: i64,
+        offset: i64,
+    ) -> Result<(Vec<PenguinEncounter>, i64), RepositoryError> {
+        let state = self.state.read().unwrap();
+
+        let mut encounters: Vec<_> = state
+            .encounters
+            .values()
+            .filter(|encounter| filter.matches(encounter))
+            .cloned()
+            .collect();
+        encounters.sort_by_key(|encounter| Reverse((encounter.date_time, encounter.id)));
+
+        let total = encounters.len() as i64;
+        let page = encounters
+            .into_iter()
+            .skip(offset.max(0) as usize)
+            .take(limit.max(0) as usize)
+            .collect();
+
+        Ok((page, total))
+    }
+
+    async fn names(&self) -> Result<Vec<String>, RepositoryError> {
+        let state = self.state.read().unwrap();
+
+        let mut names: Vec<_> = state
+            .encounters
+            .values()
+            .map(|encounter| encounter.name.clone())
+            .collect();
+        names.sort();
+        names.dedup();
+
+        Ok(names)
+    }
+
+    async fn get(&self, id: i32) -> Result<Option<PenguinEncounter>, RepositoryError> {
+        let state = self.state.read().unwrap();
+        Ok(state.encounters.get(&id).cloned())
+    }
+
+    async fn create(
+        &self,
+        encounter: &CreatePenguinEncounter<'_>,
+    ) -> Result<PenguinEncounter, RepositoryError> {
+        let mut state = self.state.write().unwrap();
+
+        state.next_id += 1;
+        let encounter = to_encounter(state.next_id, encounter);
+        state.encounters.insert(encounter.id, encounter.clone());
+
+        Ok(encounter)
+    }
+
+    async fn update(
+        &self,
+        id: i32,
+        encounter: &CreatePenguinEncounter<'_>,
+    ) -> Result<Option<PenguinEncounter>, RepositoryError> {
+        let mut state = self.state.write().unwrap();
+
+        let Some(existing) = state.encounters.get_mut(&id) else {
+            return Ok(None);
+        };
+        *existing = to_encounter(id, encounter);
+
+        Ok(Some(existing.clone()))
+    }
+
+    async fn delete(&self, id: i32) -> Result<bool, RepositoryError> {
+        let mut state = self.state.write().unwrap();
+        Ok(state.encounters.remove(&id).is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, TimeZone, Utc};
+
+    use super::*;
+    use crate::model::PenaltyEnum;
+
+    fn day(day: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 12, day, 12, 0, 0).unwrap()
+    }
+
+    async fn create(
+        repository: &InMemoryEncounterRepository,
+        name: &str,
+        location: &str,
+        penalty: PenaltyEnum,
+        date_time: DateTime<Utc>,
+    ) -> PenguinEncounter {
+        repository
+            .create(&CreatePenguinEncounter {
+                name,
+                location,
+                penalty,
+                date_time,
+            })
+            .await
+            .unwrap()
+    }
+
+    async fn repository() -> InMemoryEncounterRepository {
+        let repository = InMemoryEncounterRepository::new();
+        let encounters = [
+            ("Tux", "Hobart", PenaltyEnum::PatPenguin),
+            ("Tux", "Antarctica", PenaltyEnum::Jail),
+            ("Gentoo", "Hobart", PenaltyEnum::Jail),
+            ("Adelie", "Hobart", PenaltyEnum::Sacrifice),
+        ];
+        for (day_of_month, (name, location, penalty)) in (1..).zip(encounters) {
+            create(&repository, name, location, penalty, day(day_of_month)).await;
+        }
+        repository
+    }
+
+    fn ids(encounters: &[PenguinEncounter]) -> Vec<i32> {
+        encounters.iter().map(|encounter| encounter.id).collect()
+    }
+
+    #[tokio::test]
+    async fn search_returns_newest_first() {
+        let repository = repository().await;
+
+        let (page, total) = repository
+            .search(&EncounterFilter::default(), 10, 0)
+            .await
+            .unwrap();
+
+        assert_eq!(ids(&page), [4, 3, 2, 1]);
+        assert_eq!(total, 4);
+    }
+
+    #[tokio::test]
+    async fn search_filters() {
+        let repository = repository().await;
+        let search = |filter: EncounterFilter| {
+            let repository = &repository;
+            async move { repository.search(&filter, 10, 0).await.unwrap() }
+        };
+
+        let (page, total) = search(EncounterFilter {
+            name: Some("Tux".to_string()),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!((ids(&page), total), (vec![2, 1], 2));
+
+        let (page, _) = search(EncounterFilter {
+            location: Some("Hobart".to_string()),
+            penalty: Some(PenaltyEnum::Jail),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(ids(&page), [3]);
+
+        // `from` is inclusive and `to` exclusive.
+        let (page, _) = search(EncounterFilter {
+            from: Some(day(2)),
+            to: Some(day(4)),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(ids(&page), [3, 2]);
+
+        let (page, total) = search(EncounterFilter {
+            name: Some("Emperor".to_string()),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!((ids(&page), total), (vec![], 0));
+    }
+
+    #[tokio::test]
+    async fn search_paginates() {
+        let repository = repository().await;
+        let filter = EncounterFilter::default();
+
+        let (page, total) = repository.search(&filter, 2, 0).await.unwrap();
+        assert_eq!((ids(&page), total), (vec![4, 3], 4));
+
+        let (page, total) = repository.search(&filter, 2, 2).await.unwrap();
+        assert_eq!((ids(&page), total), (vec![2, 1], 4));
+
+        let (page, total) = repository.search(&filter, 2, 4).await.unwrap();
+        assert_eq!((ids(&page), total), (vec![], 4));
+    }
+
+    #[tokio::test]
+    async fn names_are_distinct_and_sorted() {
+        let repository = repository().await;
+
+        assert_eq!(
+            repository.names().await.unwrap(),
+            ["Adelie", "Gentoo", "Tux"]
+        );
+    }
+
+    #[tokio::test]
+    async fn updates_an_encounter() {
+        let repository = repository().await;
+        let update = CreatePenguinEncounter {
+            name: "Tux",
+            location: "Launceston",
+            penalty: PenaltyEnum::WorshipTux,
+            date_time: day(5),
+        };
+
+        let updated = repository.update(1, &update).await.unwrap().unwrap();
+        assert_eq!(updated.id, 1);
+        assert_eq!(updated.location, "Launceston");
+
+        let fetched = repository.get(1).await.unwrap().unwrap();
+        assert_eq!(fetched.location, "Launceston");
+        assert_eq!(fetched.penalty, PenaltyEnum::WorshipTux);
+
+        assert!(repository.update(99, &update).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn deletes_an_encounter() {
+        let repository = repository().await;
+
+        assert!(repository.delete(2).await.unwrap());
+        assert!(repository.get(2).await.unwrap().is_none());
+        assert!(!repository.delete(2).await.unwrap());
+        assert_eq!(ids(&repository.list().await.unwrap()), [1, 3, 4]);
+
+        // Ids aren't reused.
+        let created = create(&repository, "Tux", "Hobart", PenaltyEnum::Jail, day(6)).await;
+        assert_eq!(created.id, 5);
+    }
+}
@@ -0,0 +1,309 @@
+//! Storage for penguin encounters and accounts.
+//!
+//! Everything that reads or writes encounters goes through an
+//! [`EncounterRepository`], so the server can run against Postgres or, for
+//! demos and tests, an in-memory store. The store is chosen at startup with
+//! the `encounter_store` setting, which is `postgres` or `memory`. If it is
+//! not set, a `sqlite://` database URL selects SQLite when built with the
+//! `sqlite` feature, and anything else Postgres.
+//!
+//! Users, sessions, TOTP and API tokens go through an [`AccountRepository`],
+//! kept in SQLite with the SQLite store and in Postgres otherwise.
+
+use std::str::FromStr;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::Utc;
+
+use crate::model::{
+    ApiToken, ApiTokenScope, CreatePenguinEncounter, PenaltyEnum, PenguinEncounter, Role, User,
+};
+use crate::server::config::Config;
+use crate::server::database::{DatabaseError, DatabasePool, DatabaseState};
+
+mod memory;
+mod postgres;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+pub use memory::InMemoryEncounterRepository;
+pub use postgres::{PgAccountRepository, PgEncounterRepository};
+#[cfg(feature = "sqlite")]
+pub use sqlite::{SqliteAccountRepository, SqliteEncounterRepository};
+
+#[derive(Debug, thiserror::Error)]
+pub enum RepositoryError {
+    #[error("Database unavailable: {0}")]
+    Unavailable(String),
+    #[error(transparent)]
+    Database(#[from] diesel::result::Error),
+}
+
+/// Filters for [`EncounterRepository::search`], all optional.
+#[derive(Debug, Default, Clone)]
+pub struct EncounterFilter {
+    pub name: Option<String>,
+    pub location: Option<String>,
+    pub penalty: Option<PenaltyEnum>,
+    pub from: Option<chrono::DateTime<Utc>>,
+    pub to: Option<chrono::DateTime<Utc>>,
+}
+
+impl EncounterFilter {
+    pub fn matches(&self, encounter: &PenguinEncounter) -> bool {
+        self.name
+            .as_ref()
+            .is_none_or(|name| *name == encounter.name)
+            && self
+                .location
+                .as_ref()
+                .is_none_or(|location| *location == encounter.location)
+            && self
+                .penalty
+                .is_none_or(|penalty| penalty == encounter.penalty)
+            && self.from.is_none_or(|from| encounter.date_time >= from)
+            && self.to.is_none_or(|to| encounter.date_time < to)
+    }
+}
+
+#[async_trait]
+pub trait EncounterRepository: Send + Sync {
+    /// Every encounter, in no particular order.
+    async fn list(&self) -> Result<Vec<PenguinEncounter>, RepositoryError>;
+
+    /// Get a page of encounters matching the filter, newest first, along with
+    /// the total number of matching encounters.
+    async fn search(
+        &self,
+        filter: &EncounterFilter,
+        limit: i64,
+        offset: i64,
+    ) -> Result<(Vec<PenguinEncounter>, i64), RepositoryError>;
+
+    /// The distinct names of penguins that have been encountered, sorted.
+    async fn names(&self) -> Result<Vec<String>, RepositoryError>;
+
+    async fn get(&self, id: i32) -> Result<Option<PenguinEncounter>, RepositoryError>;
+
+    async fn create(
+        &self,
+        encounter: &CreatePenguinEncounter<'_>,
+    ) -> Result<PenguinEncounter, RepositoryError>;
+
+    /// Replace an encounter, returning `None` if it did not exist.
+    async fn update(
+        &self,
+        id: i32,
+        encounter: &CreatePenguinEncounter<'_>,
+    ) -> Result<Option<PenguinEncounter>, RepositoryError>;
+
+    /// Delete an encounter, returning `false` if it did not exist.
+    async fn delete(&self, id: i32) -> Result<bool, RepositoryError>;
+}
+
+/// The repository shared by every request.
+pub type Encounters = Arc<dyn EncounterRepository>;
+
+#[async_trait]
+pub trait AccountRepository: Send + Sync {
+    async fn count_users(&self) -> Result<i64, RepositoryError>;
+
+    async fn create_user(
+        &self,
+        username: &str,
+        password_hash: &str,
+        role: Role,
+    ) -> Result<User, RepositoryError>;
+
+    /// Look up a user along with their password hash.
+    ///
+    /// Users that only log in with OpenID Connect have no password hash.
+    async fn get_user_by_username(
+        &self,
+        username: &str,
+    ) -> Result<Option<(User, Option<String>)>, RepositoryError>;
+
+    async fn get_user_by_oidc_subject(
+        &self,
+        subject: &str,
+    ) -> Result<Option<User>, RepositoryError>;
+
+    /// Whether the user logs in with OpenID Connect.
+    async fn is_oidc_user(&self, user_id: i32) -> Result<bool, RepositoryError>;
+
+    async fn username_exists(&self, username: &str) -> Result<bool, RepositoryError>;
+
+    async fn create_oidc_user(
+        &self,
+        username: &str,
+        subject: &str,
+        role: Role,
+    ) -> Result<User, RepositoryError>;
+
+    async fn set_user_role(&self, id: i32, role: Role) -> Result<User, RepositoryError>;
+
+    /// Every user, by username.
+    async fn list_users(&self) -> Result<Vec<User>, RepositoryError>;
+
+    async fn get_totp_secret(&self, user_id: i32) -> Result<Option<String>, RepositoryError>;
+
+    /// Store a new secret that has not been confirmed yet, returning `false`
+    /// if TOTP is already enabled for the user.
+    async fn set_pending_totp_secret(
+        &self,
+        user_id: i32,
+        secret: &str,
+    ) -> Result<bool, RepositoryError>;
+
+    /// Enable TOTP, replacing any existing recovery codes.
+    async fn enable_totp(
+        &self,
+        user_id: i32,
+        recovery_code_hashes: &[String],
+    ) -> Result<(), RepositoryError>;
+
+    /// Record that a code for `step` has been used, returning `false` if one
+    /// for the same or a later step already was.
+    async fn accept_totp_step(&self, user_id: i32, step: i64) -> Result<bool, RepositoryError>;
+
+    /// Turn off TOTP and remove the secret and recovery codes, returning
+    /// `false` if there was no such user.
+    async fn reset_totp(&self, user_id: i32) -> Result<bool, RepositoryError>;
+
+    /// Mark an unused recovery code as used, returning `false` if there was
+    /// none.
+    async fn use_recovery_code(
+        &self,
+        user_id: i32,
+        code_hash: &str,
+    ) -> Result<bool, RepositoryError>;
+
+    /// Store a login state, first removing any created before `not_before`,
+    /// so logins that were never completed don't pile up.
+    async fn create_oidc_login_state(
+        &self,
+        csrf_state: &str,
+        pkce_verifier: &str,
+        nonce: &str,
+        not_before: chrono::DateTime<Utc>,
+    ) -> Result<(), RepositoryError>;
+
+    /// Remove a login state, returning the PKCE verifier and nonce if it was
+    /// created after `not_before`.
+    async fn take_oidc_login_state(
+        &self,
+        csrf_state: &str,
+        not_before: chrono::DateTime<Utc>,
+    ) -> Result<Option<(String, String)>, RepositoryError>;
+
+    async fn create_session(
+        &self,
+        session_hash: &str,
+        user_id: i32,
+        expires_at: chrono::DateTime<Utc>,
+    ) -> Result<(), RepositoryError>;
+
+    /// Get the user for a session that has not yet expired.
+    async fn get_session_user(&self, session_hash: &str) -> Result<Option<User>, RepositoryError>;
+
+    async fn delete_session(&self, session_hash: &str) -> Result<(), RepositoryError>;
+
+    async fn delete_expired_sessions(&self) -> Result<usize, RepositoryError>;
+
+    async fn create_api_token(
+        &self,
+        user_id: i32,
+        name: &str,
+        token_hash: &str,
+        scope: ApiTokenScope,
+        expires_at: chrono::DateTime<Utc>,
+    ) -> Result<ApiToken, RepositoryError>;
+
+    /// A user's tokens that have not been revoked, newest first.
+    async fn list_api_tokens(&self, user_id: i32) -> Result<Vec<ApiToken>, RepositoryError>;
+
+    /// Revoke one of a user's tokens, returning `false` if there was none.
+    async fn revoke_api_token(&self, user_id: i32, id: i32) -> Result<bool, RepositoryError>;
+
+    /// Get the user and scope for a token that is neither expired nor
+    /// revoked, recording that it has been used.
+    async fn use_api_token(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<(User, ApiTokenScope)>, RepositoryError>;
+}
+
+/// The accounts shared by every request.
+pub type Accounts = Arc<dyn AccountRepository>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Store {
+    #[default]
+    Postgres,
+    Memory,
+    /// A SQLite database file at this path.
+    #[cfg(feature = "sqlite")]
+    Sqlite(String),
+}
+
+impl FromStr for Store {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "postgres" => Ok(Store::Postgres),
+            "memory" => Ok(Store::Memory),
+            _ => Err(format!("Unknown encounter store: {s}")),
+        }
+    }
+}
+
+impl Store {
+    pub fn from_config(config: &Config) -> Result<Self, String> {
+        if let Some(store) = &config.encounter_store {
+            return store.parse();
+        }
+
+        let database_url = config.database_url.as_ref().map(|url| url.expose());
+        match database_url {
+            #[cfg(feature = "sqlite")]
+            Some(url) if url.starts_with(crate::server::database::sqlite::URL_PREFIX) => {
+                let path = crate::server::database::sqlite::path(url).unwrap_or_default();
+                Ok(Store::Sqlite(path.to_string()))
+            }
+            #[cfg(not(feature = "sqlite"))]
+            Some(url) if url.starts_with("sqlite://") => {
+                Err("SQLite needs the server to be built with the sqlite feature".to_string())
+            }
+            _ => Ok(Store::default()),
+        }
+    }
+}
+
+/// Open the store, migrating it first if needed. Anything kept in Postgres
+/// is stored using `database`, once `state` says it is ready.
+pub async fn open(
+    store: Store,
+    database: &DatabasePool,
+    state: &DatabaseState,
+) -> Result<(Encounters, Accounts), DatabaseError> {
+    let pg_accounts =
+        || -> Accounts { Arc::new(PgAccountRepository::new(database.clone(), state.clone())) };
+    let opened: (Encounters, Accounts) = match store {
+        Store::Postgres => (
+            Arc::new(PgEncounterRepository::new(database.clone(), state.clone())),
+            pg_accounts(),
+        ),
+        Store::Memory => (Arc::new(InMemoryEncounterRepository::new()), pg_accounts()),
+        #[cfg(feature = "sqlite")]
+        Store::Sqlite(path) => {
+            let pool = crate::server::database::sqlite::init(&path).await?;
+            (
+                Arc::new(SqliteEncounterRepository::new(pool.clone())),
+                Arc::new(SqliteAccountRepository::new(pool)),
+            )
+        }
+    };
+    Ok(opened)
+}
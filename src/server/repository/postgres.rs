@@ -0,0 +1,297 @@
+use async_trait::async_trait;
+
+use chrono::Utc;
+
+use super::{AccountRepository, EncounterFilter, EncounterRepository, RepositoryError};
+use crate::model::{ApiToken, ApiTokenScope, CreatePenguinEncounter, PenguinEncounter, Role, User};
+use crate::server::database::{self, DatabaseConnection, DatabasePool, DatabaseState};
+
+async fn connection(
+    pool: &DatabasePool,
+    state: &DatabaseState,
+) -> Result<DatabaseConnection, RepositoryError> {
+    // Fail fast rather than waiting for a connection, or querying tables
+    // that haven't been migrated yet.
+    if !state.is_ready() {
+        return Err(RepositoryError::Unavailable(
+            "waiting for the database to come back".to_string(),
+        ));
+    }
+
+    pool.get()
+        .await
+        .map_err(|err| RepositoryError::Unavailable(err.to_string()))
+}
+
+/// Encounters stored in the `penguin_encounter` table.
+pub struct PgEncounterRepository {
+    pool: DatabasePool,
+    state: DatabaseState,
+}
+
+impl PgEncounterRepository {
+    pub fn new(pool: DatabasePool, state: DatabaseState) -> Self {
+        PgEncounterRepository { pool, state }
+    }
+
+    async fn connection(&self) -> Result<DatabaseConnection, RepositoryError> {
+        connection(&self.pool, &self.state).await
+    }
+}
+
+#[async_trait]
+impl EncounterRepository for PgEncounterRepository {
+    async fn list(&self) -> Result<Vec<PenguinEncounter>, RepositoryError> {
+        let mut conn = self.connection().await?;
+        Ok(database::list_penguin_encounters(&mut conn).await?)
+    }
+
+    async fn search(
+        &self,
+        filter: &EncounterFilter,
+        limit: i64,
+        offset: i64,
+    ) -> Result<(Vec<PenguinEncounter>, i64), RepositoryError> {
+        let mut conn = self.connection().await?;
+        Ok(database::search_penguin_encounters(&mut conn, filter, limit, offset).await?)
+    }
+
+    async fn names(&self) -> Result<Vec<String>, RepositoryError> {
+        let mut conn = self.connection().await?;
+        Ok(database::list_penguin_names(&mut conn).await?)
+    }
+
+    async fn get(&self, id: i32) -> Result<Option<PenguinEncounter>, RepositoryError> {
+        let mut conn = self.connection().await?;
+        Ok(database::get_penguin_encounter(&mut conn, id).await?)
+    }
+
+    async fn create(
+        &self,
+        encounter: &CreatePenguinEncounter<'_>,
+    ) -> Result<PenguinEncounter, RepositoryError> {
+        let mut conn = self.connection().await?;
+        Ok(database::create_penguin_encounter(&mut conn, encounter).await?)
+    }
+
+    async fn update(
+        &self,
+        id: i32,
+        encounter: &CreatePenguinEncounter<'_>,
+    ) -> Result<Option<PenguinEncounter>, RepositoryError> {
+        let mut conn = self.connection().await?;
+        Ok(database::update_penguin_encounter(&mut conn, id, encounter).await?)
+    }
+
+    async fn delete(&self, id: i32) -> Result<bool, RepositoryError> {
+        let mut conn = self.connection().await?;
+        Ok(database::delete_penguin_encounter(&mut conn, id).await?)
+    }
+}
+
+/// Accounts stored in the `users` table and those referring to it.
+pub struct PgAccountRepository {
+    pool: DatabasePool,
+    state: DatabaseState,
+}
+
+impl PgAccountRepository {
+    pub fn new(pool: DatabasePool, state: DatabaseState) -> Self {
+        PgAccountRepository { pool, state }
+    }
+
+    async fn connection(&self) -> Result<DatabaseConnection, RepositoryError> {
+        connection(&self.pool, &self.state).await
+    }
+}
+
+#[async_trait]
+impl AccountRepository for PgAccountRepository {
+    async fn count_users(&self) -> Result<i64, RepositoryError> {
+        let mut conn = self.connection().await?;
+        Ok(database::count_users(&mut conn).await?)
+    }
+
+    async fn create_user(
+        &self,
+        username: &str,
+        password_hash: &str,
+        role: Role,
+    ) -> Result<User, RepositoryError> {
+        let mut conn = self.connection().await?;
+        Ok(database::create_user(&mut conn, username, password_hash, role).await?)
+    }
+
+    async fn get_user_by_username(
+        &self,
+        username: &str,
+    ) -> Result<Option<(User, Option<String>)>, RepositoryError> {
+        let mut conn = self.connection().await?;
+        Ok(database::get_user_by_username(&mut conn, username).await?)
+    }
+
+    async fn get_user_by_oidc_subject(
+        &self,
+        subject: &str,
+    ) -> Result<Option<User>, RepositoryError> {
+        let mut conn = self.connection().await?;
+        Ok(database::get_user_by_oidc_subject(&mut conn, subject).await?)
+    }
+
+    async fn is_oidc_user(&self, user_id: i32) -> Result<bool, RepositoryError> {
+        let mut conn = self.connection().await?;
+        Ok(database::is_oidc_user(&mut conn, user_id).await?)
+    }
+
+    async fn username_exists(&self, username: &str) -> Result<bool, RepositoryError> {
+        let mut conn = self.connection().await?;
+        Ok(database::username_exists(&mut conn, username).await?)
+    }
+
+    async fn create_oidc_user(
+        &self,
+        username: &str,
+        subject: &str,
+        role: Role,
+    ) -> Result<User, RepositoryError> {
+        let mut conn = self.connection().await?;
+        Ok(database::create_oidc_user(&mut conn, username, subject, role).await?)
+    }
+
+    async fn set_user_role(&self, id: i32, role: Role) -> Result<User, RepositoryError> {
+        let mut conn = self.connection().await?;
+        Ok(database::set_user_role(&mut conn, id, role).await?)
+    }
+
+    async fn list_users(&self) -> Result<Vec<User>, RepositoryError> {
+        let mut conn = self.connection().await?;
+        Ok(database::list_users(&mut conn).await?)
+    }
+
+    async fn get_totp_secret(&self, user_id: i32) -> Result<Option<String>, RepositoryError> {
+        let mut conn = self.connection().await?;
+        Ok(database::get_totp_secret(&mut conn, user_id).await?)
+    }
+
+    async fn set_pending_totp_secret(
+        &self,
+        user_id: i32,
+        secret: &str,
+    ) -> Result<bool, RepositoryError> {
+        let mut conn = self.connection().await?;
+        Ok(database::set_pending_totp_secret(&mut conn, user_id, secret).await?)
+    }
+
+    async fn enable_totp(
+        &self,
+        user_id: i32,
+        recovery_code_hashes: &[String],
+    ) -> Result<(), RepositoryError> {
+        let mut conn = self.connection().await?;
+        Ok(database::enable_totp(&mut conn, user_id, recovery_code_hashes).await?)
+    }
+
+    async fn accept_totp_step(&self, user_id: i32, step: i64) -> Result<bool, RepositoryError> {
+        let mut conn = self.connection().await?;
+        Ok(database::accept_totp_step(&mut conn, user_id, step).await?)
+    }
+
+    async fn reset_totp(&self, user_id: i32) -> Result<bool, RepositoryError> {
+        let mut conn = self.connection().await?;
+        Ok(database::reset_totp(&mut conn, user_id).await?)
+    }
+
+    async fn use_recovery_code(
+        &self,
+        user_id: i32,
+        code_hash: &str,
+    ) -> Result<bool, RepositoryError> {
+        let mut conn = self.connection().await?;
+        Ok(database::use_recovery_code(&mut conn, user_id, code_hash).await?)
+    }
+
+    async fn create_oidc_login_state(
+        &self,
+        csrf_state: &str,
+        pkce_verifier: &str,
+        nonce: &str,
+        not_before: chrono::DateTime<Utc>,
+    ) -> Result<(), RepositoryError> {
+        let mut conn = self.connection().await?;
+        Ok(database::create_oidc_login_state(
+            &mut conn,
+            csrf_state,
+            pkce_verifier,
+            nonce,
+            not_before,
+        )
+        .await?)
+    }
+
+    async fn take_oidc_login_state(
+        &self,
+        csrf_state: &str,
+        not_before: chrono::DateTime<Utc>,
+    ) -> Result<Option<(String, String)>, RepositoryError> {
+        let mut conn = self.connection().await?;
+        Ok(database::take_oidc_login_state(&mut conn, csrf_state, not_before).await?)
+    }
+
+    async fn create_session(
+        &self,
+        session_hash: &str,
+        user_id: i32,
+        expires_at: chrono::DateTime<Utc>,
+    ) -> Result<(), RepositoryError> {
+        let mut conn = self.connection().await?;
+        Ok(database::create_session(&mut conn, session_hash, user_id, expires_at).await?)
+    }
+
+    async fn get_session_user(&self, session_hash: &str) -> Result<Option<User>, RepositoryError> {
+        let mut conn = self.connection().await?;
+        Ok(database::get_session_user(&mut conn, session_hash).await?)
+    }
+
+    async fn delete_session(&self, session_hash: &str) -> Result<(), RepositoryError> {
+        let mut conn = self.connection().await?;
+        Ok(database::delete_session(&mut conn, session_hash).await?)
+    }
+
+    async fn delete_expired_sessions(&self) -> Result<usize, RepositoryError> {
+        let mut conn = self.connection().await?;
+        Ok(database::delete_expired_sessions(&mut conn).await?)
+    }
+
+    async fn create_api_token(
+        &self,
+        user_id: i32,
+        name: &str,
+        token_hash: &str,
+        scope: ApiTokenScope,
+        expires_at: chrono::DateTime<Utc>,
+    ) -> Result<ApiToken, RepositoryError> {
+        let mut conn = self.connection().await?;
+        Ok(
+            database::create_api_token(&mut conn, user_id, name, token_hash, scope, expires_at)
+                .await?,
+        )
+    }
+
+    async fn list_api_tokens(&self, user_id: i32) -> Result<Vec<ApiToken>, RepositoryError> {
+        let mut conn = self.connection().await?;
+        Ok(database::list_api_tokens(&mut conn, user_id).await?)
+    }
+
+    async fn revoke_api_token(&self, user_id: i32, id: i32) -> Result<bool, RepositoryError> {
+        let mut conn = self.connection().await?;
+        Ok(database::revoke_api_token(&mut conn, user_id, id).await?)
+    }
+
+    async fn use_api_token(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<(User, ApiTokenScope)>, RepositoryError> {
+        let mut conn = self.connection().await?;
+        Ok(database::use_api_token(&mut conn, token_hash).await?)
+    }
+}
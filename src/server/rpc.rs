@@ -0,0 +1,243 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::ws::{self, WebSocket};
+use axum::extract::WebSocketUpgrade;
+use axum::http::HeaderMap;
+use axum::response::Response;
+use axum::Extension;
+use dioxus::prelude::ServerFnError;
+use futures::{SinkExt, StreamExt};
+use tokio::sync::{mpsc, Semaphore};
+use tracing::{debug, error, info_span, warn, Instrument, Span};
+
+use crate::model::Role;
+use crate::rpc::{RpcCall, RpcReply, RpcRequest, RpcResponse};
+use crate::server::api_tokens::TokenAuth;
+use crate::server::auth::{self, SessionUser};
+use crate::server::authz::{self, CurrentRole};
+use crate::server::events::EncounterEvents;
+use crate::server::functions;
+use crate::server::metrics;
+use crate::server::rate_limit::{ClientKey, RateLimiter, WebsocketPermit};
+use crate::server::repository::{Accounts, Encounters};
+use crate::server::shutdown::{self, Shutdown};
+use crate::server::telemetry;
+use crate::server::MyContext;
+
+/// Most calls in flight on one connection. Further requests aren't read until
+/// one of them replies.
+const MAX_CALLS_IN_FLIGHT: usize = 16;
+
+/// How often the session a connection was opened with is looked up again.
+const SESSION_RECHECK: Duration = Duration::from_secs(60);
+
+/// Server function calls multiplexed over one websocket.
+#[axum::debug_handler]
+#[allow(clippy::too_many_arguments)]
+pub async fn ws_rpc_server(
+    ws: WebSocketUpgrade,
+    Extension(encounters): Extension<Encounters>,
+    Extension(context): Extension<MyContext>,
+    Extension(events): Extension<EncounterEvents>,
+    Extension(shutdown): Extension<Shutdown>,
+    Extension(rate_limiter): Extension<RateLimiter>,
+    Extension(accounts): Extension<Accounts>,
+    CurrentRole(role): CurrentRole,
+    token_auth: Option<Extension<TokenAuth>>,
+    session_user: Option<SessionUser>,
+    headers: HeaderMap,
+    client: ClientKey,
+    permit: WebsocketPermit,
+) -> Response {
+    debug!("Got incoming rpc websocket connection.");
+    // The session cookie is only sent with the upgrade request, so the
+    // session is looked up again now and then, to pick up logging out or a
+    // new role. An API token takes precedence, as with HTTP.
+    let session = match (token_auth, session_user, auth::session_token(&headers)) {
+        (None, Some(_), Some(token)) => Some(Session { accounts, token }),
+        _ => None,
+    };
+    let state = RpcState {
+        encounters,
+        context,
+        events,
+        role,
+    };
+    let span = Span::current();
+    let task = shutdown.task();
+    ws.on_upgrade(move |socket| {
+        async move {
+            let _permit = permit;
+            let _task = task;
+            handle_socket(socket, state, session, rate_limiter, client, shutdown).await
+        }
+        .instrument(span)
+    })
+}
+
+/// Everything a call needs, shared by all calls on a connection.
+#[derive(Clone)]
+struct RpcState {
+    encounters: Encounters,
+    context: MyContext,
+    events: EncounterEvents,
+    role: Option<Role>,
+}
+
+/// The session a connection was opened with.
+struct Session {
+    accounts: Accounts,
+    token: String,
+}
+
+/// Calls are limited per server function, as if made over HTTP. The
+/// connection is closed once its session has ended.
+async fn handle_socket(
+    socket: WebSocket,
+    mut state: RpcState,
+    session: Option<Session>,
+    rate_limiter: RateLimiter,
+    client: ClientKey,
+    shutdown: Shutdown,
+) {
+    let _guard = metrics::WebsocketGuard::new("rpc");
+    let (mut sender, mut receiver) = socket.split();
+    let (tx, mut rx) = mpsc::unbounded_channel::<RpcResponse>();
+    let calls = Arc::new(Semaphore::new(MAX_CALLS_IN_FLIGHT));
+    let mut recheck = tokio::time::interval(SESSION_RECHECK);
+    recheck.reset();
+
+    let writer = tokio::spawn(async move {
+        while let Some(response) = rx.recv().await {
+            let text = match serde_json::to_string(&response) {
+                Ok(text) => text,
+                Err(err) => {
+                    error!("Error serializing rpc response: {:?}", err);
+                    continue;
+                }
+            };
+            if sender.send(ws::Message::Text(text)).await.is_err() {
+                break;
+            }
+        }
+        sender
+    });
+
+    loop {
+        let msg = tokio::select! {
+            msg = receiver.next() => msg,
+            _ = recheck.tick(), if session.is_some() => {
+                let Some(session) = &session else {
+                    continue;
+                };
+                match auth::session_user(&session.accounts, &session.token).await {
+                    Ok(Some(user)) => state.role = Some(user.role),
+                    Ok(None) => {
+                        debug!("Session ended, closing rpc connection");
+                        break;
+                    }
+                    // Keep the role it had, rather than dropping everyone
+                    // while the database is down.
+                    Err(err) => warn!("Failed to check rpc session: {err}"),
+                }
+                continue;
+            }
+            _ = shutdown.wait() => break,
+        };
+        let Some(Ok(msg)) = msg else {
+            break;
+        };
+        let text = match msg {
+            ws::Message::Text(text) => text,
+            ws::Message::Close(..) => break,
+            ws::Message::Binary(_) => continue,
+            ws::Message::Ping(_) => continue,
+            ws::Message::Pong(_) => continue,
+        };
+
+        let request = match serde_json::from_str::<RpcRequest>(&text) {
+            Ok(request) => request,
+            Err(err) => {
+                error!("Invalid rpc request: {:?}", err);
+                continue;
+            }
+        };
+
+        // Calls are processed concurrently, responses are matched up by id
+        // on the client.
+        let permit = tokio::select! {
+            permit = calls.clone().acquire_owned() => permit.expect("Semaphore is never closed"),
+            _ = shutdown.wait() => break,
+        };
+        let tx = tx.clone();
+        let state = state.clone();
+        let name = request.call.name();
+        let span = info_span!("rpc", call = name, rpc_id = request.id);
+        telemetry::set_parent_from_traceparent(&span, request.traceparent.as_deref());
+        let limited = rate_limiter.check(Some(name), None, &client);
+        tokio::spawn(
+            async move {
+                let _permit = permit;
+                let result = match limited {
+                    Ok(()) => dispatch(request.call, &state)
+                        .await
+                        .map_err(|err| err.to_string()),
+                    Err(retry_after) => Err(format!(
+                        "Too many requests, retry in {}s",
+                        retry_after.as_secs_f64().ceil()
+                    )),
+                };
+                metrics::server_function_called(name, "websocket", result.is_err());
+                let _ = tx.send(RpcResponse {
+                    id: request.id,
+                    result,
+                });
+            }
+            .instrument(span),
+        );
+    }
+
+    // Calls in flight keep the writer going until they have replied.
+    drop(tx);
+    if let Ok(mut sender) = writer.await {
+        if shutdown.is_triggered() {
+            let _ = sender.send(shutdown::close_message()).await;
+        }
+    }
+    debug!("Lost rpc connection");
+}
+
+async fn dispatch(call: RpcCall, state: &RpcState) -> Result<RpcReply, ServerFnError> {
+    let encounters = &state.encounters;
+    let require = |required: Role| -> Result<Role, ServerFnError> {
+        authz::check(state.role, required)
+            .map_err(|err| ServerFnError::ServerError(err.to_string()))
+    };
+
+    match call {
+        RpcCall::EchoServer { input } => Ok(RpcReply::EchoServer(functions::echo(
+            &state.context,
+            &input,
+        ))),
+        RpcCall::MagicNumber => Ok(RpcReply::MagicNumber(functions::MAGIC_NUMBER)),
+        RpcCall::GetPenguinEncounters => {
+            require(Role::Viewer)?;
+            functions::get_penguin_encounters(encounters)
+                .await
+                .map(RpcReply::GetPenguinEncounters)
+        }
+        RpcCall::CreatePenguinEncounter { penalty } => {
+            require(penalty.required_role())?;
+            functions::create_penguin_encounter(encounters, &state.events, penalty)
+                .await
+                .map(RpcReply::CreatePenguinEncounter)
+        }
+        RpcCall::DeletePenguinEncounter { id } => {
+            require(Role::Admin)?;
+            functions::delete_penguin_encounter(encounters, id)
+                .await
+                .map(|()| RpcReply::DeletePenguinEncounter)
+        }
+    }
+}
@@ -0,0 +1,92 @@
+//! Graceful shutdown.
+//!
+//! On SIGTERM or SIGINT the server stops accepting connections and waits for
+//! requests in flight to finish. Websockets are upgraded out of the HTTP
+//! connection, so their handlers watch for shutdown themselves, and close
+//! with [`close_message`] so clients know to reconnect. Each holds a
+//! [`Shutdown::task`] so the server waits for them too.
+
+use std::borrow::Cow;
+use std::sync::Arc;
+
+use axum::extract::ws::{self, CloseFrame};
+use tokio::sync::watch;
+use tokio_util::task::task_tracker::TaskTrackerToken;
+use tokio_util::task::TaskTracker;
+use tracing::info;
+
+/// Whether the server is shutting down.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    triggered: Arc<watch::Sender<bool>>,
+    tasks: TaskTracker,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown {
+            triggered: Arc::new(watch::channel(false).0),
+            tasks: TaskTracker::new(),
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn trigger(&self) {
+        self.triggered.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.triggered.borrow()
+    }
+
+    /// Wait until shutdown starts.
+    pub async fn wait(&self) {
+        let mut receiver = self.triggered.subscribe();
+        let _ = receiver.wait_for(|triggered| *triggered).await;
+    }
+
+    /// Keeps [`Shutdown::wait_for_tasks`] waiting until dropped, for work
+    /// that outlives its request, such as an upgraded websocket.
+    pub fn task(&self) -> TaskTrackerToken {
+        self.tasks.token()
+    }
+
+    /// Wait until every [`Shutdown::task`] has been dropped.
+    pub async fn wait_for_tasks(&self) {
+        self.tasks.close();
+        self.tasks.wait().await;
+    }
+
+    /// Trigger shutdown on SIGTERM or SIGINT, or when triggered otherwise.
+    pub async fn on_signal(self) {
+        #[cfg(unix)]
+        let terminate = async {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            match signal(SignalKind::terminate()) {
+                Ok(mut terminate) => {
+                    terminate.recv().await;
+                }
+                Err(_) => std::future::pending().await,
+            }
+        };
+        #[cfg(not(unix))]
+        let terminate = std::future::pending::<()>();
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => info!("Interrupted, shutting down"),
+            _ = terminate => info!("Terminated, shutting down"),
+            _ = self.wait() => {}
+        }
+        self.trigger();
+    }
+}
+
+/// Tells websocket clients the server is going away, but will be back.
+pub fn close_message() -> ws::Message {
+    ws::Message::Close(Some(CloseFrame {
+        code: ws::close_code::RESTART,
+        reason: Cow::Borrowed("server restarting"),
+    }))
+}
@@ -0,0 +1,120 @@
+//! systemd integration: socket activation and service notifications.
+//!
+//! Nothing happens unless systemd set the environment variables, so this is
+//! safe to use anywhere.
+
+use std::env;
+use std::future::Future;
+use std::time::Duration;
+
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, warn};
+
+/// File descriptor of the first socket passed by systemd.
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// Sockets passed by systemd socket activation, if any.
+///
+/// Only the first call gets them, as each can only have one owner. The
+/// environment is left alone, as changing it isn't safe once other threads
+/// are running; `LISTEN_PID` already stops child processes using them.
+#[cfg(unix)]
+pub fn listen_fds() -> Vec<std::os::fd::OwnedFd> {
+    use std::os::fd::FromRawFd;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    static TAKEN: AtomicBool = AtomicBool::new(false);
+
+    let for_us = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid == std::process::id());
+    if !for_us || TAKEN.swap(true, Ordering::SeqCst) {
+        return Vec::new();
+    }
+
+    let count = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse::<i32>().ok())
+        .unwrap_or(0);
+
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        // SAFETY: systemd passes these open, and nothing else owns them.
+        .map(|fd| unsafe { std::os::fd::OwnedFd::from_raw_fd(fd) })
+        .collect()
+}
+
+/// Send `state` to systemd, if it is waiting for notifications.
+#[cfg(unix)]
+pub fn notify(state: &str) {
+    use std::os::unix::net::UnixDatagram;
+
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+
+    let result = UnixDatagram::unbound().and_then(|socket| {
+        let bytes = path.as_encoded_bytes();
+        if let Some(name) = bytes.strip_prefix(b"@") {
+            #[cfg(target_os = "linux")]
+            {
+                use std::os::linux::net::SocketAddrExt;
+                let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                socket.send_to_addr(state.as_bytes(), &address)
+            }
+            #[cfg(not(target_os = "linux"))]
+            {
+                let _ = name;
+                Err(std::io::ErrorKind::Unsupported.into())
+            }
+        } else {
+            socket.send_to(state.as_bytes(), &path)
+        }
+    });
+
+    match result {
+        Ok(_) => debug!("Notified systemd: {state}"),
+        Err(err) => warn!("Failed to notify systemd of {state}: {err}"),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn notify(_state: &str) {}
+
+/// How often systemd expects `WATCHDOG=1`, if it does.
+fn watchdog_interval() -> Option<Duration> {
+    let for_us = match env::var("WATCHDOG_PID") {
+        Ok(pid) => pid.parse::<u32>().ok() == Some(std::process::id()),
+        Err(_) => true,
+    };
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    (for_us && usec > 0).then(|| Duration::from_micros(usec))
+}
+
+/// Ping the watchdog at half the interval systemd asked for, as long as
+/// `check` passes within that time. When the pings stop, because the check
+/// fails or the runtime stops scheduling tasks, systemd restarts the service.
+pub fn spawn_watchdog<F, Fut>(check: F)
+where
+    F: Fn(Duration) -> Fut + Send + 'static,
+    Fut: Future<Output = bool> + Send,
+{
+    let Some(interval) = watchdog_interval() else {
+        return;
+    };
+    let period = interval / 2;
+
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(period);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            if check(period).await {
+                notify("WATCHDOG=1");
+            } else {
+                warn!("Liveness check failed, not pinging the watchdog");
+            }
+        }
+    });
+}
@@ -0,0 +1,135 @@
+//! OpenTelemetry export, and continuing traces started by the client.
+//!
+//! Enabled by setting `otlp_endpoint` to an OTLP/gRPC collector, such as
+//! `http://localhost:4317`. Spans are exported in batches along with the
+//! spans the browser posts to `/_telemetry/spans`, which are only accepted
+//! from logged in users so anyone else can't fill the collector.
+
+use std::collections::HashMap;
+
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use opentelemetry::propagation::Extractor;
+use opentelemetry::trace::{
+    Span as _, SpanId, SpanKind, Status, TraceError, TraceId, Tracer as _, TracerProvider as _,
+};
+use opentelemetry::{global, Context, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{Tracer, TracerProvider};
+use opentelemetry_sdk::{runtime, Resource};
+use tracing::{debug, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::server::auth::SessionUser;
+use crate::server::config::Config;
+use crate::server::error_reporting::truncate;
+use crate::telemetry::{ClientSpan, TRACEPARENT};
+
+/// Most spans accepted from the client in one request.
+const MAX_CLIENT_SPANS: usize = 20;
+
+/// Longest span name exported, in bytes.
+const MAX_NAME_LEN: usize = 100;
+
+/// Longest error exported, in bytes.
+const MAX_ERROR_LEN: usize = 1_000;
+
+/// Start exporting, if configured, returning the tracer for the
+/// `tracing` layer.
+pub fn init(config: &Config) -> Result<Option<Tracer>, TraceError> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let Some(endpoint) = &config.otlp_endpoint else {
+        return Ok(None);
+    };
+
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint)
+        .build()?;
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(Resource::new([KeyValue::new(
+            "service.name",
+            config.otel_service_name.clone(),
+        )]))
+        .build();
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+    global::set_tracer_provider(provider);
+
+    Ok(Some(tracer))
+}
+
+/// Export any spans that haven't been yet.
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// Continue the trace from the caller's headers, if they sent one.
+pub fn set_parent(span: &Span, headers: &HeaderMap) {
+    if headers.contains_key(TRACEPARENT) {
+        let parent = global::get_text_map_propagator(|propagator| {
+            propagator.extract(&HeaderExtractor(headers))
+        });
+        span.set_parent(parent);
+    }
+}
+
+/// Continue the trace from a `traceparent` sent some other way.
+pub fn set_parent_from_traceparent(span: &Span, traceparent: Option<&str>) {
+    if let Some(traceparent) = traceparent {
+        let carrier = HashMap::from([(TRACEPARENT.to_string(), traceparent.to_string())]);
+        let parent = global::get_text_map_propagator(|propagator| propagator.extract(&carrier));
+        span.set_parent(parent);
+    }
+}
+
+fn export_client_span(span: ClientSpan) -> Result<(), String> {
+    let trace_id = TraceId::from_hex(&span.trace_id).map_err(|err| err.to_string())?;
+    let span_id = SpanId::from_hex(&span.span_id).map_err(|err| err.to_string())?;
+
+    let tracer = global::tracer("client");
+    let mut builder = tracer
+        .span_builder(truncate(&span.name, MAX_NAME_LEN).to_string())
+        .with_kind(SpanKind::Client)
+        .with_start_time(span.start);
+    builder.trace_id = Some(trace_id);
+    builder.span_id = Some(span_id);
+
+    let mut exported = tracer.build_with_context(builder, &Context::new());
+    if let Some(error) = span.error {
+        exported.set_status(Status::error(truncate(&error, MAX_ERROR_LEN).to_string()));
+    }
+    exported.end_with_timestamp(span.end.into());
+    Ok(())
+}
+
+/// Spans recorded by the browser of a logged in user.
+pub async fn client_spans(_user: SessionUser, Json(spans): Json<Vec<ClientSpan>>) -> StatusCode {
+    if spans.len() > MAX_CLIENT_SPANS {
+        return StatusCode::PAYLOAD_TOO_LARGE;
+    }
+
+    // Without an exporter, the global tracer discards them.
+    for span in spans {
+        if let Err(err) = export_client_span(span) {
+            debug!("Invalid client span: {err}");
+            return StatusCode::BAD_REQUEST;
+        }
+    }
+
+    StatusCode::NO_CONTENT
+}
@@ -0,0 +1,219 @@
+//! Serving HTTPS without a reverse proxy.
+//!
+//! The certificate is read when the server starts, and again on SIGHUP or
+//! when either file changes. Each connection is set up with the certificate
+//! current when it was accepted, so reloading leaves open connections alone.
+
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use axum::extract::Host;
+use axum::http::uri::Authority;
+use axum::http::{StatusCode, Uri};
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::{Extension, Router};
+use tokio::sync::watch;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::{self, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use tracing::{error, info};
+
+use crate::server::config::Config;
+use crate::server::shutdown::Shutdown;
+
+/// How often the certificate files are checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, thiserror::Error)]
+pub enum TlsError {
+    #[error("TLS_CERT and TLS_KEY must be set together")]
+    Incomplete,
+    #[error("Failed to read {path}: {source}")]
+    Read { path: PathBuf, source: io::Error },
+    #[error("No certificates in {0}")]
+    NoCertificates(PathBuf),
+    #[error("No private key in {0}")]
+    NoKey(PathBuf),
+    #[error("Invalid certificate or key: {0}")]
+    Rustls(#[from] rustls::Error),
+}
+
+/// The certificate to serve, kept up to date by [`Tls::reload_on_change`].
+#[derive(Debug, Clone)]
+pub struct Tls {
+    cert: Arc<PathBuf>,
+    key: Arc<PathBuf>,
+    config: Arc<watch::Sender<Arc<ServerConfig>>>,
+}
+
+impl Tls {
+    /// Load the certificate from `tls_cert` and `tls_key`, if set.
+    pub fn from_config(config: &Config) -> Result<Option<Self>, TlsError> {
+        match (&config.tls_cert, &config.tls_key) {
+            (Some(cert), Some(key)) => Ok(Some(Tls::load(cert, key)?)),
+            (None, None) => Ok(None),
+            _ => Err(TlsError::Incomplete),
+        }
+    }
+
+    fn load(cert: &Path, key: &Path) -> Result<Self, TlsError> {
+        let config = server_config(cert, key)?;
+        Ok(Tls {
+            cert: Arc::new(cert.to_path_buf()),
+            key: Arc::new(key.to_path_buf()),
+            config: Arc::new(watch::channel(config).0),
+        })
+    }
+
+    /// Sets up connections with the current certificate.
+    pub fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.config.borrow().clone())
+    }
+
+    fn reload(&self) -> Result<(), TlsError> {
+        let config = server_config(&self.cert, &self.key)?;
+        self.config.send_replace(config);
+        Ok(())
+    }
+
+    fn modified(&self) -> (Option<SystemTime>, Option<SystemTime>) {
+        let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        (modified(&self.cert), modified(&self.key))
+    }
+
+    /// Reload the certificate on SIGHUP, or when either file changes. A
+    /// certificate that fails to load is logged, and the old one kept.
+    pub async fn reload_on_change(self) {
+        let mut hangup = hangups();
+        let mut ticks = tokio::time::interval(POLL_INTERVAL);
+        let mut modified = self.modified();
+
+        loop {
+            tokio::select! {
+                () = hung_up(&mut hangup) => {
+                    info!("Hung up, reloading the certificate");
+                }
+                _ = ticks.tick() => {
+                    let now = self.modified();
+                    if now == modified {
+                        continue;
+                    }
+                    modified = now;
+                    info!("Certificate changed, reloading");
+                }
+            }
+
+            match self.reload() {
+                Ok(()) => info!("Reloaded the certificate"),
+                Err(err) => error!("Failed to reload the certificate, keeping the old one: {err}"),
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+type Hangups = Option<tokio::signal::unix::Signal>;
+#[cfg(not(unix))]
+type Hangups = ();
+
+#[cfg(unix)]
+fn hangups() -> Hangups {
+    tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).ok()
+}
+
+#[cfg(not(unix))]
+fn hangups() -> Hangups {}
+
+/// Wait for SIGHUP, or forever without one.
+async fn hung_up(hangups: &mut Hangups) {
+    #[cfg(unix)]
+    if let Some(hangups) = hangups {
+        hangups.recv().await;
+        return;
+    }
+    #[cfg(not(unix))]
+    let _ = hangups;
+    std::future::pending().await
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, TlsError> {
+    std::fs::read(path).map_err(|source| TlsError::Read {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+fn server_config(cert: &Path, key: &Path) -> Result<Arc<ServerConfig>, TlsError> {
+    let certs = rustls_pemfile::certs(&mut read(cert)?.as_slice())
+        .collect::<Result<Vec<CertificateDer>, _>>()
+        .map_err(|source| TlsError::Read {
+            path: cert.to_path_buf(),
+            source,
+        })?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificates(cert.to_path_buf()));
+    }
+
+    let private_key: PrivateKeyDer = rustls_pemfile::private_key(&mut read(key)?.as_slice())
+        .map_err(|source| TlsError::Read {
+            path: key.to_path_buf(),
+            source,
+        })?
+        .ok_or_else(|| TlsError::NoKey(key.to_path_buf()))?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, private_key)?;
+    // Websockets upgrade HTTP/1.1 connections, which browsers open
+    // separately when the page itself came over HTTP/2.
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// The port HTTPS is served on, for redirects.
+#[derive(Debug, Clone, Copy)]
+struct HttpsPort(u16);
+
+async fn redirect(
+    Extension(HttpsPort(port)): Extension<HttpsPort>,
+    Host(host): Host,
+    uri: Uri,
+) -> Response {
+    let Ok(authority) = host.parse::<Authority>() else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    let host = authority.host();
+    let path = uri
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+
+    let url = if port == 443 {
+        format!("https://{host}{path}")
+    } else {
+        format!("https://{host}:{port}{path}")
+    };
+    Redirect::permanent(&url).into_response()
+}
+
+/// Redirect every plain HTTP request on `address` to HTTPS on `https_port`.
+pub async fn redirect_http(
+    address: SocketAddr,
+    https_port: u16,
+    shutdown: Shutdown,
+) -> io::Result<()> {
+    let router = Router::new()
+        .fallback(redirect)
+        .layer(Extension(HttpsPort(https_port)));
+
+    let listener = tokio::net::TcpListener::bind(address).await?;
+    info!("Redirecting HTTP on {address} to HTTPS");
+    axum::serve(listener, router)
+        .with_graceful_shutdown(async move { shutdown.wait().await })
+        .await
+}
@@ -0,0 +1,193 @@
+//! TOTP two factor authentication with hashed recovery codes.
+
+use qrcode::render::svg;
+use qrcode::QrCode;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use crate::model::{TotpEnrolment, User};
+use crate::server::auth::{self, AuthError};
+use crate::server::rate_limit::{Attempt, RateLimiter};
+use crate::server::repository::Accounts;
+
+const ISSUER: &str = "dioxus-fs-demo";
+const RECOVERY_CODE_COUNT: usize = 10;
+/// How many steps either side of now a code is accepted for, to allow for
+/// clock drift. Checked here rather than by `TOTP`, so we know which step a
+/// code was for.
+const SKEW: u64 = 1;
+
+fn totp_for(user: &User, secret: &str) -> Result<TOTP, AuthError> {
+    let secret = Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|err| AuthError::Totp(format!("{err:?}")))?;
+
+    TOTP::new(
+        Algorithm::SHA1,
+        6,
+        0,
+        30,
+        secret,
+        Some(ISSUER.to_string()),
+        user.username.clone(),
+    )
+    .map_err(|err| AuthError::Totp(err.to_string()))
+}
+
+/// The time step `code` is for, if it is valid now.
+fn matching_step(totp: &TOTP, code: &str, now: u64) -> Option<i64> {
+    let current = now / totp.step;
+    (current.saturating_sub(SKEW)..=current + SKEW)
+        .find(|step| totp.check(code, step * totp.step))
+        .and_then(|step| i64::try_from(step).ok())
+}
+
+/// Refuse a user who has got too many codes wrong recently.
+fn check_attempts(limiter: &RateLimiter, user: &User) -> Result<(), AuthError> {
+    limiter
+        .check_attempt(Attempt::SecondFactor, &user.id.to_string())
+        .map_err(AuthError::TooManyAttempts)
+}
+
+/// Check `code`, and that neither it nor a later one has been used before.
+async fn accept_code(
+    accounts: &Accounts,
+    user: &User,
+    secret: &str,
+    code: &str,
+) -> Result<bool, AuthError> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_err(|err| AuthError::Totp(err.to_string()))?
+        .as_secs();
+
+    match matching_step(&totp_for(user, secret)?, code, now) {
+        Some(step) => Ok(accounts.accept_totp_step(user.id, step).await?),
+        None => Ok(false),
+    }
+}
+
+/// Generate a new secret for the user, to be confirmed with [`confirm`].
+///
+/// Refused for users who log in with OpenID Connect.
+pub async fn begin_enrolment(accounts: &Accounts, user: &User) -> Result<TotpEnrolment, AuthError> {
+    let secret = Secret::generate_secret().to_encoded().to_string();
+    let totp = totp_for(user, &secret)?;
+
+    let qr_svg = QrCode::new(totp.get_url())
+        .map_err(|err| AuthError::Totp(err.to_string()))?
+        .render::<svg::Color>()
+        .min_dimensions(200, 200)
+        .build();
+
+    // The identity provider is responsible for their second factor, and
+    // logging in with it never asks for a code.
+    if accounts.is_oidc_user(user.id).await? {
+        return Err(AuthError::Totp(
+            "Accounts that log in with OpenID Connect can't enable two factor authentication here"
+                .to_string(),
+        ));
+    }
+
+    if !accounts.set_pending_totp_secret(user.id, &secret).await? {
+        return Err(AuthError::Totp(
+            "Two factor authentication is already enabled".to_string(),
+        ));
+    }
+
+    Ok(TotpEnrolment { secret, qr_svg })
+}
+
+/// Enable TOTP once the user has shown they can generate codes, returning
+/// the recovery codes. These are only stored hashed, so can't be shown again.
+pub async fn confirm(
+    accounts: &Accounts,
+    limiter: &RateLimiter,
+    user: &User,
+    code: &str,
+) -> Result<Vec<String>, AuthError> {
+    let secret = accounts
+        .get_totp_secret(user.id)
+        .await?
+        .ok_or_else(|| AuthError::Totp("Enrolment has not been started".to_string()))?;
+
+    check_attempts(limiter, user)?;
+    if !accept_code(accounts, user, &secret, code.trim()).await? {
+        limiter.attempt_failed(Attempt::SecondFactor, &user.id.to_string());
+        return Err(AuthError::InvalidCredentials);
+    }
+
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| auth::generate_token()[..16].to_string())
+        .collect();
+    let hashes: Vec<String> = codes.iter().map(|code| auth::hash_token(code)).collect();
+
+    accounts.enable_totp(user.id, &hashes).await?;
+    Ok(codes)
+}
+
+/// Check a TOTP code, or failing that a recovery code, for a user who has
+/// TOTP enabled. Each code can only be used once, and after too many wrong
+/// codes the user is refused for a while.
+pub async fn verify(
+    accounts: &Accounts,
+    limiter: &RateLimiter,
+    user: &User,
+    code: &str,
+) -> Result<bool, AuthError> {
+    check_attempts(limiter, user)?;
+
+    let code = code.trim();
+    if let Some(secret) = accounts.get_totp_secret(user.id).await? {
+        if accept_code(accounts, user, &secret, code).await? {
+            return Ok(true);
+        }
+    }
+
+    let accepted = accounts
+        .use_recovery_code(user.id, &auth::hash_token(code))
+        .await?;
+    if !accepted {
+        limiter.attempt_failed(Attempt::SecondFactor, &user.id.to_string());
+    }
+    Ok(accepted)
+}
+
+/// Remove TOTP from a user, for example if they have lost their device.
+pub async fn reset(accounts: &Accounts, user_id: i32) -> Result<bool, AuthError> {
+    Ok(accounts.reset_totp(user_id).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Role;
+
+    fn totp() -> TOTP {
+        let user = User {
+            id: 1,
+            username: "tux".to_string(),
+            role: Role::Viewer,
+            totp_enabled: true,
+        };
+        totp_for(&user, "KRSXG5CTMVRXEZLUKN2XAZLSKNSWG4TFOQ").unwrap()
+    }
+
+    #[test]
+    fn finds_the_step_a_code_is_for() {
+        let totp = totp();
+        let now = 1_700_000_000;
+        let step = (now / 30) as i64;
+
+        assert_eq!(matching_step(&totp, &totp.generate(now), now), Some(step));
+        assert_eq!(
+            matching_step(&totp, &totp.generate(now - 30), now),
+            Some(step - 1)
+        );
+        assert_eq!(
+            matching_step(&totp, &totp.generate(now + 30), now),
+            Some(step + 1)
+        );
+        assert_eq!(matching_step(&totp, &totp.generate(now - 60), now), None);
+        assert_eq!(matching_step(&totp, "bogus", now), None);
+    }
+}
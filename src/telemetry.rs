@@ -0,0 +1,137 @@
+//! Client side tracing, continued on the server.
+//!
+//! [`traced`] runs a future in a new trace. Server function calls made while
+//! it is polled send a W3C `traceparent` header, or field over the rpc
+//! websocket, so the server's spans join the trace. When the future finishes
+//! in the browser, its span is posted to `/_telemetry/spans` for the server
+//! to export with its own, which it only accepts from logged in users.
+
+use std::cell::RefCell;
+use std::fmt::Display;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[allow(dead_code)]
+pub const TRACEPARENT: &str = "traceparent";
+
+/// A span recorded in the browser.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClientSpan {
+    pub name: String,
+    /// 32 hex digits.
+    pub trace_id: String,
+    /// 16 hex digits.
+    pub span_id: String,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub error: Option<String>,
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// The `traceparent` of the span being polled, if any.
+pub fn current_traceparent() -> Option<String> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+fn random_hex<const N: usize>() -> String {
+    let mut bytes = [0u8; N];
+    getrandom::getrandom(&mut bytes).expect("Failed to get random bytes");
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Makes the span current while polling `inner`.
+struct WithTrace<F> {
+    traceparent: String,
+    inner: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for WithTrace<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let previous = CURRENT.with(|current| current.replace(Some(self.traceparent.clone())));
+        let result = self.inner.as_mut().poll(cx);
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+        result
+    }
+}
+
+/// Run `future` in a new trace, recording a span called `name`.
+pub async fn traced<F, T, E>(name: &str, future: F) -> Result<T, E>
+where
+    F: Future<Output = Result<T, E>>,
+    E: Display,
+{
+    let trace_id = random_hex::<16>();
+    let span_id = random_hex::<8>();
+    let start = Utc::now();
+
+    let result = WithTrace {
+        traceparent: format!("00-{trace_id}-{span_id}-01"),
+        inner: Box::pin(future),
+    }
+    .await;
+
+    let span = ClientSpan {
+        name: name.to_string(),
+        trace_id,
+        span_id,
+        start,
+        end: Utc::now(),
+        error: result.as_ref().err().map(|err| err.to_string()),
+    };
+    report(span);
+
+    result
+}
+
+#[cfg(target_arch = "wasm32")]
+fn report(span: ClientSpan) {
+    use dioxus::prelude::spawn_forever;
+
+    spawn_forever(async move {
+        let request = match gloo_net::http::Request::post("/_telemetry/spans").json(&[span]) {
+            Ok(request) => request,
+            Err(err) => {
+                tracing::debug!("Failed to encode span: {err}");
+                return;
+            }
+        };
+        if let Err(err) = request.send().await {
+            tracing::debug!("Failed to send span: {err}");
+        }
+    });
+}
+
+/// Spans from server side rendering are already recorded by the server.
+#[cfg(not(target_arch = "wasm32"))]
+fn report(_span: ClientSpan) {}
+
+/// Server function client adding the `traceparent` header.
+///
+/// Used with `#[server(..., client = TracingClient)]` in the browser.
+#[cfg(target_arch = "wasm32")]
+pub struct TracingClient;
+
+#[cfg(target_arch = "wasm32")]
+impl<E: Send + 'static> dioxus::prelude::server_fn::client::Client<E> for TracingClient {
+    type Request = dioxus::prelude::server_fn::request::browser::BrowserRequest;
+    type Response = dioxus::prelude::server_fn::response::browser::BrowserResponse;
+
+    fn send(
+        request: Self::Request,
+    ) -> impl Future<Output = Result<Self::Response, dioxus::prelude::ServerFnError<E>>> + Send
+    {
+        if let Some(traceparent) = current_traceparent() {
+            request.headers().set(TRACEPARENT, &traceparent);
+        }
+        dioxus::prelude::server_fn::client::browser::BrowserClient::send(request)
+    }
+}